@@ -1,26 +1,151 @@
 use std::collections::HashMap;
 use std::ffi::{c_void, CStr, CString};
 use std::path::Path;
-use std::ptr;
+use std::time::Duration;
+use std::{mem, ptr, slice};
 
 use anyhow::{anyhow, bail, Result};
 use libbpf_sys;
+pub use libbpf_sys::{
+    XDP_FLAGS_DRV_MODE, XDP_FLAGS_HW_MODE, XDP_FLAGS_SKB_MODE, XDP_FLAGS_UPDATE_IF_NOEXIST,
+};
 
-#[allow(dead_code)]
+// filters of other tools on the same hook have other handles or priorities
+const TC_HANDLE: u32 = 0x7066;
+const TC_PRIORITY: u32 = 1;
+
+/// A program attached to a hook, it is detached when the link is dropped unless it is pinned.
 pub struct BPFLink {
-    ptr: *mut libbpf_sys::bpf_link,
+    // name of the program, the one that replaces it must have the same name
+    prog: String,
+    hook: Hook,
+    // pinned XDP links and TC filters left attached outlive the link
+    pinned: bool,
+}
+
+impl BPFLink {
+    /// Opens the XDP link pinned at `path`, e.g. to replace its program with
+    /// `BPFObj::update_link`. `prog` is the name of the program attached with it.
+    pub fn from_pin<T: AsRef<Path>>(path: T, prog: &str) -> Result<Self> {
+        let fd = obj_get(path.as_ref(), "link")?;
+        Ok(BPFLink {
+            prog: prog.to_string(),
+            hook: Hook::Xdp { fd },
+            pinned: true,
+        })
+    }
+
+    pub fn prog(&self) -> &str {
+        &self.prog
+    }
+
+    /// Pins the link at `path` so that the program stays attached once the link is dropped,
+    /// until `detach` is called. TC filters have no link, nothing is pinned and they are
+    /// only left attached.
+    pub fn pin<T: AsRef<Path>>(&mut self, path: T) -> Result<()> {
+        if let Hook::Xdp { fd } = self.hook {
+            pin_fd(fd, path.as_ref(), "link")?;
+        }
+        self.pinned = true;
+        Ok(())
+    }
+
+    /// Detaches the program, even if the link is pinned.
+    pub fn detach(mut self) -> Result<()> {
+        if let Hook::Xdp { fd } = self.hook {
+            let res = unsafe { libbpf_sys::bpf_link_detach(fd) };
+            if res < 0 {
+                bail!("error {}: failed to detach the xdp link", errno());
+            }
+        }
+        // TC filters are detached once dropped
+        self.pinned = false;
+        Ok(())
+    }
+}
+
+enum Hook {
+    // created with bpf_link_create since bpf_program__attach_xdp takes no flags,
+    // the program stays attached until the link is closed
+    Xdp { fd: i32 },
+    // TC filters have no link and stay attached until they are detached
+    Tc { ifindex: i32, attach_point: TcHook },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TcHook {
+    Ingress,
+    Egress,
+}
+
+impl TcHook {
+    fn hook(&self, ifindex: i32) -> libbpf_sys::bpf_tc_hook {
+        libbpf_sys::bpf_tc_hook {
+            sz: mem::size_of::<libbpf_sys::bpf_tc_hook>() as libbpf_sys::size_t,
+            ifindex,
+            attach_point: match self {
+                TcHook::Ingress => libbpf_sys::BPF_TC_INGRESS,
+                TcHook::Egress => libbpf_sys::BPF_TC_EGRESS,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+fn tc_opts(prog_fd: i32, flags: u32) -> libbpf_sys::bpf_tc_opts {
+    libbpf_sys::bpf_tc_opts {
+        sz: mem::size_of::<libbpf_sys::bpf_tc_opts>() as libbpf_sys::size_t,
+        prog_fd,
+        flags,
+        handle: TC_HANDLE,
+        priority: TC_PRIORITY,
+        ..Default::default()
+    }
+}
+
+impl Drop for BPFLink {
+    fn drop(&mut self) {
+        match self.hook {
+            Hook::Xdp { fd } => unsafe {
+                libc::close(fd);
+            },
+            Hook::Tc {
+                ifindex,
+                attach_point,
+            } => {
+                if !self.pinned {
+                    let _ = detach_tc(ifindex, attach_point);
+                }
+            }
+        }
+    }
 }
 
 pub struct BPFObj {
     ptr: *mut libbpf_sys::bpf_object,
-    progs: Vec<BPFProg>,
+    progs: HashMap<String, BPFProg>,
     maps: HashMap<String, BPFMap>,
 }
 
 impl BPFObj {
-    pub fn load_from_file<T: AsRef<Path>>(src: T) -> Result<Self> {
+    /// Loads the object, its maps named in `reuse` are the given maps instead of new ones.
+    pub fn load_from_file<T: AsRef<Path>>(src: T, reuse: &[(&str, &BPFMap)]) -> Result<Self> {
         let obj_ptr = BPFObj::open_file(src.as_ref())?;
 
+        for (name, map) in reuse {
+            let c_name = CString::new(*name)?;
+            let map_ptr =
+                unsafe { libbpf_sys::bpf_object__find_map_by_name(obj_ptr, c_name.as_ptr()) };
+            if map_ptr.is_null() {
+                bail!("unknown map {}", name);
+            }
+
+            let res = unsafe { libbpf_sys::bpf_map__reuse_fd(map_ptr, map.fd) };
+            if res != 0 {
+                bail!("error {}: failed to reuse map {}", -res, name);
+            }
+        }
+
         let res = unsafe { libbpf_sys::bpf_object__load(obj_ptr) };
         if res != 0 {
             bail!("error {}: failed to load bpf object", -res);
@@ -28,7 +153,7 @@ impl BPFObj {
 
         let mut obj = BPFObj {
             ptr: obj_ptr,
-            progs: Vec::new(),
+            progs: HashMap::new(),
             maps: HashMap::new(),
         };
 
@@ -60,7 +185,6 @@ impl BPFObj {
             prev_map = next_ptr;
         }
 
-        // we're only expecting to handle one program but this will make it easier to extend
         let mut prev_prog: *mut libbpf_sys::bpf_program = std::ptr::null_mut();
         loop {
             let next_ptr = unsafe { libbpf_sys::bpf_object__next_program(obj.ptr, prev_prog) };
@@ -68,7 +192,10 @@ impl BPFObj {
                 break;
             }
 
-            obj.progs.push(BPFProg::new(next_ptr));
+            // bpf_program__name does not return null unless we pass null
+            let str_ptr = unsafe { libbpf_sys::bpf_program__name(next_ptr) };
+            let name = unsafe { CStr::from_ptr(str_ptr) }.to_str()?.to_string();
+            obj.progs.insert(name, BPFProg::new(next_ptr));
             prev_prog = next_ptr;
         }
 
@@ -115,11 +242,82 @@ impl BPFObj {
         }
     }
 
-    pub fn attach_prog(&mut self, ifindex: i32) -> Result<BPFLink> {
-        // for now we only support one program
-        match self.progs.get_mut(0) {
-            Some(p) => p.attach_xdp(ifindex),
-            _ => bail!("failed to retrieve prog"),
+    pub fn delete_map_elem<T: AsRef<str>>(&mut self, name: T, key: &[u8]) -> Result<()> {
+        match self.maps.get_mut(name.as_ref()) {
+            Some(m) => m.delete_elem(key),
+            _ => bail!("unknown map"),
+        }
+    }
+
+    pub fn map<T: AsRef<str>>(&self, name: T) -> Result<&BPFMap> {
+        match self.maps.get(name.as_ref()) {
+            Some(m) => Ok(m),
+            _ => bail!("unknown map"),
+        }
+    }
+
+    fn prog(&self, name: &str) -> Result<&BPFProg> {
+        match self.progs.get(name) {
+            Some(p) => Ok(p),
+            _ => bail!("unknown prog {}", name),
+        }
+    }
+
+    /// Attaches the program to the XDP hook of the interface with `XDP_FLAGS_*` flags.
+    ///
+    /// Unless `XDP_FLAGS_UPDATE_IF_NOEXIST` is set, a program attached to the hook
+    /// without a link is detached first, links cannot replace it otherwise. The hook is
+    /// empty in between and the program is attached again if the link fails. A program
+    /// attached with a link, e.g. by another process, is not replaced.
+    pub fn attach_xdp(&mut self, prog: &str, ifindex: i32, flags: u32) -> Result<BPFLink> {
+        let fd = self.prog(prog)?.attach_xdp(ifindex, flags)?;
+        Ok(BPFLink {
+            prog: prog.to_string(),
+            hook: Hook::Xdp { fd },
+            pinned: false,
+        })
+    }
+
+    /// Attaches the program to the clsact qdisc of the interface, which is created
+    /// if needed. `replace` replaces the filter of another `BPFObj` instead of failing.
+    pub fn attach_tc(
+        &mut self,
+        prog: &str,
+        ifindex: i32,
+        attach_point: TcHook,
+        replace: bool,
+    ) -> Result<BPFLink> {
+        self.prog(prog)?.attach_tc(ifindex, attach_point, replace)?;
+        Ok(BPFLink {
+            prog: prog.to_string(),
+            hook: Hook::Tc {
+                ifindex,
+                attach_point,
+            },
+            pinned: false,
+        })
+    }
+
+    /// Pins the program at `path`, it stays loaded until it is unpinned and detached.
+    pub fn pin_prog<T: AsRef<Path>>(&self, prog: &str, path: T) -> Result<()> {
+        pin_fd(self.prog(prog)?.fd()?, path.as_ref(), "prog")
+    }
+
+    /// Runs the program once on `data` without attaching it and returns its return value.
+    pub fn test_run(&self, prog: &str, data: &[u8]) -> Result<u32> {
+        self.prog(prog)?.test_run(data)
+    }
+
+    /// Atomically replaces the program of the link with the program
+    /// of the same name of this object.
+    pub fn update_link(&mut self, link: &mut BPFLink) -> Result<()> {
+        let prog = self.prog(&link.prog)?;
+        match link.hook {
+            Hook::Xdp { fd } => prog.update_xdp_link(fd),
+            Hook::Tc {
+                ifindex,
+                attach_point,
+            } => prog.attach_tc(ifindex, attach_point, true),
         }
     }
 }
@@ -132,8 +330,8 @@ impl Drop for BPFObj {
     }
 }
 
-struct BPFMap {
-    #[allow(dead_code)]
+pub struct BPFMap {
+    // null for maps opened from a pin
     map_ptr: *mut libbpf_sys::bpf_map,
     fd: i32,
     key_size: u32,
@@ -150,6 +348,106 @@ impl BPFMap {
         }
     }
 
+    /// Opens a map pinned at `path` with keys and values of the given sizes.
+    pub fn from_pin<T: AsRef<Path>>(path: T, key_size: u32, val_size: u32) -> Result<Self> {
+        let fd = obj_get(path.as_ref(), "map")?;
+        Ok(BPFMap::new(ptr::null_mut(), fd, key_size, val_size))
+    }
+
+    /// Maximum number of entries of the map, as the kernel created it.
+    pub fn max_entries(&self) -> Result<u32> {
+        let mut info = libbpf_sys::bpf_map_info::default();
+        let mut len = mem::size_of::<libbpf_sys::bpf_map_info>() as u32;
+        let res = unsafe {
+            libbpf_sys::bpf_obj_get_info_by_fd(
+                self.fd,
+                &mut info as *mut _ as *mut c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            bail!("error {}: failed to get the info of the map", errno());
+        }
+        Ok(info.max_entries)
+    }
+
+    /// Opens the map again, the new map is not closed with its object.
+    pub fn try_clone(&self) -> Result<Self> {
+        let fd = unsafe { libc::dup(self.fd) };
+        if fd < 0 {
+            bail!("error {}: failed to duplicate map fd", errno());
+        }
+        Ok(BPFMap::new(
+            ptr::null_mut(),
+            fd,
+            self.key_size,
+            self.val_size,
+        ))
+    }
+
+    pub fn pin<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        pin_fd(self.fd, path.as_ref(), "map")
+    }
+
+    /// Returns the value of every CPU for a per-CPU map, `None` if the key is not in the map.
+    pub fn lookup_percpu(&self, key: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        if key.len() != self.key_size as usize {
+            bail!("invalid key size for map");
+        };
+
+        let cpus = unsafe { libbpf_sys::libbpf_num_possible_cpus() };
+        if cpus < 0 {
+            bail!("error {}: failed to get the number of CPUs", -cpus);
+        }
+
+        // the value of each CPU is padded to 8 bytes
+        let size = (self.val_size as usize).div_ceil(8) * 8;
+        let mut values = vec![0u8; size * cpus as usize];
+        let res = unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                self.fd,
+                key.as_ptr() as *const c_void,
+                values.as_mut_ptr() as *mut c_void,
+            )
+        };
+
+        if res < 0 {
+            match errno() {
+                libc::ENOENT => Ok(None),
+                e => bail!("error {}: failed to look up the map", e),
+            }
+        } else {
+            Ok(Some(
+                values
+                    .chunks(size)
+                    .map(|v| v[..self.val_size as usize].to_vec())
+                    .collect(),
+            ))
+        }
+    }
+
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        loop {
+            let prev = match keys.last() {
+                Some(k) => k.as_ptr() as *const c_void,
+                None => ptr::null(),
+            };
+            let mut next = vec![0u8; self.key_size as usize];
+            let res = unsafe {
+                libbpf_sys::bpf_map_get_next_key(self.fd, prev, next.as_mut_ptr() as *mut c_void)
+            };
+
+            if res < 0 {
+                match errno() {
+                    libc::ENOENT => return Ok(keys),
+                    e => bail!("error {}: failed to iterate over the map", e),
+                }
+            }
+            keys.push(next);
+        }
+    }
+
     fn update_map(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<()> {
         if key.len() != self.key_size as usize {
             bail!("invalid key size for map");
@@ -174,6 +472,246 @@ impl BPFMap {
             Ok(())
         }
     }
+
+    fn delete_elem(&mut self, key: &[u8]) -> Result<()> {
+        if key.len() != self.key_size as usize {
+            bail!("invalid key size for map");
+        };
+
+        let res =
+            unsafe { libbpf_sys::bpf_map_delete_elem(self.fd, key.as_ptr() as *const c_void) };
+
+        if res < 0 {
+            bail!("failed to delete from the map {}", res);
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct RingBuffer {
+    ptr: *mut libbpf_sys::ring_buffer,
+    // filled by `ring_buffer_sample` while polling
+    samples: *mut Vec<Vec<u8>>,
+    // keeps the map open for as long as the ring buffer
+    _map: BPFMap,
+}
+
+impl RingBuffer {
+    pub fn new(map: &BPFMap) -> Result<Self> {
+        let map = map.try_clone()?;
+        let samples = Box::into_raw(Box::new(Vec::new()));
+
+        let ptr = unsafe {
+            libbpf_sys::ring_buffer__new(
+                map.fd,
+                Some(ring_buffer_sample),
+                samples as *mut c_void,
+                ptr::null(),
+            )
+        };
+        let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
+        if err != 0 {
+            unsafe { drop(Box::from_raw(samples)) };
+            bail!("error {}: failed to create ring buffer", err as i32);
+        }
+
+        Ok(RingBuffer {
+            ptr,
+            samples,
+            _map: map,
+        })
+    }
+
+    /// Waits up to `timeout` for samples and returns the ones available.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<Vec<u8>>> {
+        let res = unsafe { libbpf_sys::ring_buffer__poll(self.ptr, timeout.as_millis() as i32) };
+        if res < 0 && -res != libc::EINTR {
+            bail!("error {}: failed to poll ring buffer", -res);
+        }
+        Ok(mem::take(unsafe { &mut *self.samples }))
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libbpf_sys::ring_buffer__free(self.ptr);
+            drop(Box::from_raw(self.samples));
+        }
+    }
+}
+
+unsafe extern "C" fn ring_buffer_sample(
+    ctx: *mut c_void,
+    data: *mut c_void,
+    size: libbpf_sys::size_t,
+) -> i32 {
+    let samples = &mut *(ctx as *mut Vec<Vec<u8>>);
+    samples.push(slice::from_raw_parts(data as *const u8, size as usize).to_vec());
+    0
+}
+
+// the maps of an object are closed with it
+impl Drop for BPFMap {
+    fn drop(&mut self) {
+        if self.map_ptr.is_null() {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+/// Id of the program attached to the XDP hook of the interface in the given
+/// `XDP_FLAGS_*` mode, in any mode if none is given. 0 if there is none.
+pub fn xdp_prog_id(ifindex: i32, mode: u32) -> Result<u32> {
+    let mut id = 0;
+    let res = unsafe { libbpf_sys::bpf_get_link_xdp_id(ifindex, &mut id, mode) };
+    if res < 0 {
+        bail!(
+            "error {}: failed to query the xdp hook of {}",
+            -res,
+            ifindex
+        );
+    }
+    Ok(id)
+}
+
+/// Id of the program that a `BPFObj` attached to the TC hook of the interface, 0 if there is none.
+pub fn tc_prog_id(ifindex: i32, attach_point: TcHook) -> Result<u32> {
+    let hook = attach_point.hook(ifindex);
+    let mut opts = tc_opts(0, 0);
+    let res = unsafe { libbpf_sys::bpf_tc_query(&hook, &mut opts) };
+    match -res {
+        0 => Ok(opts.prog_id),
+        // no filter or no clsact qdisc
+        libc::ENOENT | libc::EINVAL => Ok(0),
+        e => bail!("error {}: failed to query the tc hook of {}", e, ifindex),
+    }
+}
+
+/// Detaches the filter that a `BPFObj` attached to the TC hook of the interface, if any.
+pub fn detach_tc(ifindex: i32, attach_point: TcHook) -> Result<()> {
+    let hook = attach_point.hook(ifindex);
+    let opts = tc_opts(0, 0);
+    let res = unsafe { libbpf_sys::bpf_tc_detach(&hook, &opts) };
+    match -res {
+        0 | libc::ENOENT | libc::EINVAL => Ok(()),
+        e => bail!(
+            "error {}: failed to detach from the tc hook of {}",
+            e,
+            ifindex
+        ),
+    }
+}
+
+/// Id of the program pinned at `path`.
+pub fn pinned_prog_id<T: AsRef<Path>>(path: T) -> Result<u32> {
+    let fd = obj_get(path.as_ref(), "prog")?;
+    let mut info = libbpf_sys::bpf_prog_info::default();
+    let mut len = mem::size_of::<libbpf_sys::bpf_prog_info>() as u32;
+    let res = unsafe {
+        libbpf_sys::bpf_obj_get_info_by_fd(fd, &mut info as *mut _ as *mut c_void, &mut len)
+    };
+    unsafe {
+        libc::close(fd);
+    }
+    if res < 0 {
+        bail!(
+            "error {}: failed to get the info of the pinned prog",
+            errno()
+        );
+    }
+    Ok(info.id)
+}
+
+// `what` is the kind of object, for errors
+fn pin_fd(fd: i32, path: &Path, what: &str) -> Result<()> {
+    let str_path = path.to_str().ok_or(anyhow!("invalid unicode in path"))?;
+    let c_path = CString::new(str_path)?;
+
+    let res = unsafe { libbpf_sys::bpf_obj_pin(fd, c_path.as_ptr()) };
+    if res < 0 {
+        bail!("error {}: failed to pin {} at {}", errno(), what, str_path);
+    }
+    Ok(())
+}
+
+fn obj_get(path: &Path, what: &str) -> Result<i32> {
+    let str_path = path.to_str().ok_or(anyhow!("invalid unicode in path"))?;
+    let c_path = CString::new(str_path)?;
+
+    let fd = unsafe { libbpf_sys::bpf_obj_get(c_path.as_ptr()) };
+    if fd < 0 {
+        bail!(
+            "error {}: failed to open pinned {} {}",
+            errno(),
+            what,
+            str_path
+        );
+    }
+    Ok(fd)
+}
+
+// detaches the prog attached without a link to the hook in `mode`, or in the only mode
+// used if 0, and returns an fd that keeps it loaded and the mode to attach it again
+fn detach_xdp(ifindex: i32, mode: u32) -> Result<Option<(i32, u32)>> {
+    let mut info = libbpf_sys::xdp_link_info::default();
+    let res = unsafe {
+        libbpf_sys::bpf_get_link_xdp_info(
+            ifindex,
+            &mut info,
+            mem::size_of::<libbpf_sys::xdp_link_info>() as libbpf_sys::size_t,
+            0,
+        )
+    };
+    if res < 0 {
+        bail!(
+            "error {}: failed to query the xdp hook of {}",
+            -res,
+            ifindex
+        );
+    }
+
+    let (id, mode) = match (mode, info.attach_mode as u32) {
+        (libbpf_sys::XDP_FLAGS_SKB_MODE, _) => (info.skb_prog_id, mode),
+        (libbpf_sys::XDP_FLAGS_DRV_MODE, _) => (info.drv_prog_id, mode),
+        (libbpf_sys::XDP_FLAGS_HW_MODE, _) => (info.hw_prog_id, mode),
+        (_, libbpf_sys::XDP_ATTACHED_SKB) => (info.prog_id, libbpf_sys::XDP_FLAGS_SKB_MODE),
+        (_, libbpf_sys::XDP_ATTACHED_DRV) => (info.prog_id, libbpf_sys::XDP_FLAGS_DRV_MODE),
+        (_, libbpf_sys::XDP_ATTACHED_HW) => (info.prog_id, libbpf_sys::XDP_FLAGS_HW_MODE),
+        (_, libbpf_sys::XDP_ATTACHED_MULTI) => {
+            bail!("progs are attached in several xdp modes of {}", ifindex)
+        }
+        _ => return Ok(None),
+    };
+    if id == 0 {
+        return Ok(None);
+    }
+
+    let prog_fd = unsafe { libbpf_sys::bpf_prog_get_fd_by_id(id) };
+    if prog_fd < 0 {
+        bail!("error {}: could not get the attached prog", errno());
+    }
+    let res = unsafe { libbpf_sys::bpf_set_link_xdp_fd(ifindex, -1, mode) };
+    if res < 0 {
+        unsafe {
+            libc::close(prog_fd);
+        }
+        match -res {
+            libc::EBUSY => bail!(
+                "a prog is attached with a link to the xdp hook of {}, it cannot be replaced",
+                ifindex
+            ),
+            e => bail!("error {}: could not detach the attached prog", e),
+        }
+    }
+    Ok(Some((prog_fd, mode)))
+}
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
 }
 
 struct BPFProg {
@@ -185,13 +723,116 @@ impl BPFProg {
         BPFProg { ptr: ptr }
     }
 
-    fn attach_xdp(&mut self, ifindex: i32) -> Result<BPFLink> {
-        let ptr = unsafe { libbpf_sys::bpf_program__attach_xdp(self.ptr, ifindex) };
-        let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
-        if err != 0 {
-            bail!("error {}: could not attach prog to xdp hook", err as i32);
+    fn fd(&self) -> Result<i32> {
+        let fd = unsafe { libbpf_sys::bpf_program__fd(self.ptr) };
+        if fd < 0 {
+            bail!("error {}: failed to get file descriptor", -fd);
         }
+        Ok(fd)
+    }
 
-        Ok(BPFLink { ptr })
+    // returns the fd of the link
+    fn attach_xdp(&self, ifindex: i32, flags: u32) -> Result<i32> {
+        let fd = self.fd()?;
+
+        // links only take the mode
+        let mode = flags & libbpf_sys::XDP_FLAGS_MODES;
+        let previous = if flags & libbpf_sys::XDP_FLAGS_UPDATE_IF_NOEXIST == 0 {
+            detach_xdp(ifindex, mode)?
+        } else {
+            None
+        };
+
+        let opts = libbpf_sys::bpf_link_create_opts {
+            sz: mem::size_of::<libbpf_sys::bpf_link_create_opts>() as libbpf_sys::size_t,
+            flags: mode,
+            ..Default::default()
+        };
+        let link_fd =
+            unsafe { libbpf_sys::bpf_link_create(fd, ifindex, libbpf_sys::BPF_XDP, &opts) };
+        let err = errno();
+        if let Some((prog_fd, prog_mode)) = previous {
+            if link_fd < 0 {
+                // the error of the link is the one returned if this fails too
+                unsafe {
+                    libbpf_sys::bpf_set_link_xdp_fd(
+                        ifindex,
+                        prog_fd,
+                        prog_mode | libbpf_sys::XDP_FLAGS_UPDATE_IF_NOEXIST,
+                    );
+                }
+            }
+            unsafe {
+                libc::close(prog_fd);
+            }
+        }
+        if link_fd < 0 {
+            match err {
+                libc::EBUSY | libc::EEXIST => {
+                    bail!("a prog is already attached to the xdp hook of {}", ifindex)
+                }
+                libc::EOPNOTSUPP => bail!("the driver does not support this xdp mode"),
+                e => bail!("error {}: could not attach prog to xdp hook", e),
+            }
+        }
+
+        Ok(link_fd)
+    }
+
+    fn attach_tc(&self, ifindex: i32, attach_point: TcHook, replace: bool) -> Result<()> {
+        let fd = self.fd()?;
+
+        // the qdisc is shared with other filters and is left in place once detached
+        let mut hook = attach_point.hook(ifindex);
+        let res = unsafe { libbpf_sys::bpf_tc_hook_create(&mut hook) };
+        if res < 0 && -res != libc::EEXIST {
+            bail!(
+                "error {}: could not create the clsact qdisc of {}",
+                -res,
+                ifindex
+            );
+        }
+
+        let flags = if replace {
+            libbpf_sys::BPF_TC_F_REPLACE
+        } else {
+            0
+        };
+        let mut opts = tc_opts(fd, flags);
+        let res = unsafe { libbpf_sys::bpf_tc_attach(&hook, &mut opts) };
+        if res < 0 {
+            match -res {
+                libc::EEXIST => bail!("a prog is already attached to the tc hook of {}", ifindex),
+                e => bail!("error {}: could not attach prog to tc hook", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn test_run(&self, data: &[u8]) -> Result<u32> {
+        let fd = self.fd()?;
+
+        let mut opts = libbpf_sys::bpf_test_run_opts {
+            sz: mem::size_of::<libbpf_sys::bpf_test_run_opts>() as libbpf_sys::size_t,
+            data_in: data.as_ptr() as *const c_void,
+            data_size_in: data.len() as u32,
+            repeat: 1,
+            ..Default::default()
+        };
+        let res = unsafe { libbpf_sys::bpf_prog_test_run_opts(fd, &mut opts) };
+        if res < 0 {
+            bail!("error {}: failed to test run prog", errno());
+        }
+        Ok(opts.retval)
+    }
+
+    fn update_xdp_link(&self, link_fd: i32) -> Result<()> {
+        let fd = self.fd()?;
+        let res = unsafe { libbpf_sys::bpf_link_update(link_fd, fd, ptr::null()) };
+        if res < 0 {
+            bail!("error {}: could not update the prog of the link", errno());
+        }
+        Ok(())
     }
 }
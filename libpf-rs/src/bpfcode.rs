@@ -13,8 +13,46 @@ pub const DEFINES: &str = "\
 #define ETH_P_IPV6 0x86DD\n\
 #define IPPROTO_UDP 17\n\
 #define IPPROTO_TCP 6\n\
+#define IPPROTO_ICMP 1\n\
+#define IPPROTO_ICMPV6 58\n\
+#define PROTO_ANY 256\n\
 #define IPV6_ADDR_LEN 16\n\
-#define NOOP 0\n";
+#define NOOP 0\n\
+#define PORT_OP_NONE 0\n\
+#define PORT_OP_EQ 1\n\
+#define PORT_OP_NE 2\n\
+#define PORT_OP_LT 3\n\
+#define PORT_OP_LE 4\n\
+#define PORT_OP_GT 5\n\
+#define PORT_OP_GE 6\n\
+#define PORT_OP_RANGE 7\n\
+#define PORT_OP_WITHIN 8\n\
+#define PORT_OP_OUTSIDE 9\n\
+#define TH_FIN 0x01\n\
+#define TH_SYN 0x02\n\
+#define TH_RST 0x04\n\
+#define TH_PSH 0x08\n\
+#define TH_ACK 0x10\n\
+#define TH_URG 0x20\n\
+#define TH_ECE 0x40\n\
+#define TH_CWR 0x80\n\
+#define NEGATE_SADDR 0x1\n\
+#define NEGATE_DADDR 0x2\n\
+#define NEGATE_SPORT 0x4\n\
+#define NEGATE_DPORT 0x8\n\
+#define ICMP_MATCH_TYPE 0x1\n\
+#define ICMP_MATCH_CODE 0x2\n\
+#define LOG_BUFFER_SIZE (256 * 1024)\n\
+#define STATS_DEFAULT_ACTION 0\n\
+#define STATS_PARSE_FAILURE 1\n\
+#define STATE_NONE 0\n\
+#define STATE_PASS 1\n\
+#define STATE_DROP 2\n\
+#define TC_ACT_OK 0\n\
+#define TC_ACT_SHOT 2\n\
+#define DIR_IN 1\n\
+#define DIR_OUT 2\n\
+#define MAX_INTERFACES 1024\n";
 
 pub const STRUCTS: &str = "\
 struct ip4_addr {
@@ -27,12 +65,37 @@ struct ip6_addr {
     __u8 daddr[16];
 };
 
+// ports are in host byte order
+struct port_range {
+    __u16 op;
+    __u16 lo;
+    __u16 hi;
+};
+
 struct rule {
+    __u32 id;
     __u32 action;
     __u32 quick;
+    __u32 keep_state;
+    __u32 log;
+    // 0 if the rule applies to both directions
+    __u32 direction;
+    // id of the interfaces given with `on`, 0 if the rule applies to all of them,
+    // the index of the interface for packets
+    __u32 iface;
     __u32 proto;
-    __be16 sport;
-    __be16 dport;
+    __u32 negate;
+    __u32 stable;
+    __u32 dtable;
+    struct port_range sport;
+    struct port_range dport;
+    __u16 tcp_flags;
+    __u16 tcp_flags_mask;
+    __u8 icmp_type;
+    __u8 icmp_code;
+    __u16 icmp_match;
+    __u32 sprefix;
+    __u32 dprefix;
 
     struct ip4_addr ip4_addr;
     struct ip6_addr ip6_addr;
@@ -41,19 +104,224 @@ struct rule {
 pub const IP4RULES_MAPS: &str = r#"\
 struct {
     __uint(type, BPF_MAP_TYPE_ARRAY);
-    __uint(max_entries, IPV4_RULE_COUNT);
+    __uint(max_entries, 4 * RULE_CAPACITY);
     __type(key, __u32);
     __type(value, struct rule);
 } ipv4_rules SEC(".maps");"#;
 
+// the rule maps hold two rulesets and `active_ruleset` tells which one is in use,
+// a new ruleset is written next to the active one and swapped in with a single update.
+// Each ruleset is split into the rules of incoming and of outgoing packets, rules without
+// a direction are in both, so that a program only walks the rules of its direction
+pub const RULESET_MAPS: &str = r#"
+struct ruleset {
+    __u32 offset;
+    __u32 ipv4_count;
+    __u32 ipv6_count;
+    __u32 default_action;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 4);
+    __type(key, __u32);
+    __type(value, struct ruleset);
+} rulesets SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, __u32);
+    __type(value, __u32);
+} active_ruleset SEC(".maps");
+
+// rules of the active ruleset for packets of `direction`, DIR_IN or DIR_OUT
+static struct ruleset *get_ruleset(__u32 direction)
+{
+    __u32 key = 0;
+    __u32 *active = bpf_map_lookup_elem(&active_ruleset, &key);
+
+    if (!active)
+        return NULL;
+    key = *active * 2 + direction - 1;
+    return bpf_map_lookup_elem(&rulesets, &key);
+}"#;
+
+// counters summed over all CPUs by userspace, `last_hit` is in ns since boot
+pub const STATS_MAPS: &str = r#"
+struct counters {
+    __u64 packets;
+    __u64 bytes;
+    __u64 last_hit;
+};
+
+// keyed by rule id, room for the rules of both rulesets
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_HASH);
+    __uint(max_entries, 4 * RULE_CAPACITY);
+    __type(key, __u32);
+    __type(value, struct counters);
+} rule_stats SEC(".maps");
+
+// indexed by STATS_DEFAULT_ACTION and STATS_PARSE_FAILURE
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 2);
+    __type(key, __u32);
+    __type(value, struct counters);
+} filter_stats SEC(".maps");
+
+static void count_packet(struct counters *stats, __u64 bytes)
+{
+    if (!stats)
+        return;
+    stats->packets++;
+    stats->bytes += bytes;
+    stats->last_hit = bpf_ktime_get_ns();
+}
+
+static void count_rule(__u32 id, __u64 bytes)
+{
+    struct counters zero = {0};
+    struct counters *stats = bpf_map_lookup_elem(&rule_stats, &id);
+
+    if (!stats) {
+        // keeps the counters if another CPU added the entry first
+        bpf_map_update_elem(&rule_stats, &id, &zero, BPF_NOEXIST);
+        stats = bpf_map_lookup_elem(&rule_stats, &id);
+    }
+    count_packet(stats, bytes);
+}
+
+static void count_filter(__u32 index, __u64 bytes)
+{
+    count_packet(bpf_map_lookup_elem(&filter_stats, &index), bytes);
+}"#;
+
+// events of the packets decided on by rules with `log`, ports are in host byte order
+pub const LOG_MAPS: &str = r#"
+struct log_event {
+    __u64 timestamp;
+    __u32 rule_id;
+    __u32 action;
+    __u32 ip_version;
+    __u32 proto;
+    struct ip4_addr ip4_addr;
+    struct ip6_addr ip6_addr;
+    __u16 sport;
+    __u16 dport;
+    __u32 len;
+    __u32 snaplen;
+    __u8 data[LOG_SNAPLEN];
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, LOG_BUFFER_SIZE);
+} log_events SEC(".maps");
+
+static void log_packet(void *data, void *data_end, __u64 len, int ip_version, struct rule *packet)
+{
+    struct log_event *event = bpf_ringbuf_reserve(&log_events, sizeof(*event), 0);
+
+    // the event is lost if userspace does not keep up
+    if (!event)
+        return;
+
+    event->timestamp = bpf_ktime_get_ns();
+    event->rule_id = packet->id;
+    event->action = packet->action;
+    event->ip_version = ip_version == bpf_htons(ETH_P_IP) ? 4 : 6;
+    event->proto = packet->proto;
+    event->ip4_addr = packet->ip4_addr;
+    event->ip6_addr = packet->ip6_addr;
+    event->sport = packet->sport.lo;
+    event->dport = packet->dport.lo;
+    event->len = len;
+
+    // first bytes of the frame, starting with the Ethernet header
+    event->snaplen = 0;
+    for (int i = 0; i < LOG_SNAPLEN; i++) {
+        __u8 *byte = data + i;
+        if ((void *)(byte + 1) > data_end)
+            break;
+        event->data[i] = *byte;
+        event->snaplen++;
+    }
+
+    bpf_ringbuf_submit(event, 0);
+}"#;
+
 pub const IP6RULES_MAPS: &str = r#"\
 struct {
     __uint(type, BPF_MAP_TYPE_ARRAY);
-    __uint(max_entries, IPV6_RULE_COUNT);
+    __uint(max_entries, 4 * RULE_CAPACITY);
     __type(key, __u32);
     __type(value, struct rule);
 } ipv6_rules SEC(".maps");"#;
 
+pub const TABLE_MAPS: &str = r#"
+struct ipv4_table_key {
+    __u32 prefixlen;
+    __u32 table;
+    __u32 addr;
+};
+
+struct ipv6_table_key {
+    __u32 prefixlen;
+    __u32 table;
+    __u8 addr[IPV6_ADDR_LEN];
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __uint(max_entries, TABLE_SIZE);
+    __type(key, struct ipv4_table_key);
+    __type(value, __u8);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} ipv4_tables SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LPM_TRIE);
+    __uint(max_entries, TABLE_SIZE);
+    __type(key, struct ipv6_table_key);
+    __type(value, __u8);
+    __uint(map_flags, BPF_F_NO_PREALLOC);
+} ipv6_tables SEC(".maps");"#;
+
+pub const TABLE_FUNCS: &str = r##"
+// the table id is part of the prefix so the lookup only matches entries of that table
+static int in_ipv4_table(__u32 table, __u32 addr)
+{
+    struct ipv4_table_key key = {
+        .prefixlen = 32 + 32,
+        .table = table,
+        .addr = addr,
+    };
+    return bpf_map_lookup_elem(&ipv4_tables, &key) != NULL;
+}
+
+static int in_ipv6_table(__u32 table, __u8 addr[IPV6_ADDR_LEN])
+{
+    struct ipv6_table_key key = {
+        .prefixlen = 32 + 128,
+        .table = table,
+    };
+    __builtin_memcpy(key.addr, addr, IPV6_ADDR_LEN);
+    return bpf_map_lookup_elem(&ipv6_tables, &key) != NULL;
+}"##;
+
+pub const TABLE_NOOP: &str = r#"
+static int in_ipv4_table(__u32 table, __u32 addr)
+{
+    return 0;
+}
+
+static int in_ipv6_table(__u32 table, __u8 addr[IPV6_ADDR_LEN])
+{
+    return 0;
+}"#;
+
 pub const PARSERS: &str = r##"
 struct hdr_cursor {
     void *pos;
@@ -138,8 +406,292 @@ static int parse_tcphdr(struct hdr_cursor *nh, void *data_end, struct tcphdr **t
     nh->pos += hdrsize;
     *tcphdr = tcph;
     return 0;
+}
+
+static int parse_icmphdr(struct hdr_cursor *nh, void *data_end, struct icmphdr **icmphdr)
+{
+    struct icmphdr *icmph = nh->pos;
+
+    if (icmph + 1 > data_end)
+        return -1;
+
+    nh->pos = icmph + 1;
+    *icmphdr = icmph;
+    return 0;
+}
+
+static int parse_icmp6hdr(struct hdr_cursor *nh, void *data_end, struct icmp6hdr **icmp6hdr)
+{
+    struct icmp6hdr *icmp6h = nh->pos;
+
+    if (icmp6h + 1 > data_end)
+        return -1;
+
+    nh->pos = icmp6h + 1;
+    *icmp6hdr = icmp6h;
+    return 0;
+}
+
+static int port_matches(struct port_range *rule, __u16 port)
+{
+    switch (rule->op) {
+    case PORT_OP_EQ:
+        return port == rule->lo;
+    case PORT_OP_NE:
+        return port != rule->lo;
+    case PORT_OP_LT:
+        return port < rule->lo;
+    case PORT_OP_LE:
+        return port <= rule->lo;
+    case PORT_OP_GT:
+        return port > rule->lo;
+    case PORT_OP_GE:
+        return port >= rule->lo;
+    case PORT_OP_RANGE:
+        return port >= rule->lo && port <= rule->hi;
+    case PORT_OP_WITHIN:
+        return port > rule->lo && port < rule->hi;
+    case PORT_OP_OUTSIDE:
+        return port < rule->lo || port > rule->hi;
+    }
+    return 0;
+}
+
+static int eval_port(struct port_range *rule, __u32 proto, __u16 port, int negate)
+{
+    if (rule->op == PORT_OP_NONE)
+        return 1;
+
+    // only TCP and UDP packets have ports, even for negated ports
+    if (proto != IPPROTO_TCP && proto != IPPROTO_UDP)
+        return 0;
+
+    return port_matches(rule, port) != negate;
+}
+
+static int eval_tcp_flags(struct rule *rule, struct rule *pack)
+{
+    if (rule->tcp_flags_mask == 0)
+        return 1;
+    return pack->proto == IPPROTO_TCP &&
+           (pack->tcp_flags & rule->tcp_flags_mask) == rule->tcp_flags;
+}
+
+static int eval_icmp(struct rule *rule, struct rule *pack)
+{
+    // the rule protocol already tells ICMP and ICMPv6 apart
+    if ((rule->icmp_match & ICMP_MATCH_TYPE) && rule->icmp_type != pack->icmp_type)
+        return 0;
+    if ((rule->icmp_match & ICMP_MATCH_CODE) && rule->icmp_code != pack->icmp_code)
+        return 0;
+    return 1;
+}"##;
+
+// the other FLOW_* states are defined from `TcpState`
+pub const STATE_MAPS: &str = r#"
+#define FLOW_NONE 0
+
+#define FIN_INITIATOR 1
+#define FIN_RESPONDER 2
+
+// addresses and ports of a connection, IPv4 addresses use the first 4 bytes
+struct flow {
+    __u8 saddr[16];
+    __u8 daddr[16];
+    __u16 sport;
+    __u16 dport;
+    __u32 proto;
+};
+
+struct flow_state {
+    __u64 last_seen;
+    // only used by TCP connections
+    __u32 tcp_state;
+    __u32 fin;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, STATE_TABLE_SIZE);
+    __type(key, struct flow);
+    __type(value, struct flow_state);
+} state_table SEC(".maps");"#;
+
+pub const STATE_FUNCS: &str = r##"
+static void get_flow(int ip_version, struct rule *pack, struct flow *flow)
+{
+    flow->proto = pack->proto;
+    flow->sport = pack->sport.lo;
+    flow->dport = pack->dport.lo;
+    if (ip_version == bpf_htons(ETH_P_IP)) {
+        __builtin_memcpy(flow->saddr, &pack->ip4_addr.saddr, sizeof(__be32));
+        __builtin_memcpy(flow->daddr, &pack->ip4_addr.daddr, sizeof(__be32));
+    } else {
+        __builtin_memcpy(flow->saddr, pack->ip6_addr.saddr, IPV6_ADDR_LEN);
+        __builtin_memcpy(flow->daddr, pack->ip6_addr.daddr, IPV6_ADDR_LEN);
+    }
+}
+
+static void reverse_flow(struct flow *flow, struct flow *rev)
+{
+    rev->proto = flow->proto;
+    rev->sport = flow->dport;
+    rev->dport = flow->sport;
+    __builtin_memcpy(rev->saddr, flow->daddr, IPV6_ADDR_LEN);
+    __builtin_memcpy(rev->daddr, flow->saddr, IPV6_ADDR_LEN);
+}
+
+static __u64 state_timeout(struct flow_state *state)
+{
+    switch (state->tcp_state) {
+    case FLOW_SYN_SENT:
+        return TCP_SYN_SENT_TIMEOUT;
+    case FLOW_ESTABLISHED:
+        return TCP_ESTABLISHED_TIMEOUT;
+    case FLOW_FIN_WAIT:
+        return TCP_FIN_WAIT_TIMEOUT;
+    case FLOW_CLOSED:
+        return TCP_CLOSED_TIMEOUT;
+    }
+    return STATE_TIMEOUT;
+}
+
+// moves the connection to its next state, returns STATE_DROP if the packet does not fit,
+// `one_way` is set for the XDP program, which never sees the packets the host sends
+static int update_tcp_state(struct flow_state *state, __u8 flags, int from_initiator, int one_way)
+{
+    __u8 syn_ack = flags & (TH_SYN | TH_ACK);
+
+    if (flags & TH_RST) {
+        state->tcp_state = FLOW_CLOSED;
+        return STATE_PASS;
+    }
+
+    switch (state->tcp_state) {
+    case FLOW_SYN_SENT:
+        // retransmitted SYN
+        if (from_initiator && syn_ack == TH_SYN)
+            return STATE_PASS;
+        if (!from_initiator && syn_ack == (TH_SYN | TH_ACK)) {
+            state->tcp_state = FLOW_ESTABLISHED;
+            return STATE_PASS;
+        }
+        // the host's SYN+ACK went out unseen, the initiator's ACK ends the handshake
+        if (one_way && from_initiator && syn_ack == TH_ACK) {
+            state->tcp_state = FLOW_ESTABLISHED;
+            return STATE_PASS;
+        }
+        return STATE_DROP;
+    case FLOW_ESTABLISHED:
+    case FLOW_FIN_WAIT:
+        // only a retransmitted SYN+ACK is allowed once the handshake is done
+        if ((flags & TH_SYN) && (from_initiator || syn_ack != (TH_SYN | TH_ACK)))
+            return STATE_DROP;
+        if (flags & TH_FIN) {
+            state->fin |= from_initiator ? FIN_INITIATOR : FIN_RESPONDER;
+            state->tcp_state = FLOW_FIN_WAIT;
+        }
+        if (state->fin == (FIN_INITIATOR | FIN_RESPONDER))
+            state->tcp_state = FLOW_CLOSED;
+        return STATE_PASS;
+    case FLOW_CLOSED:
+        // a new connection with the same addresses and ports goes through the rules again
+        if (from_initiator && syn_ack == TH_SYN)
+            return STATE_NONE;
+        if (flags & TH_SYN)
+            return STATE_DROP;
+        return STATE_PASS;
+    }
+    return STATE_DROP;
+}
+
+// returns STATE_PASS or STATE_DROP if the packet belongs to a tracked connection
+static int check_state(int ip_version, struct rule *pack, __u8 tcp_flags, int one_way)
+{
+    struct flow flow = {0};
+    struct flow rev = {0};
+    struct flow *key = &flow;
+    struct flow_state *state;
+    int from_initiator = 1;
+    int res = STATE_PASS;
+    __u64 now = bpf_ktime_get_ns();
+
+    get_flow(ip_version, pack, &flow);
+    reverse_flow(&flow, &rev);
+
+    state = bpf_map_lookup_elem(&state_table, &flow);
+    if (!state) {
+        key = &rev;
+        from_initiator = 0;
+        state = bpf_map_lookup_elem(&state_table, &rev);
+    }
+    if (!state)
+        return STATE_NONE;
+
+    if (now - state->last_seen > state_timeout(state)) {
+        bpf_map_delete_elem(&state_table, key);
+        return STATE_NONE;
+    }
+
+    if (pack->proto == IPPROTO_TCP)
+        res = update_tcp_state(state, tcp_flags, from_initiator, one_way);
+
+    if (res == STATE_NONE)
+        bpf_map_delete_elem(&state_table, key);
+    else if (res == STATE_PASS)
+        state->last_seen = now;
+
+    return res;
+}
+
+// returns the action for the first packet of a connection
+static int save_state(int ip_version, struct rule *pack, __u8 tcp_flags)
+{
+    struct flow flow = {0};
+    struct flow_state state = { .last_seen = bpf_ktime_get_ns() };
+
+    if (pack->proto == IPPROTO_TCP) {
+        // TCP connections can only be tracked from the start of the handshake
+        if ((tcp_flags & (TH_SYN | TH_ACK | TH_RST | TH_FIN)) != TH_SYN)
+            return XDP_DROP;
+        state.tcp_state = FLOW_SYN_SENT;
+    }
+
+    get_flow(ip_version, pack, &flow);
+    bpf_map_update_elem(&state_table, &flow, &state, BPF_ANY);
+    return XDP_PASS;
 }"##;
 
+pub const STATE_NOOP: &str = r#"
+static int check_state(int ip_version, struct rule *pack, __u8 tcp_flags, int one_way)
+{
+    return STATE_NONE;
+}
+
+static int save_state(int ip_version, struct rule *pack, __u8 tcp_flags)
+{
+    return XDP_PASS;
+}"#;
+
+// bit `id - 1` of the value is set if the interface is one of the interfaces with `id`
+pub const IFACE_MAPS: &str = r#"
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_INTERFACES);
+    __type(key, __u32);
+    __type(value, __u64);
+} interfaces SEC(".maps");
+
+static int eval_iface(__u32 iface, __u32 ifindex)
+{
+    __u64 *ifaces;
+
+    if (iface == 0)
+        return 1;
+    ifaces = bpf_map_lookup_elem(&interfaces, &ifindex);
+    return ifaces && ((*ifaces >> (iface - 1)) & 1);
+}"#;
+
 pub const IP4_EVAL_FUNCS: &str = r##"
 static int get_ipv4_rule(int i, struct rule **rule)
 {
@@ -150,14 +702,33 @@ static int get_ipv4_rule(int i, struct rule **rule)
     return 0;
 }
 
-static int eval_ipv4_rule(struct rule *rule, struct rule *pack)
+static __be32 ipv4_mask(__u32 prefix)
 {
-    return (rule->proto == 0 || rule->proto == pack->proto) &&
-           (rule->sport == 0 || rule->sport == pack->sport) &&
-           (rule->dport == 0 || rule->dport == pack->dport) &&
-           (rule->ip4_addr.saddr == 0 || rule->ip4_addr.saddr == pack->ip4_addr.saddr) &&
-           (rule->ip4_addr.daddr == 0 || rule->ip4_addr.daddr == pack->ip4_addr.daddr);
+    // a prefix of length 0 matches any address
+    if (prefix == 0)
+        return 0;
+    return bpf_htonl(0xffffffff << (32 - prefix));
+}
+
+static int eval_ipv4_addr(__u32 table, __u32 rule_addr, __u32 pack_addr, __u32 prefix)
+{
+    if (table)
+        return in_ipv4_table(table, pack_addr);
+    return ((rule_addr ^ pack_addr) & ipv4_mask(prefix)) == 0;
+}
 
+static int eval_ipv4_rule(struct rule *rule, struct rule *pack)
+{
+    return eval_iface(rule->iface, pack->iface) &&
+           (rule->proto == PROTO_ANY || rule->proto == pack->proto) &&
+           eval_port(&rule->sport, pack->proto, pack->sport.lo, (rule->negate & NEGATE_SPORT) != 0) &&
+           eval_port(&rule->dport, pack->proto, pack->dport.lo, (rule->negate & NEGATE_DPORT) != 0) &&
+           eval_tcp_flags(rule, pack) &&
+           eval_icmp(rule, pack) &&
+           eval_ipv4_addr(rule->stable, rule->ip4_addr.saddr, pack->ip4_addr.saddr, rule->sprefix) !=
+               ((rule->negate & NEGATE_SADDR) != 0) &&
+           eval_ipv4_addr(rule->dtable, rule->ip4_addr.daddr, pack->ip4_addr.daddr, rule->dprefix) !=
+               ((rule->negate & NEGATE_DADDR) != 0);
 }"##;
 
 pub const IP6_EVAL_FUNCS: &str = r##"
@@ -170,195 +741,245 @@ static int get_ipv6_rule(int i, struct rule **rule)
     return 0;
 }
 
-static int is_zero(const __u8 a[IPV6_ADDR_LEN])
+// compares the first `prefix` bits of both addresses
+static int prefix_equals(__u8 a[IPV6_ADDR_LEN], __u8 b[IPV6_ADDR_LEN], __u32 prefix)
 {
-    for (int i = 0; i < IPV6_ADDR_LEN ; i++) {
-        if (a[i] != 0)
-            return 0;
+    for (int i = 0; i < IPV6_ADDR_LEN; i++) {
+        if (prefix >= 8) {
+            if (a[i] != b[i])
+                return 0;
+            prefix -= 8;
+        } else {
+            __u8 mask = (__u8)(0xff << (8 - prefix));
+            return (a[i] & mask) == (b[i] & mask);
+        }
     }
     return 1;
 }
 
-static int equals(__u8 a[IPV6_ADDR_LEN], __u8 b[IPV6_ADDR_LEN])
+static int eval_ipv6_addr(__u32 table, __u8 rule_addr[IPV6_ADDR_LEN], __u8 pack_addr[IPV6_ADDR_LEN], __u32 prefix)
 {
-    for (int i = 0; i < IPV6_ADDR_LEN; i++) {
-        if (a[i] != b[i])
-            return 0;
-    }
-    return 1;
+    if (table)
+        return in_ipv6_table(table, pack_addr);
+    return prefix_equals(rule_addr, pack_addr, prefix);
 }
 
 static int eval_ipv6_rule(struct rule *rule, struct rule *pack)
 {
-    return (rule->proto == 0 || rule->proto == pack->proto) &&
-           (rule->sport == 0 || rule->sport == pack->sport) &&
-           (rule->dport == 0 || rule->dport == pack->dport) &&
-           (is_zero(rule->ip6_addr.saddr) || equals(rule->ip6_addr.saddr, pack->ip6_addr.saddr)) &&
-           (is_zero(rule->ip6_addr.daddr) || equals(rule->ip6_addr.daddr, pack->ip6_addr.daddr));
+    return eval_iface(rule->iface, pack->iface) &&
+           (rule->proto == PROTO_ANY || rule->proto == pack->proto) &&
+           eval_port(&rule->sport, pack->proto, pack->sport.lo, (rule->negate & NEGATE_SPORT) != 0) &&
+           eval_port(&rule->dport, pack->proto, pack->dport.lo, (rule->negate & NEGATE_DPORT) != 0) &&
+           eval_tcp_flags(rule, pack) &&
+           eval_icmp(rule, pack) &&
+           eval_ipv6_addr(rule->stable, rule->ip6_addr.saddr, pack->ip6_addr.saddr, rule->sprefix) !=
+               ((rule->negate & NEGATE_SADDR) != 0) &&
+           eval_ipv6_addr(rule->dtable, rule->ip6_addr.daddr, pack->ip6_addr.daddr, rule->dprefix) !=
+               ((rule->negate & NEGATE_DADDR) != 0);
 }
 "##;
 
-pub static EVAL_BOTH_IPVER: &str = r#"
-static int eval_rules(int ip_version, struct rule *packet)
+pub const EVAL_RULES: &str = r#"
+static int eval_rules(int ip_version, struct ruleset *ruleset, struct rule *packet)
 {
     struct rule *rule = NULL;
     int action = -1;
     if (ip_version == bpf_htons(ETH_P_IP)) {
-        for (int i=0; i < IPV4_RULE_COUNT; i++) {
-            if (get_ipv4_rule(i, &rule) < 0) {
+        for (int i=0; i < RULE_CAPACITY && i < ruleset->ipv4_count; i++) {
+            if (get_ipv4_rule(ruleset->offset + i, &rule) < 0) {
                 bpf_printk("Error: failed to get rule [index %d]", i);
                 return -1;
             }
             if (eval_ipv4_rule(rule, packet)) {
                 action = rule->action;
+                packet->id = rule->id;
+                packet->keep_state = rule->keep_state;
+                packet->log = rule->log;
+                // first match wins for `quick` rules
+                if (rule->quick)
+                    return action;
             }
         }
     } else if (ip_version == bpf_htons(ETH_P_IPV6)) {
-        for (int i=0; i < IPV6_RULE_COUNT; i++) {
-            if (get_ipv6_rule(i, &rule) < 0) {
-                bpf_printk("Error: failed to get rule [index %d]", i);
-                return -1;
-            }
-            if (eval_ipv6_rule(rule, packet)) {
-                action = rule->action;
-            }
-        }
-    }
-
-    // if action != 1 then it must have matched a rule
-    if (rule && rule->quick && action != -1)
-        return action;
-
-    return action;
-}"#;
-
-pub static EVAL_ONLY_IP6: &str = r#"
-static int eval_rules(int ip_version, struct rule *packet)
-{
-    struct rule *rule = NULL;
-    int action = -1;
-    if (ip_version == bpf_htons(ETH_P_IPV6)) {
-        for (int i=0; i < IPV6_RULE_COUNT; i++) {
-            if (get_ipv6_rule(i, &rule) < 0) {
+        for (int i=0; i < RULE_CAPACITY && i < ruleset->ipv6_count; i++) {
+            if (get_ipv6_rule(ruleset->offset + i, &rule) < 0) {
                 bpf_printk("Error: failed to get rule [index %d]", i);
                 return -1;
             }
             if (eval_ipv6_rule(rule, packet)) {
                 action = rule->action;
+                packet->id = rule->id;
+                packet->keep_state = rule->keep_state;
+                packet->log = rule->log;
+                // first match wins for `quick` rules
+                if (rule->quick)
+                    return action;
             }
         }
     }
 
-    // if action != 1 then it must have matched a rule
-    if (rule && rule->quick && action != -1)
-        return action;
-
+    // otherwise the last matching rule wins
     return action;
 }"#;
 
-pub static EVAL_ONLY_IP4: &str = r#"
-static int eval_rules(int ip_version, struct rule *packet)
-{
-    struct rule *rule = NULL;
-    int action = -1;
-    if (ip_version == bpf_htons(ETH_P_IP)) {
-        for (int i=0; i < IPV4_RULE_COUNT; i++) {
-            if (get_ipv4_rule(i, &rule) < 0) {
-                bpf_printk("Error: failed to get rule [index %d]", i);
-                return -1;
-            }
-            if (eval_ipv4_rule(rule, packet)) {
-                action = rule->action;
-            }
-        }
-    }
-
-    // if action != 1 then it must have matched a rule
-    if (rule && rule->quick && action != -1)
-        return action;
-
-    return action;
-}"#;
-
-pub static EVAL_NOOP: &str = r#"
-static int eval_rules(int ip_version, struct rule *packet)
-{
-    return -1;
-}"#;
-
+// shared by the XDP and TC programs, returns XDP_PASS or XDP_DROP,
+// `one_way` is set if the program only sees incoming packets
 pub const PROGRAM: &str = r##"
-static void print_rule(struct rule *rule)
+static __always_inline int filter_packet(void *data, void *data_end, __u64 bytes, __u32 direction,
+                                         __u32 ifindex, int one_way)
 {
-    bpf_printk("action [ %u ] (DROP: 1) (PASS: 2)", rule->action);
-    bpf_printk("proto [ %u ]", rule->proto);
-    bpf_printk("ports [ src %u ] [ dst %u ]", bpf_ntohs(rule->sport), bpf_ntohs(rule->dport));
-    bpf_printk("ipv4 [ src %pI4 ] [ dst %pI4 ]", &(rule->ip4_addr.saddr), &(rule->ip4_addr.daddr));
-    bpf_printk("ipv6 [ src %pI6 ]", &rule->ip6_addr.saddr);
-    bpf_printk("ipv6 [ dst %pI6 ]", &rule->ip6_addr.daddr);
-}
-
-SEC("xdp")
-int xdp_pf(struct xdp_md *ctx)
-{
-    void *data = (void *)(long)ctx->data;
-    void *data_end = (void *)(long)ctx->data_end;
-
     // L2, L3 & L4 structures
     struct ethhdr *ethhdr;
     struct iphdr *iphdr;
     struct ipv6hdr *ipv6hdr;
     struct udphdr *udphdr;
     struct tcphdr *tcphdr;
+    struct icmphdr *icmphdr;
+    struct icmp6hdr *icmp6hdr;
 
     int action;
     int proto;
     __be16 sport = 0;
     __be16 dport = 0;
+    __u8 tcp_flags = 0;
+    __u8 icmp_type = 0;
+    __u8 icmp_code = 0;
     struct ip4_addr ip4 = {0};
     struct ip6_addr ip6 = {0};
     struct hdr_cursor nh = { .pos = data };
+    struct ruleset *ruleset = get_ruleset(direction);
     int ip_version = parse_ethhdr(&nh, data_end, &ethhdr);
 
+    // the ruleset is written before the program is attached
+    if (!ruleset)
+        return XDP_PASS;
+
     // ETH_P_IP(0x0800) and ETH_P_IPV6(0x86DD)
     if (ip_version == bpf_htons(ETH_P_IP)) {
         if ((proto = parse_ip4hdr(&nh, data_end, &iphdr)) < 0)
-            goto out;
+            goto parse_failure;
         ip4.saddr = iphdr->saddr;
         ip4.daddr = iphdr->daddr;
     } else if (ip_version == bpf_htons(ETH_P_IPV6)) {
         if ((proto = parse_ip6hdr(&nh, data_end, &ipv6hdr)) < 0)
-            goto out;
+            goto parse_failure;
         __builtin_memcpy(ip6.saddr, ipv6hdr->saddr.in6_u.u6_addr8, sizeof ipv6hdr->saddr.in6_u.u6_addr8);
         __builtin_memcpy(ip6.daddr, ipv6hdr->daddr.in6_u.u6_addr8, sizeof ipv6hdr->daddr.in6_u.u6_addr8);
     } else {
         goto out;
     }
 
-    // parse UDP and tcp
+    // parse UDP, TCP and ICMP
     if (proto == IPPROTO_UDP) {
         if (parse_udphdr(&nh, data_end, &udphdr) == -1)
-            goto out;
+            goto parse_failure;
         sport = udphdr->source;
         dport = udphdr->dest;
     } else if (proto == IPPROTO_TCP) {
         if (parse_tcphdr(&nh, data_end, &tcphdr) == -1)
-            goto out;
+            goto parse_failure;
         sport = tcphdr->source;
         dport = tcphdr->dest;
+        // FIN, SYN, RST, PSH, ACK, URG, ECE and CWR are the 14th byte of the header
+        tcp_flags = ((__u8 *)tcphdr)[13];
+    } else if (proto == IPPROTO_ICMP) {
+        if (parse_icmphdr(&nh, data_end, &icmphdr) == -1)
+            goto parse_failure;
+        icmp_type = icmphdr->type;
+        icmp_code = icmphdr->code;
+    } else if (proto == IPPROTO_ICMPV6) {
+        if (parse_icmp6hdr(&nh, data_end, &icmp6hdr) == -1)
+            goto parse_failure;
+        icmp_type = icmp6hdr->icmp6_type;
+        icmp_code = icmp6hdr->icmp6_code;
     }
 
     // eval packet against rules
-    struct rule packet = { NOOP, NOOP, proto, sport, dport, ip4, ip6 };
-    if ((action = eval_rules(ip_version ,&packet)) >= 0) {
+    struct rule packet = {
+        .action = NOOP,
+        .quick = NOOP,
+        .keep_state = NOOP,
+        .log = NOOP,
+        .direction = direction,
+        .iface = ifindex,
+        .proto = proto,
+        .sport.lo = bpf_ntohs(sport),
+        .dport.lo = bpf_ntohs(dport),
+        .tcp_flags = tcp_flags,
+        .icmp_type = icmp_type,
+        .icmp_code = icmp_code,
+        .ip4_addr = ip4,
+        .ip6_addr = ip6,
+    };
+
+    // packets of tracked connections skip the rules
+    switch (check_state(ip_version, &packet, tcp_flags, one_way)) {
+    case STATE_PASS:
+        return XDP_PASS;
+    case STATE_DROP:
+        return XDP_DROP;
+    }
+
+    if ((action = eval_rules(ip_version, ruleset, &packet)) >= 0) {
         // (struct rule) packet has info about (net) packet except action
         // so we add action only for logging purposes
         packet.action = action;
-        print_rule(&packet);
+        if (packet.log)
+            log_packet(data, data_end, bytes, ip_version, &packet);
+        count_rule(packet.id, bytes);
+        if (action == XDP_PASS && packet.keep_state)
+            return save_state(ip_version, &packet, tcp_flags);
         return action;
     }
+    goto out;
+
+    parse_failure:
+    count_filter(STATS_PARSE_FAILURE, bytes);
     out:
     // default action
-    return DEFAULT_ACTION;
+    count_filter(STATS_DEFAULT_ACTION, bytes);
+    return ruleset->default_action;
+}
+
+SEC("xdp")
+int xdp_pf(struct xdp_md *ctx)
+{
+    void *data = (void *)(long)ctx->data;
+    void *data_end = (void *)(long)ctx->data_end;
+
+    return filter_packet(data, data_end, data_end - data, DIR_IN, ctx->ingress_ifindex, 1);
+}
+
+// Ethernet header followed by IPv4 and TCP headers with the most options
+#define TC_HEADERS_LEN (sizeof(struct ethhdr) + 60 + 60)
+
+// the headers are pulled into the linear part of the buffer since the filter only reads
+// from there, `ifindex` is the interface the packet is received or sent on
+static __always_inline int tc_filter(struct __sk_buff *skb, __u32 direction)
+{
+    void *data;
+    void *data_end;
+
+    // if the pull fails, headers that are not in the linear part count as parse failures
+    bpf_skb_pull_data(skb, skb->len < TC_HEADERS_LEN ? skb->len : TC_HEADERS_LEN);
+    data = (void *)(long)skb->data;
+    data_end = (void *)(long)skb->data_end;
+
+    if (filter_packet(data, data_end, skb->len, direction, skb->ifindex, 0) == XDP_DROP)
+        return TC_ACT_SHOT;
+    return TC_ACT_OK;
+}
+
+SEC("tc")
+int tc_pf_in(struct __sk_buff *skb)
+{
+    return tc_filter(skb, DIR_IN);
+}
+
+SEC("tc")
+int tc_pf_out(struct __sk_buff *skb)
+{
+    return tc_filter(skb, DIR_OUT);
 }
 
 char __license[] SEC("license") = "GPL";
@@ -8,4 +8,6 @@ pub enum Error {
     Internal(String),
     #[error("invalid input: {0}")]
     InvalidInput(String),
+    #[error("already attached: {0}")]
+    AlreadyAttached(String),
 }
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+use crate::ip::ToSockAddr;
+use crate::proto;
+use crate::rule::{self, Action, Direction};
+
+pub(crate) const IPPROTO_ICMP: u8 = 1;
+pub(crate) const IPPROTO_TCP: u8 = 6;
+pub(crate) const IPPROTO_UDP: u8 = 17;
+pub(crate) const IPPROTO_ICMPV6: u8 = 58;
+
+/// The fields of a packet that rules match on, as parsed by the filter.
+///
+/// Ports are only read for TCP and UDP packets, flags for TCP packets
+/// and type and code for ICMP and ICMPv6 packets. Packets are inbound unless
+/// set otherwise with `with_direction` and only match rules without `on` unless
+/// their interface is set with `with_ifindex`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PacketMeta {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: u8,
+    pub sport: u16,
+    pub dport: u16,
+    pub tcp_flags: u8,
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub direction: Direction,
+    /// Index of the interface the packet goes through, 0 if unknown.
+    pub ifindex: i32,
+}
+
+impl PacketMeta {
+    /// A TCP packet with no flags set, e.g. `PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22")`.
+    pub fn tcp<T: ToSockAddr>(src: T, dst: T) -> Result<Self> {
+        PacketMeta::with_ports(IPPROTO_TCP, src, dst)
+    }
+
+    pub fn udp<T: ToSockAddr>(src: T, dst: T) -> Result<Self> {
+        PacketMeta::with_ports(IPPROTO_UDP, src, dst)
+    }
+
+    /// An ICMP packet, or an ICMPv6 one if the addresses are IPv6 addresses.
+    pub fn icmp<T: ToSockAddr>(src: T, dst: T, icmp_type: u8, icmp_code: u8) -> Result<Self> {
+        let mut packet = PacketMeta::with_ports(IPPROTO_ICMP, src, dst)?;
+        if packet.src.is_ipv6() {
+            packet.proto = IPPROTO_ICMPV6;
+        }
+        packet.sport = 0;
+        packet.dport = 0;
+        packet.icmp_type = icmp_type;
+        packet.icmp_code = icmp_code;
+        Ok(packet)
+    }
+
+    /// Sets the TCP flags by their letters, e.g. `"S"` for the first packet of a handshake.
+    pub fn with_tcp_flags<T: AsRef<str>>(mut self, flags: T) -> Result<Self> {
+        self.tcp_flags = rule::parse_tcp_flags(flags.as_ref())? as u8;
+        Ok(self)
+    }
+
+    /// Sets whether the packet is received or sent by the interface.
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    pub fn with_ifindex(mut self, ifindex: i32) -> Self {
+        self.ifindex = ifindex;
+        self
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.src.is_ipv6()
+    }
+
+    fn with_ports<T: ToSockAddr>(proto: u8, src: T, dst: T) -> Result<Self> {
+        let src = src
+            .to_sock_addr()
+            .map_err(|e| Error::InvalidInput(e.to_string()))?;
+        let dst = dst
+            .to_sock_addr()
+            .map_err(|e| Error::InvalidInput(e.to_string()))?;
+        if src.is_ipv6() != dst.is_ipv6() {
+            bail!(Error::InvalidInput(format!(
+                "packet from {} to {}: addresses must be of the same IP version",
+                src, dst
+            )));
+        }
+
+        Ok(PacketMeta {
+            src: src.ip(),
+            dst: dst.ip(),
+            proto,
+            sport: src.port(),
+            dport: dst.port(),
+            tcp_flags: 0,
+            icmp_type: 0,
+            icmp_code: 0,
+            direction: Direction::In,
+            ifindex: 0,
+        })
+    }
+}
+
+// same as the packets of `pf log`, e.g. `proto tcp 10.0.0.1:40000 > 10.0.0.2:22`
+impl fmt::Display for PacketMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match proto::proto_name(self.proto) {
+            Some(name) => write!(f, "proto {}", name)?,
+            None => write!(f, "proto {}", self.proto)?,
+        }
+        write!(
+            f,
+            " {} > {}",
+            SocketAddr::new(self.src, self.sport),
+            SocketAddr::new(self.dst, self.dport)
+        )
+    }
+}
+
+/// What the filter does with a packet and which rule decided it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Verdict {
+    pub action: Action,
+    /// Id the rule gets once the filter is loaded, `None` for the default action.
+    pub rule: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PacketMeta, IPPROTO_ICMPV6, IPPROTO_TCP};
+
+    #[test]
+    fn packets_from_addresses() {
+        let packet = PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22")
+            .unwrap()
+            .with_tcp_flags("SA")
+            .unwrap();
+        assert_eq!(packet.proto, IPPROTO_TCP);
+        assert_eq!((packet.sport, packet.dport), (40000, 22));
+        assert_eq!(packet.tcp_flags, 0x12);
+        assert!(!packet.is_ipv6());
+
+        let packet = PacketMeta::icmp("::1", "::2", 128, 0).unwrap();
+        assert_eq!(packet.proto, IPPROTO_ICMPV6);
+        assert_eq!(packet.icmp_type, 128);
+
+        assert!(PacketMeta::udp("10.0.0.1", "::1").is_err());
+        assert!(PacketMeta::tcp("10.0.0.1", "10.0.0.2")
+            .unwrap()
+            .with_tcp_flags("X")
+            .is_err());
+    }
+}
@@ -1,76 +1,490 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::Path;
+use std::mem;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::Serialize;
 use tempfile::tempdir;
 
-use crate::bpf::{BPFLink, BPFObj};
+use crate::bpf::{BPFLink, BPFMap, BPFObj, TcHook};
 use crate::bpfcode::{
-    DEFINES, EVAL_BOTH_IPVER, EVAL_NOOP, EVAL_ONLY_IP4, EVAL_ONLY_IP6, INCLUDE_HEADERS,
-    IP4RULES_MAPS, IP4_EVAL_FUNCS, IP6RULES_MAPS, IP6_EVAL_FUNCS, PARSERS, PROGRAM, STRUCTS,
-    VMLINUX,
+    DEFINES, EVAL_RULES, IFACE_MAPS, INCLUDE_HEADERS, IP4RULES_MAPS, IP4_EVAL_FUNCS, IP6RULES_MAPS,
+    IP6_EVAL_FUNCS, LOG_MAPS, PARSERS, PROGRAM, RULESET_MAPS, STATE_FUNCS, STATE_MAPS, STATE_NOOP,
+    STATS_MAPS, STRUCTS, TABLE_FUNCS, TABLE_MAPS, TABLE_NOOP, VMLINUX,
 };
 use crate::error::Error;
-use crate::rule::{Action, InnerRule, RawRule, Rule};
-use crate::{bpf, compile};
+use crate::eval::{PacketMeta, Verdict};
+use crate::ip::ToIpNet;
+use crate::log::{LogReader, LOG_MAP};
+use crate::rule::{Action, Direction, InnerRule, RawRule, Rule};
+use crate::stats::{self, FilterStats, RuleStats, FILTER_STATS_MAP, RULE_STATS_MAP};
+use crate::table::{self, Table, IPV4_TABLES_MAP, IPV6_TABLES_MAP};
+use crate::{bpf, compile, iface};
 
-#[derive(Debug)]
+const DEFAULT_RULE_CAPACITY: usize = 256;
+const DEFAULT_STATE_TABLE_SIZE: u32 = 65536;
+const DEFAULT_TABLE_SIZE: u32 = 65536;
+const DEFAULT_LOG_SNAPLEN: u32 = 128;
+const XDP_PROG: &str = "xdp_pf";
+const TC_INGRESS_PROG: &str = "tc_pf_in";
+const TC_EGRESS_PROG: &str = "tc_pf_out";
+const IFACE_MAP: &str = "interfaces";
+const STATE_MAP: &str = "state_table";
+// interfaces, groups and patterns given with `on`, one bit each in `IFACE_MAP`
+const MAX_IFACE_IDS: usize = 64;
+// maps that other processes read from
+const PINNED_MAPS: [&str; 3] = [RULE_STATS_MAP, FILTER_STATS_MAP, LOG_MAP];
+// pinned by `LoadedFilter::pin_attached`, the state table only exists if rules use `keep state`
+const ATTACHED_MAPS: [&str; 4] = [RULE_STATS_MAP, FILTER_STATS_MAP, LOG_MAP, STATE_MAP];
+// carried over by `Filter::reattach`, rule ids start from 1 again so the rule counters do not
+const REATTACHED_MAPS: [&str; 3] = [FILTER_STATS_MAP, LOG_MAP, STATE_MAP];
+const PINNED_LINK: &str = "link";
+// each hook has its own program so that rules only see packets of their direction
+const TC_PROGS: [(&str, TcHook); 2] = [
+    (TC_INGRESS_PROG, TcHook::Ingress),
+    (TC_EGRESS_PROG, TcHook::Egress),
+];
+const DEFAULT_STATE_TIMEOUT: Duration = Duration::from_secs(60);
+// same defaults as OpenBSD's pf, indexed by `TcpState::index`
+const DEFAULT_TCP_TIMEOUTS: [Duration; 4] = [
+    Duration::from_secs(30),
+    Duration::from_secs(24 * 60 * 60),
+    Duration::from_secs(45),
+    Duration::from_secs(90),
+];
+
+/// States of a TCP connection tracked by a `keep state` rule.
+///
+/// They have the values of the states of the entries of the state table,
+/// where 0 is a connection that is not TCP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TcpState {
+    SynSent = 1,
+    Established = 2,
+    FinWait = 3,
+    Closed = 4,
+}
+
+impl TcpState {
+    // of the timeouts of the state
+    fn index(self) -> usize {
+        self as usize - 1
+    }
+}
+
+/// Where the filter is attached to an interface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    /// Filters incoming packets only, before the kernel allocates anything for them.
+    Xdp,
+    /// Filters incoming and outgoing packets from the ingress and egress hooks of the
+    /// clsact qdisc, which is created if needed.
+    Tc,
+}
+
+/// Where in the network stack the XDP program runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XdpMode {
+    /// Native mode if the driver supports it, generic mode otherwise.
+    Auto,
+    /// Generic mode, after the kernel allocated the socket buffer. Works with any driver.
+    Skb,
+    /// Native mode, in the driver before any allocation.
+    Drv,
+    /// Offloaded to the NIC.
+    Hw,
+}
+
+/// How `Filter::load_on` attaches the program to an interface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AttachOptions {
+    hook: Hook,
+    mode: XdpMode,
+    replace: bool,
+}
+
+impl AttachOptions {
+    /// Attaches to XDP in the mode the kernel picks and fails if a program is already attached.
+    pub fn new() -> Self {
+        AttachOptions {
+            hook: Hook::Xdp,
+            mode: XdpMode::Auto,
+            replace: false,
+        }
+    }
+
+    pub fn hook(self, hook: Hook) -> Self {
+        AttachOptions { hook, ..self }
+    }
+
+    /// Only applies to `Hook::Xdp`.
+    pub fn mode(self, mode: XdpMode) -> Self {
+        AttachOptions { mode, ..self }
+    }
+
+    /// Replaces a program already attached instead of failing. With `Hook::Xdp`, only programs
+    /// attached without a link, e.g. with `ip link`, can be replaced, other filters cannot.
+    /// The device is unfiltered while the program is swapped, which is attached again if the
+    /// filter fails to attach.
+    /// With `Hook::Tc`, other filters are replaced and filters of other tools are left in place.
+    pub fn replace(self) -> Self {
+        AttachOptions {
+            replace: true,
+            ..self
+        }
+    }
+
+    fn mode_flags(&self) -> u32 {
+        match self.mode {
+            XdpMode::Auto => 0,
+            XdpMode::Skb => bpf::XDP_FLAGS_SKB_MODE,
+            XdpMode::Drv => bpf::XDP_FLAGS_DRV_MODE,
+            XdpMode::Hw => bpf::XDP_FLAGS_HW_MODE,
+        }
+    }
+
+    fn xdp_flags(&self) -> u32 {
+        if self.replace {
+            self.mode_flags()
+        } else {
+            self.mode_flags() | bpf::XDP_FLAGS_UPDATE_IF_NOEXIST
+        }
+    }
+}
+
+impl Default for AttachOptions {
+    fn default() -> Self {
+        AttachOptions::new()
+    }
+}
+
+/// What the program tells the kernel to do with a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XdpAction {
+    Aborted = 0,
+    Drop = 1,
+    Pass = 2,
+    Tx = 3,
+    Redirect = 4,
+}
+
+impl XdpAction {
+    fn from_raw(action: u32) -> Result<Self> {
+        match action {
+            0 => Ok(XdpAction::Aborted),
+            1 => Ok(XdpAction::Drop),
+            2 => Ok(XdpAction::Pass),
+            3 => Ok(XdpAction::Tx),
+            4 => Ok(XdpAction::Redirect),
+            a => bail!(Error::Internal(format!("unknown XDP action {}", a))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Filter {
-    default_act: Action,
-    ipv4_rules: Vec<RawRule>,
-    ipv6_rules: Vec<RawRule>,
+    // in evaluation order and with the ids of their tables
+    rules: Vec<InnerRule>,
+    rule_capacity: usize,
+    state_table_size: u32,
+    state_timeout: Duration,
+    tcp_timeouts: [Duration; 4],
+    // the id of a table is its position plus one, 0 means no table
+    tables: Vec<Table>,
+    table_size: u32,
+    log_snaplen: u32,
+    // what rules give with `on`, the id of each is its position plus one
+    ifaces: Vec<String>,
 }
 
 impl Filter {
     pub fn new() -> Self {
         Filter {
-            default_act: Action::Pass,
-            ipv4_rules: Vec::new(),
-            ipv6_rules: Vec::new(),
+            rules: Vec::new(),
+            rule_capacity: DEFAULT_RULE_CAPACITY,
+            state_table_size: DEFAULT_STATE_TABLE_SIZE,
+            state_timeout: DEFAULT_STATE_TIMEOUT,
+            tcp_timeouts: DEFAULT_TCP_TIMEOUTS,
+            tables: Vec::new(),
+            table_size: DEFAULT_TABLE_SIZE,
+            log_snaplen: DEFAULT_LOG_SNAPLEN,
+            ifaces: Vec::new(),
+        }
+    }
+
+    /// Number of rules of each IP version that the loaded filter can hold,
+    /// rules added to the `LoadedFilter` must fit in it.
+    pub fn set_rule_capacity(&mut self, rules: usize) {
+        self.rule_capacity = rules;
+    }
+
+    /// Maximum number of connections tracked by `keep state` rules.
+    /// Once full, the least recently used connections are evicted.
+    pub fn set_state_table_size(&mut self, entries: u32) {
+        self.state_table_size = entries;
+    }
+
+    /// Time after which an idle connection is no longer tracked.
+    /// TCP connections use the timeout of their current state instead.
+    pub fn set_state_timeout(&mut self, timeout: Duration) {
+        self.state_timeout = timeout;
+    }
+
+    pub fn set_tcp_timeout(&mut self, state: TcpState, timeout: Duration) {
+        self.tcp_timeouts[state.index()] = timeout;
+    }
+
+    /// Maximum number of entries of all tables of each IP version,
+    /// including the ones added once the filter is loaded.
+    pub fn set_table_size(&mut self, entries: u32) {
+        self.table_size = entries;
+    }
+
+    /// Number of bytes of each frame copied to the events of rules with `log`, 0 for none.
+    pub fn set_log_snaplen(&mut self, bytes: u32) {
+        self.log_snaplen = bytes;
+    }
+
+    /// Adds a table that rules can reference by name.
+    /// Replaces the entries of an existing table with the same name.
+    pub fn add_table(&mut self, table: Table) {
+        match self.tables.iter_mut().find(|t| t.name() == table.name()) {
+            Some(t) => *t = table,
+            None => self.tables.push(table),
         }
     }
 
     pub fn add_rule(&mut self, rule: Rule) {
-        match rule.get_rule() {
-            InnerRule::IPv6Rule(r) => self.ipv6_rules.push(r),
-            InnerRule::IPv4Rule(r) => self.ipv4_rules.push(r),
-            InnerRule::DefaultRule(a) => self.default_act = a,
+        let (stable, dtable) = rule.tables();
+        let (stable, dtable) = (self.table_id(stable), self.table_id(dtable));
+        let iface = iface_id(&mut self.ifaces, rule.ifname());
+
+        let mut rule = rule.get_rule();
+        rule.set_tables(stable, dtable);
+        rule.set_iface(iface);
+        self.rules.push(rule);
+    }
+
+    // tables referenced before being added start empty
+    fn table_id(&mut self, name: Option<&str>) -> u32 {
+        let name = match name {
+            Some(name) => name,
+            None => return 0,
+        };
+
+        match self.tables.iter().position(|t| t.name() == name) {
+            Some(i) => i as u32 + 1,
+            None => {
+                self.tables.push(Table::new(name));
+                self.tables.len() as u32
+            }
         }
     }
 
-    pub fn load_on(self, ifindex: i32) -> Result<BPFLink> {
-        let mut bpf_obj = self
-            .generate_and_load()
-            .map_err(|e| Error::Internal(e.to_string()))?;
+    /// Action of the packets no rule matches, set by the last `pass all` or `block all`.
+    pub fn default_action(&self) -> Action {
+        default_action(&self.rules)
+    }
 
-        for (i, rule) in self.ipv4_rules.into_iter().enumerate() {
-            let initial_value =
-                bincode2::serialize(&rule).map_err(|e| Error::Internal(e.to_string()))?;
-            let index =
-                bincode2::serialize(&(i as u32)).map_err(|e| Error::Internal(e.to_string()))?;
-            bpf_obj
-                .update_map("ipv4_rules", &index, &initial_value, 0)
-                .map_err(|e| Error::Internal(e.to_string()))?;
+    /// Runs the rules on a packet the way the loaded filter would, without loading anything.
+    ///
+    /// Connections tracked by `keep state` rules are not taken into account,
+    /// every packet is evaluated as if it started a new connection.
+    pub fn evaluate(&self, packet: &PacketMeta) -> Verdict {
+        let in_table = |id: u32, addr: IpAddr| {
+            self.tables[id as usize - 1]
+                .entries()
+                .iter()
+                .any(|net| net.contains(addr))
+        };
+        let ifaces = match packet.ifindex {
+            0 => 0,
+            ifindex => iface::name(ifindex)
+                .map(|name| iface_mask(&self.ifaces, &name, &egress(&self.ifaces)))
+                .unwrap_or(0),
+        };
+        let on_iface = |id: u32| (ifaces >> (id - 1)) & 1 != 0;
+
+        let mut verdict = Verdict {
+            action: self.default_action(),
+            rule: None,
+        };
+        for (i, rule) in self.rules.iter().enumerate() {
+            let rule = match (rule, packet.is_ipv6()) {
+                (InnerRule::IPv4Rule(r), false)
+                | (InnerRule::IPv6Rule(r), true)
+                | (InnerRule::IPRule(r), _) => r,
+                _ => continue,
+            };
+
+            // the last matching rule wins unless a `quick` one matches first
+            if rule.matches(packet, &in_table, &on_iface) {
+                verdict = Verdict {
+                    action: rule.action(),
+                    rule: Some(i as u32 + 1),
+                };
+                if rule.is_quick() {
+                    break;
+                }
+            }
         }
+        verdict
+    }
 
-        for (i, rule) in self.ipv6_rules.into_iter().enumerate() {
-            let initial_value =
-                bincode2::serialize(&rule).map_err(|e| Error::Internal(e.to_string()))?;
-            let index =
-                bincode2::serialize(&(i as u32)).map_err(|e| Error::Internal(e.to_string()))?;
+    /// Loads the filter without attaching it and runs it on an Ethernet frame,
+    /// like `packet::build_frame` builds. Needs the same privileges as `load_on`.
+    pub fn test_run(&self, frame: &[u8]) -> Result<XdpAction> {
+        Ok(self.test_run_all(&[frame])?[0])
+    }
 
-            bpf_obj
-                .update_map("ipv6_rules", &index, &initial_value, 0)
-                .map_err(|e| Error::Internal(e.to_string()))?;
+    /// Same as `test_run` for several frames, the filter is only loaded once.
+    ///
+    /// Frames are run in order so that connections tracked by `keep state` rules
+    /// are seen by the frames that follow.
+    pub fn test_run_all<T: AsRef<[u8]>>(&self, frames: &[T]) -> Result<Vec<XdpAction>> {
+        let loaded = self.clone().load(&[])?;
+        frames
+            .iter()
+            .map(|f| {
+                let action = loaded
+                    .bpf_obj
+                    .test_run(XDP_PROG, f.as_ref())
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+                XdpAction::from_raw(action)
+            })
+            .collect()
+    }
+
+    /// Loads the filter and attaches it to the devices with the given indexes, see
+    /// `iface::index`. All devices share the same program, rules, tables and counters.
+    ///
+    /// The rules keep the order in which they were added and get ids starting from 1.
+    pub fn load_on(self, ifindexes: &[i32], opts: AttachOptions) -> Result<LoadedFilter> {
+        // fail before compiling anything
+        if ifindexes.is_empty() {
+            bail!(Error::InvalidInput(
+                "no device to attach the filter to".to_string()
+            ));
+        }
+        check_hook(&self.rules, &opts)?;
+        if !opts.replace {
+            for ifindex in ifindexes {
+                check_not_attached(*ifindex, &opts)?;
+            }
+        }
+
+        let mut loaded = self.load(&[])?;
+
+        // the links attached so far are detached if one of them fails
+        let mut links = Vec::new();
+        for &ifindex in ifindexes {
+            for link in attach(&mut loaded.bpf_obj, ifindex, &opts)? {
+                links.push((ifindex, link));
+            }
+        }
+
+        Ok(LoadedFilter::new(loaded, links, opts))
+    }
+
+    /// Loads the filter and swaps it in for filters pinned with `LoadedFilter::pin_attached`,
+    /// e.g. by a process that exited since, given with the index of their device and the
+    /// directory they are pinned in. The devices stay filtered in between.
+    ///
+    /// The log, the filter counters and the connections tracked by the first pinned filter
+    /// carry over, the connections unless the state table changes size. Rule ids start from
+    /// 1 again and the rule counters start from 0. The new filter is pinned in place of
+    /// the old ones. If it fails to attach to a device, the devices it was swapped in on
+    /// so far keep it and it is pinned on them. They must have been attached with the same
+    /// hook as `opts`.
+    pub fn reattach(self, pinned: &[(i32, PathBuf)], opts: AttachOptions) -> Result<LoadedFilter> {
+        check_hook(&self.rules, &opts)?;
+        for (_, dir) in pinned {
+            check_pinned_hook(dir, &opts)?;
+        }
+
+        let maps = match pinned.first() {
+            Some((_, dir)) => self.pinned_maps(dir),
+            None => Vec::new(),
+        };
+        let reuse = maps
+            .iter()
+            .map(|(name, map)| (*name, map))
+            .collect::<Vec<_>>();
+        let loaded = self.load(&reuse)?;
+        let mut filter = LoadedFilter::new(loaded, Vec::new(), opts);
+
+        let mut res = Ok(());
+        for (ifindex, dir) in pinned {
+            res = filter.swap_pinned(*ifindex, dir);
+            if res.is_err() {
+                break;
+            }
+        }
+        // the pins tell which filter runs on the devices, also if some were not swapped
+        for (ifindex, dir) in pinned {
+            if filter.links.iter().any(|(i, _)| i == ifindex) {
+                res = res.and(filter.pin_attached(*ifindex, dir));
+            }
+        }
+        res.map(|_| filter)
+    }
+
+    // maps pinned in `dir` that this filter can be loaded with instead of new ones
+    fn pinned_maps(&self, dir: &Path) -> Vec<(&'static str, BPFMap)> {
+        REATTACHED_MAPS
+            .into_iter()
+            .filter_map(|name| {
+                // only the fd is used to load the filter with the map
+                let map = BPFMap::from_pin(dir.join(name), 0, 0).ok()?;
+                self.can_reuse(name, &map).then_some((name, map))
+            })
+            .collect()
+    }
+
+    // compiles and loads the program and fills its maps without attaching it
+    fn load(self, reuse: &[(&str, &BPFMap)]) -> Result<Loaded> {
+        if self.ifaces.len() > MAX_IFACE_IDS {
+            bail!(Error::InvalidInput(format!(
+                "rules can be limited to at most {} different interfaces, groups or patterns",
+                MAX_IFACE_IDS
+            )));
         }
 
-        // attach prog
-        let link = bpf_obj
-            .attach_prog(ifindex)
+        let mut bpf_obj = self
+            .generate_and_load(reuse)
             .map_err(|e| Error::Internal(e.to_string()))?;
 
-        Ok(link)
+        for (i, t) in self.tables.iter().enumerate() {
+            for net in t.entries() {
+                let (map, key) = table::table_key(i as u32 + 1, net)?;
+                bpf_obj
+                    .update_map(map, &key, &[1], 0)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+            }
+        }
+
+        let keeps_state = self.keeps_state();
+        let capacity = self.rule_capacity();
+        let rules = self
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| (i as u32 + 1, r))
+            .collect::<Vec<_>>();
+        write_ruleset(&mut bpf_obj, 0, capacity, &rules)?;
+        write_interfaces(&mut bpf_obj, &self.ifaces)?;
+
+        Ok(Loaded {
+            bpf_obj,
+            tables: self.tables,
+            rules,
+            capacity,
+            keeps_state,
+            ifaces: self.ifaces,
+        })
     }
 
     pub fn generate_src(self) -> Result<()> {
@@ -89,7 +503,7 @@ impl Filter {
         Ok(())
     }
 
-    fn generate_and_load(&self) -> Result<BPFObj> {
+    fn generate_and_load(&self, reuse: &[(&str, &BPFMap)]) -> Result<BPFObj> {
         let filename = "pf";
         let src_dir = tempdir().expect("error creating temp dir");
 
@@ -103,8 +517,8 @@ impl Filter {
 
         compile::compile(src_path.as_path(), obj_path.as_path())?;
 
-        let bpf_obj =
-            bpf::BPFObj::load_from_file(obj_path).map_err(|e| Error::Internal(e.to_string()))?;
+        let bpf_obj = bpf::BPFObj::load_from_file(obj_path, reuse)
+            .map_err(|e| Error::Internal(e.to_string()))?;
 
         drop(hdr);
         drop(src);
@@ -119,58 +533,114 @@ impl Filter {
         Ok(bpf_obj)
     }
 
+    fn keeps_state(&self) -> bool {
+        self.rules.iter().any(|r| r.keeps_state())
+    }
+
+    // whether the program can keep using a map of a running filter, which
+    // it only has if the rules need it and must have with the same size,
+    // the log and the counters of the filter are the same in every program
+    fn can_reuse(&self, name: &str, map: &BPFMap) -> bool {
+        let size = match name {
+            LOG_MAP | FILTER_STATS_MAP => return true,
+            STATE_MAP if self.keeps_state() => self.state_table_size,
+            _ => return false,
+        };
+        map.max_entries().is_ok_and(|max| max == size)
+    }
+
+    // there is always room for the rules the filter is loaded with
+    fn rule_capacity(&self) -> usize {
+        self.rule_capacity
+            .max(ipv4_rules(&self.rules).len())
+            .max(ipv6_rules(&self.rules).len())
+    }
+
+    // the sizes and timeouts of the filter and the TCP states shared with `TcpState`
+    fn defines(&self) -> String {
+        let mut defines = format!(
+            "\
+            #define RULE_CAPACITY {}\n\
+            #define STATE_TABLE_SIZE {}\n\
+            #define STATE_TIMEOUT {}ULL\n\
+            #define TABLE_SIZE {}\n\
+            #define LOG_SNAPLEN {}\n",
+            self.rule_capacity(),
+            self.state_table_size,
+            self.state_timeout.as_nanos(),
+            self.table_size,
+            self.log_snaplen
+        );
+        for (state, name) in [
+            (TcpState::SynSent, "SYN_SENT"),
+            (TcpState::Established, "ESTABLISHED"),
+            (TcpState::FinWait, "FIN_WAIT"),
+            (TcpState::Closed, "CLOSED"),
+        ] {
+            defines.push_str(&format!(
+                "#define FLOW_{} {}\n#define TCP_{}_TIMEOUT {}ULL\n",
+                name,
+                state as u32,
+                name,
+                self.tcp_timeouts[state.index()].as_nanos()
+            ));
+        }
+        defines
+    }
+
     fn generate_src_file(&self, path: &Path) -> Result<File> {
         let mut src = File::create(path).map_err(|e| Error::Internal(e.to_string()))?;
         src.write_all(INCLUDE_HEADERS.as_bytes())
             .map_err(|e| Error::Internal(e.to_string()))?;
         src.write_all(DEFINES.as_bytes())
             .map_err(|e| Error::Internal(e.to_string()))?;
-        src.write_all(
-            format!(
-                "\
-            #define DEFAULT_ACTION {}\n\
-            #define IPV4_RULE_COUNT {}\n\
-            #define IPV6_RULE_COUNT {}\n",
-                self.default_act as u32,
-                self.ipv4_rules.len(),
-                self.ipv6_rules.len()
-            )
-            .as_bytes(),
-        )
-        .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(self.defines().as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
         src.write_all(STRUCTS.as_bytes())
             .map_err(|e| Error::Internal(e.to_string()))?;
         src.write_all(PARSERS.as_bytes())
             .map_err(|e| Error::Internal(e.to_string()))?;
 
-        if !self.ipv4_rules.is_empty() {
-            src.write_all(IP4RULES_MAPS.as_bytes())
+        if !self.tables.is_empty() {
+            src.write_all(TABLE_MAPS.as_bytes())
                 .map_err(|e| Error::Internal(e.to_string()))?;
-            src.write_all(IP4_EVAL_FUNCS.as_bytes())
+            src.write_all(TABLE_FUNCS.as_bytes())
+                .map_err(|e| Error::Internal(e.to_string()))?;
+        } else {
+            src.write_all(TABLE_NOOP.as_bytes())
                 .map_err(|e| Error::Internal(e.to_string()))?;
         }
 
-        if !self.ipv6_rules.is_empty() {
-            src.write_all(IP6RULES_MAPS.as_bytes())
+        // rules of both IP versions can be added once the filter is loaded
+        src.write_all(IP4RULES_MAPS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(IP6RULES_MAPS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(RULESET_MAPS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(STATS_MAPS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(LOG_MAPS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(IFACE_MAPS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(IP4_EVAL_FUNCS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        src.write_all(IP6_EVAL_FUNCS.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+
+        if self.keeps_state() {
+            src.write_all(STATE_MAPS.as_bytes())
                 .map_err(|e| Error::Internal(e.to_string()))?;
-            src.write_all(IP6_EVAL_FUNCS.as_bytes())
+            src.write_all(STATE_FUNCS.as_bytes())
+                .map_err(|e| Error::Internal(e.to_string()))?;
+        } else {
+            src.write_all(STATE_NOOP.as_bytes())
                 .map_err(|e| Error::Internal(e.to_string()))?;
         }
 
-        match (self.ipv6_rules.is_empty(), self.ipv4_rules.is_empty()) {
-            (true, true) => src
-                .write_all(EVAL_NOOP.as_bytes())
-                .map_err(|e| Error::Internal(e.to_string()))?,
-            (true, false) => src
-                .write_all(EVAL_ONLY_IP4.as_bytes())
-                .map_err(|e| Error::Internal(e.to_string()))?,
-            (false, true) => src
-                .write_all(EVAL_ONLY_IP6.as_bytes())
-                .map_err(|e| Error::Internal(e.to_string()))?,
-            (false, false) => src
-                .write_all(EVAL_BOTH_IPVER.as_bytes())
-                .map_err(|e| Error::Internal(e.to_string()))?,
-        }
+        src.write_all(EVAL_RULES.as_bytes())
+            .map_err(|e| Error::Internal(e.to_string()))?;
 
         src.write_all(PROGRAM.as_bytes())
             .map_err(|e| Error::Internal(e.to_string()))?;
@@ -179,6 +649,831 @@ impl Filter {
     }
 }
 
+// a filter whose program is loaded but not attached
+struct Loaded {
+    bpf_obj: BPFObj,
+    tables: Vec<Table>,
+    rules: Vec<(u32, InnerRule)>,
+    capacity: usize,
+    keeps_state: bool,
+    ifaces: Vec<String>,
+}
+
+/// A filter attached to one or more interfaces.
+///
+/// Rules and tables can be changed without reloading the program. Rules are identified
+/// by the ids returned when adding them and changes to them are applied atomically,
+/// packets are either evaluated against the old rules or against the new ones.
+///
+/// Each change writes its rules over the ones the previous change replaced, so a packet
+/// still evaluated against those, which takes at most the time of a packet going through
+/// the filter, may see a mix of both rulesets if two changes follow each other that closely.
+pub struct LoadedFilter {
+    bpf_obj: BPFObj,
+    // with the index of their device, detached when dropped
+    links: Vec<(i32, BPFLink)>,
+    opts: AttachOptions,
+    // as loaded, without the entries added at runtime
+    tables: Vec<Table>,
+    ifaces: Vec<String>,
+    rules: Vec<(u32, InnerRule)>,
+    next_id: u32,
+    capacity: usize,
+    // which of the two rulesets in the maps is in use
+    active: u32,
+    keeps_state: bool,
+    // where the maps in `PINNED_MAPS` are pinned
+    pin_dirs: Vec<PathBuf>,
+    // devices whose program, maps and link are pinned, with their directory
+    attached_pins: Vec<(i32, PathBuf)>,
+}
+
+impl LoadedFilter {
+    fn new(loaded: Loaded, links: Vec<(i32, BPFLink)>, opts: AttachOptions) -> Self {
+        LoadedFilter {
+            bpf_obj: loaded.bpf_obj,
+            links,
+            opts,
+            tables: loaded.tables,
+            ifaces: loaded.ifaces,
+            next_id: loaded.rules.len() as u32 + 1,
+            rules: loaded.rules,
+            capacity: loaded.capacity,
+            active: 0,
+            keeps_state: loaded.keeps_state,
+            pin_dirs: Vec::new(),
+            attached_pins: Vec::new(),
+        }
+    }
+
+    /// Replaces the running filter with a new one.
+    ///
+    /// The new program is swapped in atomically on the interfaces so no packet goes
+    /// unfiltered in between. If it fails to compile, load or attach anywhere, the old
+    /// filter is kept on every interface. Rule ids start from 1 again and the counters
+    /// start from 0. Log readers keep reading the new filter's events.
+    ///
+    /// Tracked connections are kept, unless the state table changes size. The entries added
+    /// at runtime to tables that keep their name are copied to the new filter before it is
+    /// swapped in, the old filter keeps its own tables until then. Table entries removed
+    /// from the config are left out.
+    pub fn reload(&mut self, filter: Filter) -> Result<()> {
+        check_hook(&filter.rules, &self.opts)?;
+
+        let mut reuse = vec![(
+            LOG_MAP,
+            self.bpf_obj
+                .map(LOG_MAP)
+                .map_err(|e| Error::Internal(e.to_string()))?,
+        )];
+        if let Ok(map) = self.bpf_obj.map(STATE_MAP) {
+            if filter.can_reuse(STATE_MAP, map) {
+                reuse.push((STATE_MAP, map));
+            }
+        }
+        let mut loaded = filter.load(&reuse)?;
+        copy_table_entries(
+            &self.bpf_obj,
+            &mut loaded.bpf_obj,
+            &self.tables,
+            &loaded.tables,
+        )?;
+
+        for i in 0..self.links.len() {
+            if let Err(e) = loaded.bpf_obj.update_link(&mut self.links[i].1) {
+                // the links swapped so far go back to the old program
+                for (_, link) in self.links[..i].iter_mut() {
+                    let _ = self.bpf_obj.update_link(link);
+                }
+                bail!(Error::Internal(e.to_string()));
+            }
+        }
+
+        // the old program is unloaded once its object is dropped
+        self.bpf_obj = loaded.bpf_obj;
+        self.tables = loaded.tables;
+        self.ifaces = loaded.ifaces;
+        self.next_id = loaded.rules.len() as u32 + 1;
+        self.rules = loaded.rules;
+        self.capacity = loaded.capacity;
+        self.active = 0;
+        self.keeps_state = loaded.keeps_state;
+
+        for dir in self.pin_dirs.clone() {
+            self.pin(dir)?;
+        }
+        for (ifindex, dir) in self.attached_pins.clone() {
+            self.pin_attached(ifindex, dir)?;
+        }
+        Ok(())
+    }
+
+    /// Attaches the filter to one more device with the options it was loaded with,
+    /// e.g. an interface created after the filter was loaded.
+    pub fn attach(&mut self, ifindex: i32) -> Result<()> {
+        if self.links.iter().any(|(i, _)| *i == ifindex) {
+            return Ok(());
+        }
+        if !self.opts.replace {
+            check_not_attached(ifindex, &self.opts)?;
+        }
+        for link in attach(&mut self.bpf_obj, ifindex, &self.opts)? {
+            self.links.push((ifindex, link));
+        }
+        Ok(())
+    }
+
+    /// Detaches the filter from a device, it stays attached to the other ones.
+    /// If it was pinned with `pin_attached` on the device, its pins are removed.
+    pub fn detach(&mut self, ifindex: i32) {
+        let (links, kept) = mem::take(&mut self.links)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(i, _)| *i == ifindex);
+        self.links = kept;
+        for (_, link) in links {
+            let _ = link.detach();
+        }
+
+        if let Some(pos) = self.attached_pins.iter().position(|(i, _)| *i == ifindex) {
+            let (_, dir) = self.attached_pins.remove(pos);
+            let _ = remove_pins(&dir);
+        }
+    }
+
+    /// Indexes of the devices the filter is attached to.
+    pub fn ifindexes(&self) -> Vec<i32> {
+        let mut ifindexes = self.links.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+        ifindexes.dedup();
+        ifindexes
+    }
+
+    /// Looks up the interfaces of the rules with `on` again. To be called when interfaces
+    /// or routes change, see `iface::LinkMonitor`, until then new interfaces match no rule
+    /// with `on` and removed ones keep matching if their index is reused.
+    pub fn update_interfaces(&mut self) -> Result<()> {
+        write_interfaces(&mut self.bpf_obj, &self.ifaces)
+    }
+
+    /// Ids of the rules in evaluation order.
+    pub fn rule_ids(&self) -> Vec<u32> {
+        self.rules.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Inserts a rule at `pos` in the evaluation order and returns its id.
+    pub fn insert_rule(&mut self, pos: usize, rule: Rule) -> Result<u32> {
+        let id = self.next_id;
+        let mut ifaces = self.ifaces.clone();
+        let rule = self.resolve(rule, &mut ifaces)?;
+        let rules = with_rule(&self.rules, pos, id, rule)?;
+        self.add_ifaces(ifaces)?;
+        self.commit(rules)?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    pub fn remove_rule(&mut self, id: u32) -> Result<()> {
+        let rules = without_rule(&self.rules, id)?;
+        self.commit(rules)
+    }
+
+    /// Replaces all rules at once and returns the ids of the new ones.
+    pub fn replace_rules(&mut self, rules: Vec<Rule>) -> Result<Vec<u32>> {
+        let mut ifaces = self.ifaces.clone();
+        let rules = rules
+            .into_iter()
+            .map(|r| self.resolve(r, &mut ifaces))
+            .collect::<Result<Vec<_>>>()?;
+        let rules = with_ids(self.next_id, rules);
+        self.add_ifaces(ifaces)?;
+
+        self.commit(rules)?;
+        self.next_id += self.rules.len() as u32;
+        Ok(self.rule_ids())
+    }
+
+    // the program is compiled with the tables and state tracking it was loaded with,
+    // interfaces that are not known yet get an id in `ifaces` once the rule is valid
+    fn resolve(&self, rule: Rule, ifaces: &mut Vec<String>) -> Result<InnerRule> {
+        let (stable, dtable) = rule.tables();
+        let (stable, dtable) = (self.table_id(stable)?, self.table_id(dtable)?);
+        let ifname = rule.ifname().map(str::to_string);
+
+        let mut rule = rule.get_rule();
+        if rule.keeps_state() && !self.keeps_state {
+            bail!(Error::InvalidInput(
+                "`keep state` needs a filter loaded with `keep state` rules".to_string(),
+            ));
+        }
+        if let Some(name) = ifname.as_deref() {
+            if !ifaces.iter().any(|i| i == name) && ifaces.len() == MAX_IFACE_IDS {
+                bail!(Error::InvalidInput(format!(
+                    "rules can be limited to at most {} different interfaces, groups or patterns",
+                    MAX_IFACE_IDS
+                )));
+            }
+        }
+
+        rule.set_tables(stable, dtable);
+        rule.set_iface(iface_id(ifaces, ifname.as_deref()));
+        Ok(rule)
+    }
+
+    // the interfaces map has to know the interfaces of the rules before they are committed
+    fn add_ifaces(&mut self, ifaces: Vec<String>) -> Result<()> {
+        if ifaces.len() != self.ifaces.len() {
+            write_interfaces(&mut self.bpf_obj, &ifaces)?;
+            self.ifaces = ifaces;
+        }
+        Ok(())
+    }
+
+    fn table_id(&self, name: Option<&str>) -> Result<u32> {
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(0),
+        };
+
+        match self.tables.iter().position(|t| t.name() == name) {
+            Some(i) => Ok(i as u32 + 1),
+            None => bail!(Error::InvalidInput(format!("unknown table <{}>", name))),
+        }
+    }
+
+    // writes the rules next to the active ones and then swaps them in, see the
+    // docs of `LoadedFilter` for packets still evaluated against the older rules
+    fn commit(&mut self, rules: Vec<(u32, InnerRule)>) -> Result<()> {
+        let next = 1 - self.active;
+        write_ruleset(&mut self.bpf_obj, next, self.capacity, &rules)?;
+        self.active = next;
+
+        // a packet still evaluated against the old rules may count them again
+        for (id, _) in self.rules.iter() {
+            if !rules.iter().any(|(i, _)| i == id) {
+                let key = bincode2::serialize(id).map_err(|e| Error::Internal(e.to_string()))?;
+                let _ = self.bpf_obj.delete_map_elem(RULE_STATS_MAP, &key);
+            }
+        }
+
+        self.rules = rules;
+        Ok(())
+    }
+
+    /// Counters of the rules in evaluation order.
+    ///
+    /// `pass all` and `block all` rules never match, the packets that get
+    /// the default action are counted by `filter_stats`.
+    pub fn stats(&self) -> Result<Vec<RuleStats>> {
+        let map = self
+            .bpf_obj
+            .map(RULE_STATS_MAP)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        stats::rule_stats(map, &self.rule_ids())
+    }
+
+    pub fn filter_stats(&self) -> Result<FilterStats> {
+        let map = self
+            .bpf_obj
+            .map(FILTER_STATS_MAP)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        stats::filter_stats(map)
+    }
+
+    /// Pins the counters and the log under `dir`, usually in `/sys/fs/bpf`, so that other
+    /// processes can read them with `stats::read_pinned` and `LogReader::from_pinned`.
+    /// They stay pinned across reloads and are unpinned when the filter is dropped.
+    ///
+    /// Can be called with several directories, e.g. one per interface the filter is attached to.
+    pub fn pin<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        fs::create_dir_all(dir.as_ref()).map_err(|e| Error::Internal(e.to_string()))?;
+
+        for map in PINNED_MAPS {
+            let path = dir.as_ref().join(map);
+            // left behind by a filter that did not exit cleanly
+            let _ = fs::remove_file(&path);
+            self.bpf_obj
+                .map(map)
+                .and_then(|m| m.pin(&path))
+                .map_err(|e| Error::Internal(e.to_string()))?;
+        }
+
+        if !self.pin_dirs.iter().any(|d| d == dir.as_ref()) {
+            self.pin_dirs.push(dir.as_ref().to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Pins the program, the maps and the link of the device `ifindex` under `dir`, usually
+    /// `/sys/fs/bpf/pfrs/<ifname>`, so that the filter stays attached to the device once
+    /// dropped, e.g. after the process exits. `Filter::reattach` replaces it later on and
+    /// `unload` detaches it.
+    ///
+    /// The counters, the log and the state table are pinned too, for `Filter::reattach` to
+    /// carry them over and for other processes to read. Pins are updated on reload.
+    pub fn pin_attached<P: AsRef<Path>>(&mut self, ifindex: i32, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        if !self.links.iter().any(|(i, _)| *i == ifindex) {
+            bail!(Error::InvalidInput(format!(
+                "the filter is not attached to device {}",
+                ifindex
+            )));
+        }
+        fs::create_dir_all(dir).map_err(|e| Error::Internal(e.to_string()))?;
+
+        for name in ATTACHED_MAPS {
+            let path = dir.join(name);
+            // the pins of the filter this one replaced, whether this one has the map or not
+            let _ = fs::remove_file(&path);
+            if let Ok(map) = self.bpf_obj.map(name) {
+                map.pin(&path).map_err(|e| Error::Internal(e.to_string()))?;
+            }
+        }
+
+        for (_, link) in self.links.iter_mut().filter(|(i, _)| *i == ifindex) {
+            let prog = dir.join(link.prog());
+            let _ = fs::remove_file(&prog);
+            self.bpf_obj
+                .pin_prog(link.prog(), &prog)
+                .map_err(|e| Error::Internal(e.to_string()))?;
+
+            let path = dir.join(PINNED_LINK);
+            let _ = fs::remove_file(&path);
+            link.pin(&path)
+                .map_err(|e| Error::Internal(e.to_string()))?;
+        }
+
+        if !self.attached_pins.iter().any(|(_, d)| d == dir) {
+            self.attached_pins.push((ifindex, dir.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    // swaps the filter in for the one pinned in `dir`, the programs swapped in
+    // on the device are in `links` even if it fails
+    fn swap_pinned(&mut self, ifindex: i32, dir: &Path) -> Result<()> {
+        match self.opts.hook {
+            Hook::Xdp => {
+                let mut link = BPFLink::from_pin(dir.join(PINNED_LINK), XDP_PROG)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+                self.bpf_obj
+                    .update_link(&mut link)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+                self.links.push((ifindex, link));
+            }
+            // the pinned filters have the same handle and priority, they are replaced
+            Hook::Tc => {
+                for (prog, attach_point) in TC_PROGS {
+                    let link = self
+                        .bpf_obj
+                        .attach_tc(prog, ifindex, attach_point, true)
+                        .map_err(|e| Error::Internal(e.to_string()))?;
+                    self.links.push((ifindex, link));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a reader of the events of the rules with `log`.
+    pub fn log_reader(&self) -> Result<LogReader> {
+        let map = self
+            .bpf_obj
+            .map(LOG_MAP)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        LogReader::new(map)
+    }
+
+    // the counters and the log of filters pinned with `pin_attached` stay pinned with them
+    fn unpin(&mut self) {
+        for dir in self.pin_dirs.drain(..) {
+            if self.attached_pins.iter().any(|(_, d)| *d == dir) {
+                continue;
+            }
+            for map in PINNED_MAPS {
+                let _ = fs::remove_file(dir.join(map));
+            }
+        }
+    }
+
+    /// Adds an address or subnet to a table of the running filter.
+    pub fn add_table_entry<T: ToIpNet>(&mut self, table: &str, addr: T) -> Result<()> {
+        let (map, key) = self.table_key(table, addr)?;
+        self.bpf_obj
+            .update_map(map, &key, &[1], 0)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Removes an address or subnet from a table of the running filter.
+    pub fn remove_table_entry<T: ToIpNet>(&mut self, table: &str, addr: T) -> Result<()> {
+        let (map, key) = self.table_key(table, addr)?;
+        self.bpf_obj
+            .delete_map_elem(map, &key)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        Ok(())
+    }
+
+    fn table_key<T: ToIpNet>(&self, table: &str, addr: T) -> Result<(&'static str, Vec<u8>)> {
+        let id = match self.tables.iter().position(|t| t.name() == table) {
+            Some(i) => i as u32 + 1,
+            None => bail!(Error::InvalidInput(format!("unknown table <{}>", table))),
+        };
+        table::table_key(id, &table::to_entry(addr)?)
+    }
+}
+
+impl Drop for LoadedFilter {
+    fn drop(&mut self) {
+        self.unpin();
+    }
+}
+
+/// Whether a filter is pinned under `dir` with `LoadedFilter::pin_attached`.
+pub fn is_pinned<P: AsRef<Path>>(dir: P) -> bool {
+    [XDP_PROG, TC_INGRESS_PROG]
+        .iter()
+        .any(|p| dir.as_ref().join(p).exists())
+}
+
+/// Detaches the filter pinned under `dir` with `LoadedFilter::pin_attached` and removes
+/// the directory. `ifindex` is the index of its device, `None` if the device is gone,
+/// in which case the pins are only removed.
+pub fn unload<P: AsRef<Path>>(dir: P, ifindex: Option<i32>) -> Result<()> {
+    let dir = dir.as_ref();
+    if !is_pinned(dir) {
+        bail!(Error::InvalidInput(format!(
+            "no filter is pinned in {}",
+            dir.display()
+        )));
+    }
+
+    let link = dir.join(PINNED_LINK);
+    if link.exists() {
+        BPFLink::from_pin(&link, XDP_PROG)
+            .and_then(|l| l.detach())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+    }
+
+    if let Some(ifindex) = ifindex {
+        for (prog, attach_point) in TC_PROGS {
+            let path = dir.join(prog);
+            if !path.exists() {
+                continue;
+            }
+            let id = bpf::pinned_prog_id(&path).map_err(|e| Error::Internal(e.to_string()))?;
+            // the filter of another tool or process may have replaced it since
+            let attached = bpf::tc_prog_id(ifindex, attach_point)
+                .map_err(|e| Error::Internal(e.to_string()))?;
+            if attached == id {
+                bpf::detach_tc(ifindex, attach_point)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+            }
+        }
+    }
+
+    remove_pins(dir)
+}
+
+// pins are the only files in the directory of a device
+fn remove_pins(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir).map_err(|e| Error::Internal(e.to_string()))? {
+        let path = entry.map_err(|e| Error::Internal(e.to_string()))?.path();
+        fs::remove_file(path).map_err(|e| Error::Internal(e.to_string()))?;
+    }
+    fs::remove_dir(dir).map_err(|e| Error::Internal(e.to_string()))?;
+    Ok(())
+}
+
+// keys of the entries of the tables in the given map
+fn table_keys(tables: &[Table], map: &str) -> Result<Vec<Vec<u8>>> {
+    let mut keys = Vec::new();
+    for (i, t) in tables.iter().enumerate() {
+        for net in t.entries() {
+            let (m, key) = table::table_key(i as u32 + 1, net)?;
+            if m == map {
+                keys.push(key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+// the entries added at runtime to the tables of the old filter go to the table of the same
+// name in the new one, the old filter keeps its own table maps until it is swapped out
+fn copy_table_entries(from: &BPFObj, to: &mut BPFObj, old: &[Table], new: &[Table]) -> Result<()> {
+    for map in [IPV4_TABLES_MAP, IPV6_TABLES_MAP] {
+        // the old filter has no tables
+        let keys = match from.map(map) {
+            Ok(m) => m.keys().map_err(|e| Error::Internal(e.to_string()))?,
+            Err(_) => continue,
+        };
+        let old_keys = table_keys(old, map)?;
+
+        for key in keys.into_iter().filter(|k| !old_keys.contains(k)) {
+            let target = table::key_table(&key)
+                .checked_sub(1)
+                .and_then(|i| old.get(i as usize))
+                .and_then(|t| new.iter().position(|n| n.name() == t.name()));
+            if let Some(i) = target {
+                to.update_map(map, &table::with_table(&key, i as u32 + 1), &[1], 0)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// the rules with `rule` inserted at `pos` under the id `id`
+fn with_rule(
+    rules: &[(u32, InnerRule)],
+    pos: usize,
+    id: u32,
+    rule: InnerRule,
+) -> Result<Vec<(u32, InnerRule)>> {
+    if pos > rules.len() {
+        bail!(Error::InvalidInput(format!(
+            "position {} is past the last rule",
+            pos
+        )));
+    }
+    let mut rules = rules.to_vec();
+    rules.insert(pos, (id, rule));
+    Ok(rules)
+}
+
+fn without_rule(rules: &[(u32, InnerRule)], id: u32) -> Result<Vec<(u32, InnerRule)>> {
+    let mut rules = rules.to_vec();
+    match rules.iter().position(|(i, _)| *i == id) {
+        Some(pos) => rules.remove(pos),
+        None => bail!(Error::InvalidInput(format!("unknown rule {}", id))),
+    };
+    Ok(rules)
+}
+
+// ids are never reused, new rules get the ones following the last id given out
+fn with_ids(next_id: u32, rules: Vec<InnerRule>) -> Vec<(u32, InnerRule)> {
+    rules
+        .into_iter()
+        .enumerate()
+        .map(|(i, r)| (next_id + i as u32, r))
+        .collect()
+}
+
+// same layout as `struct ruleset`
+#[derive(Serialize)]
+struct RawRuleset {
+    offset: u32,
+    ipv4_count: u32,
+    ipv6_count: u32,
+    default_action: u32,
+}
+
+fn ipv4_rules(rules: &[InnerRule]) -> Vec<RawRule> {
+    rules
+        .iter()
+        .filter_map(|r| match r {
+            InnerRule::IPv4Rule(r) | InnerRule::IPRule(r) => Some(*r),
+            _ => None,
+        })
+        .collect()
+}
+
+fn ipv6_rules(rules: &[InnerRule]) -> Vec<RawRule> {
+    rules
+        .iter()
+        .filter_map(|r| match r {
+            InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => Some(*r),
+            _ => None,
+        })
+        .collect()
+}
+
+fn check_not_attached(ifindex: i32, opts: &AttachOptions) -> Result<()> {
+    let ids = match opts.hook {
+        Hook::Xdp => vec![("XDP", bpf::xdp_prog_id(ifindex, opts.mode_flags()))],
+        Hook::Tc => vec![
+            ("TC ingress", bpf::tc_prog_id(ifindex, TcHook::Ingress)),
+            ("TC egress", bpf::tc_prog_id(ifindex, TcHook::Egress)),
+        ],
+    };
+
+    for (hook, id) in ids {
+        match id.map_err(|e| Error::Internal(e.to_string()))? {
+            0 => {}
+            id => bail!(Error::AlreadyAttached(format!(
+                "{} program {} on device {}",
+                hook, id, ifindex
+            ))),
+        }
+    }
+    Ok(())
+}
+
+// the last `pass all` or `block all` wins
+fn default_action(rules: &[InnerRule]) -> Action {
+    rules
+        .iter()
+        .rev()
+        .find_map(|r| match r {
+            InnerRule::DefaultRule(a) => Some(*a),
+            _ => None,
+        })
+        .unwrap_or(Action::Pass)
+}
+
+// interfaces given with `on` that are not known yet get the next id
+fn iface_id(ifaces: &mut Vec<String>, name: Option<&str>) -> u32 {
+    let name = match name {
+        Some(name) => name,
+        None => return 0,
+    };
+
+    match ifaces.iter().position(|i| i == name) {
+        Some(i) => i as u32 + 1,
+        None => {
+            ifaces.push(name.to_string());
+            ifaces.len() as u32
+        }
+    }
+}
+
+// bit `id - 1` is set for each of `ifaces` that matches the interface
+fn iface_mask(ifaces: &[String], ifname: &str, egress: &[String]) -> u64 {
+    ifaces
+        .iter()
+        .enumerate()
+        .filter(|(_, spec)| iface::matches(spec, ifname, egress))
+        .fold(0, |mask, (i, _)| mask | 1 << i)
+}
+
+// only read when needed, it comes from the routing tables
+fn egress(ifaces: &[String]) -> Vec<String> {
+    if ifaces.iter().any(|i| i == iface::EGRESS) {
+        iface::egress()
+    } else {
+        Vec::new()
+    }
+}
+
+// writes the ids of the interfaces of the host and removes the interfaces that are gone
+fn write_interfaces(bpf_obj: &mut BPFObj, ifaces: &[String]) -> Result<()> {
+    let egress = egress(ifaces);
+    let mut keys = Vec::new();
+    for (ifindex, name) in iface::interfaces()? {
+        let mask = iface_mask(ifaces, &name, &egress);
+        if mask == 0 {
+            continue;
+        }
+        let key =
+            bincode2::serialize(&(ifindex as u32)).map_err(|e| Error::Internal(e.to_string()))?;
+        let value = bincode2::serialize(&mask).map_err(|e| Error::Internal(e.to_string()))?;
+        bpf_obj
+            .update_map(IFACE_MAP, &key, &value, 0)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        keys.push(key);
+    }
+
+    let stale = bpf_obj
+        .map(IFACE_MAP)
+        .and_then(|m| m.keys())
+        .map_err(|e| Error::Internal(e.to_string()))?;
+    for key in stale.into_iter().filter(|k| !keys.contains(k)) {
+        bpf_obj
+            .delete_map_elem(IFACE_MAP, &key)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn attach(bpf_obj: &mut BPFObj, ifindex: i32, opts: &AttachOptions) -> Result<Vec<BPFLink>> {
+    match opts.hook {
+        Hook::Xdp => Ok(vec![bpf_obj
+            .attach_xdp(XDP_PROG, ifindex, opts.xdp_flags())
+            .map_err(|e| Error::Internal(e.to_string()))?]),
+        Hook::Tc => TC_PROGS
+            .into_iter()
+            .map(|(prog, h)| {
+                bpf_obj
+                    .attach_tc(prog, ifindex, h, opts.replace)
+                    .map_err(|e| Error::Internal(e.to_string()).into())
+            })
+            .collect(),
+    }
+}
+
+// the XDP program only sees incoming packets
+fn check_hook(rules: &[InnerRule], opts: &AttachOptions) -> Result<()> {
+    if opts.hook == Hook::Xdp && has_outbound_rules(rules) {
+        bail!(Error::InvalidInput(
+            "`out` rules need the filter to be attached with the TC hook".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// only XDP filters have a link to pin
+fn check_pinned_hook(dir: &Path, opts: &AttachOptions) -> Result<()> {
+    if !is_pinned(dir) {
+        bail!(Error::InvalidInput(format!(
+            "no filter is pinned in {}",
+            dir.display()
+        )));
+    }
+    let hook = if dir.join(PINNED_LINK).exists() {
+        Hook::Xdp
+    } else {
+        Hook::Tc
+    };
+    if hook != opts.hook {
+        bail!(Error::InvalidInput(format!(
+            "the filter pinned in {} is attached with another hook, unload it first",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+fn has_outbound_rules(rules: &[InnerRule]) -> bool {
+    rules.iter().any(|r| match r {
+        InnerRule::IPv4Rule(r) | InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => r.is_outbound(),
+        InnerRule::DefaultRule(_) => false,
+    })
+}
+
+// writes the rules to the ruleset `index` of the maps and makes it the active one,
+// the rules of each direction are written to a ruleset of their own
+fn write_ruleset(
+    bpf_obj: &mut BPFObj,
+    index: u32,
+    capacity: usize,
+    rules: &[(u32, InnerRule)],
+) -> Result<()> {
+    let rules = rules
+        .iter()
+        .map(|(id, r)| {
+            let mut r = *r;
+            r.set_id(*id);
+            r
+        })
+        .collect::<Vec<_>>();
+
+    for direction in [Direction::In, Direction::Out] {
+        // same key as `get_ruleset`
+        let key = index * 2 + direction as u32 - 1;
+        let offset = key * capacity as u32;
+        let ipv4 = directed(ipv4_rules(&rules), direction);
+        let ipv6 = directed(ipv6_rules(&rules), direction);
+
+        for (map, raw_rules) in [("ipv4_rules", &ipv4), ("ipv6_rules", &ipv6)] {
+            if raw_rules.len() > capacity {
+                bail!(Error::InvalidInput(format!(
+                    "{} rules do not fit in a filter with a capacity of {}",
+                    raw_rules.len(),
+                    capacity
+                )));
+            }
+
+            for (i, rule) in raw_rules.iter().enumerate() {
+                let value =
+                    bincode2::serialize(rule).map_err(|e| Error::Internal(e.to_string()))?;
+                let index = bincode2::serialize(&(offset + i as u32))
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+                bpf_obj
+                    .update_map(map, &index, &value, 0)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+            }
+        }
+
+        let ruleset = RawRuleset {
+            offset,
+            ipv4_count: ipv4.len() as u32,
+            ipv6_count: ipv6.len() as u32,
+            default_action: default_action(&rules) as u32,
+        };
+        let value = bincode2::serialize(&ruleset).map_err(|e| Error::Internal(e.to_string()))?;
+        let key = bincode2::serialize(&key).map_err(|e| Error::Internal(e.to_string()))?;
+        bpf_obj
+            .update_map("rulesets", &key, &value, 0)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+    }
+
+    // a single update so packets see either the old or the new ruleset
+    let key = bincode2::serialize(&0u32).map_err(|e| Error::Internal(e.to_string()))?;
+    let index = bincode2::serialize(&index).map_err(|e| Error::Internal(e.to_string()))?;
+    bpf_obj
+        .update_map("active_ruleset", &key, &index, 0)
+        .map_err(|e| Error::Internal(e.to_string()))?;
+
+    Ok(())
+}
+
+// rules of a ruleset that apply to packets of `direction`
+fn directed(rules: Vec<RawRule>, direction: Direction) -> Vec<RawRule> {
+    rules
+        .into_iter()
+        .filter(|r| r.applies_to(direction))
+        .collect()
+}
+
 fn generate_vmlinux_file(path: &Path) -> Result<File> {
     let mut hdr = File::create(path).map_err(|e| Error::Internal(e.to_string()))?;
     if let Err(e) = hdr.write_all(VMLINUX.as_bytes()) {
@@ -186,3 +1481,826 @@ fn generate_vmlinux_file(path: &Path) -> Result<File> {
     }
     Ok(hdr)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::time::Duration;
+
+    use tempfile::{tempdir, tempdir_in};
+
+    use super::{
+        default_action, directed, iface_mask, ipv4_rules, ipv6_rules, is_pinned, unload, with_ids,
+        with_rule, without_rule, AttachOptions, Filter, LoadedFilter, TcpState, XdpAction, XdpMode,
+        TC_EGRESS_PROG, TC_INGRESS_PROG, XDP_PROG,
+    };
+    use crate::eval::{PacketMeta, Verdict};
+    use crate::ip::ToIpNet;
+    use crate::packet::build_frame;
+    use crate::rule::{Action, Builder, Direction, InnerRule, RawRule, Rule};
+    use crate::table::{table_key, Table, IPV4_TABLES_MAP};
+    use crate::{bpf, iface};
+
+    fn raw(rule: Rule) -> RawRule {
+        match rule.get_rule() {
+            InnerRule::IPv4Rule(r) | InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => r,
+            InnerRule::DefaultRule(_) => panic!("expected a filter rule"),
+        }
+    }
+
+    fn rules() -> Vec<Rule> {
+        vec![
+            Builder::new()
+                .block()
+                .quick()
+                .from_addr("10.0.0.1")
+                .build()
+                .unwrap(),
+            Builder::new().pass().from_addr("10.0.0.1").build().unwrap(),
+            Builder::new()
+                .pass()
+                .quick()
+                .from_addr("::1")
+                .build()
+                .unwrap(),
+            Builder::new()
+                .block()
+                .from_addr("10.0.0.0/8")
+                .build()
+                .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn rules_keep_insertion_order() {
+        let mut filter = Filter::new();
+        for rule in rules() {
+            filter.add_rule(rule);
+        }
+
+        let expected = rules().into_iter().map(raw).collect::<Vec<_>>();
+        assert_eq!(
+            ipv4_rules(&filter.rules),
+            vec![expected[0], expected[1], expected[3]]
+        );
+        assert_eq!(ipv6_rules(&filter.rules), vec![expected[2]]);
+    }
+
+    #[test]
+    fn last_default_rule_wins() {
+        let mut filter = Filter::new();
+        assert_eq!(default_action(&filter.rules), Action::Pass);
+
+        filter.add_rule(Builder::new().block_all().unwrap());
+        filter.add_rule(Builder::new().pass().from_addr("10.0.0.1").build().unwrap());
+        assert_eq!(default_action(&filter.rules), Action::Block);
+
+        filter.add_rule(Builder::new().pass_all().unwrap());
+        assert_eq!(default_action(&filter.rules), Action::Pass);
+    }
+
+    #[test]
+    fn capacity_fits_initial_rules() {
+        let mut filter = Filter::new();
+        filter.set_rule_capacity(2);
+        for rule in rules() {
+            filter.add_rule(rule);
+        }
+        assert_eq!(filter.rule_capacity(), 3);
+
+        filter.set_rule_capacity(16);
+        assert_eq!(filter.rule_capacity(), 16);
+    }
+
+    #[test]
+    fn rules_reference_tables_by_id() {
+        let block = || {
+            Builder::new()
+                .block()
+                .from_table("bad_hosts")
+                .to_table("servers")
+                .build()
+                .unwrap()
+        };
+        let pass = || Builder::new().pass().to_table("bad_hosts").build().unwrap();
+
+        let mut filter = Filter::new();
+        filter.add_rule(block());
+        let mut bad_hosts = Table::new("bad_hosts");
+        bad_hosts.add("10.0.0.0/8").unwrap();
+        filter.add_table(bad_hosts.clone());
+        filter.add_rule(pass());
+
+        // tables referenced by a rule are created empty and filled in later
+        assert_eq!(filter.tables, vec![bad_hosts, Table::new("servers")]);
+
+        let mut expected = vec![raw(block()), raw(pass())];
+        expected[0].set_tables(1, 2);
+        expected[1].set_tables(0, 1);
+        assert_eq!(ipv4_rules(&filter.rules), expected);
+        assert_eq!(ipv6_rules(&filter.rules), expected);
+    }
+
+    #[test]
+    fn eval_returns_on_first_quick_match() {
+        // each later rule would decide otherwise if evaluation went on
+        let mut filter = Filter::new();
+        filter.add_rule(
+            Builder::new()
+                .pass()
+                .quick()
+                .from_addr("10.0.0.1")
+                .build()
+                .unwrap(),
+        );
+        filter.add_rule(
+            Builder::new()
+                .block()
+                .quick()
+                .from_addr("10.0.0.0/8")
+                .build()
+                .unwrap(),
+        );
+        filter.add_rule(
+            Builder::new()
+                .pass()
+                .from_addr("10.0.0.0/8")
+                .build()
+                .unwrap(),
+        );
+
+        let packet = PacketMeta::tcp("10.0.0.1:40000", "10.1.0.1:22").unwrap();
+        assert_eq!(
+            filter.evaluate(&packet),
+            Verdict {
+                action: Action::Pass,
+                rule: Some(1)
+            }
+        );
+        let packet = PacketMeta::tcp("10.0.0.2:40000", "10.1.0.1:22").unwrap();
+        assert_eq!(
+            filter.evaluate(&packet),
+            Verdict {
+                action: Action::Block,
+                rule: Some(2)
+            }
+        );
+    }
+
+    #[test]
+    fn evaluate_reports_deciding_rule() {
+        let mut filter = Filter::new();
+        for rule in rules() {
+            filter.add_rule(rule);
+        }
+        let verdict = |src: &str| {
+            let packet = PacketMeta::tcp(
+                src,
+                if src.contains('.') {
+                    "10.0.0.2:22"
+                } else {
+                    "[::2]:22"
+                },
+            );
+            filter.evaluate(&packet.unwrap())
+        };
+
+        // the first quick match wins over later ones
+        assert_eq!(
+            verdict("10.0.0.1:40000"),
+            Verdict {
+                action: Action::Block,
+                rule: Some(1)
+            }
+        );
+        // otherwise the last match wins
+        assert_eq!(
+            verdict("10.1.0.1:40000"),
+            Verdict {
+                action: Action::Block,
+                rule: Some(4)
+            }
+        );
+        assert_eq!(verdict("[::1]:40000").rule, Some(3));
+        assert_eq!(
+            verdict("192.168.0.1:40000"),
+            Verdict {
+                action: Action::Pass,
+                rule: None
+            }
+        );
+    }
+
+    #[test]
+    fn evaluate_matches_ports_protos_and_tables() {
+        let mut filter = Filter::new();
+        let mut bad_hosts = Table::new("bad_hosts");
+        bad_hosts.add("10.0.0.0/8").unwrap();
+        filter.add_table(bad_hosts);
+        filter.add_rule(Builder::new().block_all().unwrap());
+        filter.add_rule(
+            Builder::new()
+                .pass()
+                .proto("tcp")
+                .to_port_range(1024, 65535)
+                .build()
+                .unwrap(),
+        );
+        filter.add_rule(
+            Builder::new()
+                .block()
+                .from_table("bad_hosts")
+                .to_port_not(8080)
+                .build()
+                .unwrap(),
+        );
+        filter.add_rule(
+            Builder::new()
+                .pass()
+                .proto("tcp")
+                .tcp_flags("S", "SA")
+                .to_port(22)
+                .build()
+                .unwrap(),
+        );
+
+        let eval = |packet: PacketMeta| filter.evaluate(&packet).rule;
+        let tcp = |src, dst| PacketMeta::tcp(src, dst).unwrap();
+
+        assert_eq!(eval(tcp("192.168.0.1:40000", "192.168.0.2:8080")), Some(2));
+        assert_eq!(
+            eval(PacketMeta::udp("192.168.0.1:40000", "192.168.0.2:8080").unwrap()),
+            None
+        );
+        assert_eq!(eval(tcp("10.0.0.1:40000", "192.168.0.2:8080")), Some(2));
+        assert_eq!(eval(tcp("10.0.0.1:40000", "192.168.0.2:8081")), Some(3));
+        // ports only exist for TCP and UDP
+        assert_eq!(
+            eval(PacketMeta::icmp("10.0.0.1", "10.0.0.2", 8, 0).unwrap()),
+            None
+        );
+
+        let syn = tcp("192.168.0.1:40000", "192.168.0.2:22")
+            .with_tcp_flags("S")
+            .unwrap();
+        assert_eq!(eval(syn), Some(4));
+        assert_eq!(eval(syn.with_tcp_flags("SA").unwrap()), None);
+    }
+
+    #[test]
+    fn rulesets_are_split_by_direction() {
+        let both = raw(Builder::new().block().build().unwrap());
+        let inbound = raw(Builder::new().pass().inbound().build().unwrap());
+        let outbound = raw(Builder::new().pass().outbound().build().unwrap());
+        let rules = vec![both, inbound, outbound];
+
+        assert_eq!(directed(rules.clone(), Direction::In), vec![both, inbound]);
+        assert_eq!(directed(rules, Direction::Out), vec![both, outbound]);
+    }
+
+    #[test]
+    fn rule_changes_keep_ids() {
+        let inner = rules()
+            .into_iter()
+            .map(|r| r.get_rule())
+            .collect::<Vec<_>>();
+        let ids = |rules: &[(u32, InnerRule)]| rules.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+
+        let rules = with_ids(1, inner[..2].to_vec());
+        assert_eq!(ids(&rules), [1, 2]);
+
+        // inserted rules get the next id wherever they go
+        let rules = with_rule(&rules, 0, 3, inner[2]).unwrap();
+        let rules = with_rule(&rules, 3, 4, inner[3]).unwrap();
+        assert_eq!(ids(&rules), [3, 1, 2, 4]);
+        assert_eq!(rules[0].1, inner[2]);
+        assert!(with_rule(&rules, 5, 5, inner[0]).is_err());
+
+        let rules = without_rule(&rules, 1).unwrap();
+        assert_eq!(ids(&rules), [3, 2, 4]);
+        assert!(without_rule(&rules, 1).is_err());
+
+        // replaced rules do not get the ids of the old ones back
+        assert_eq!(ids(&with_ids(5, inner.clone())), [5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn tcp_timeouts_are_indexed_by_state() {
+        let mut filter = Filter::new();
+        assert_eq!(
+            filter.tcp_timeouts[TcpState::SynSent.index()],
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            filter.tcp_timeouts[TcpState::Established.index()],
+            Duration::from_secs(24 * 60 * 60)
+        );
+        assert_eq!(
+            filter.tcp_timeouts[TcpState::Closed.index()],
+            Duration::from_secs(90)
+        );
+
+        filter.set_tcp_timeout(TcpState::FinWait, Duration::from_secs(10));
+        assert_eq!(
+            filter.tcp_timeouts,
+            [
+                Duration::from_secs(30),
+                Duration::from_secs(24 * 60 * 60),
+                Duration::from_secs(10),
+                Duration::from_secs(90),
+            ]
+        );
+
+        // each state is defined with its own value and timeout
+        let defines = filter.defines();
+        let lines = defines.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"#define FLOW_FIN_WAIT 3"));
+        assert!(lines.contains(&"#define TCP_FIN_WAIT_TIMEOUT 10000000000ULL"));
+        assert!(lines.contains(&"#define FLOW_ESTABLISHED 2"));
+        assert!(lines.contains(&"#define TCP_ESTABLISHED_TIMEOUT 86400000000000ULL"));
+    }
+
+    #[test]
+    fn attach_options_to_xdp_flags() {
+        let opts = AttachOptions::new();
+        assert_eq!(opts.xdp_flags(), 1);
+        assert_eq!(opts.mode(XdpMode::Skb).xdp_flags(), 1 | 2);
+        assert_eq!(opts.mode(XdpMode::Drv).replace().xdp_flags(), 4);
+        assert_eq!(opts.replace().mode(XdpMode::Hw).xdp_flags(), 8);
+    }
+
+    #[test]
+    fn reattach_needs_a_filter_pinned_with_the_same_hook() {
+        let dir = tempdir().unwrap();
+        let pinned = [(1, dir.path().to_path_buf())];
+        assert!(!is_pinned(dir.path()));
+        assert!(unload(dir.path(), None).is_err());
+        let err = Filter::new()
+            .reattach(&pinned, AttachOptions::new())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("no filter is pinned"));
+
+        // TC filters have no link
+        File::create(dir.path().join(TC_INGRESS_PROG)).unwrap();
+        assert!(is_pinned(dir.path()));
+        let err = Filter::new()
+            .reattach(&pinned, AttachOptions::new())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("another hook"));
+    }
+
+    #[test]
+    fn rules_apply_to_their_direction() {
+        let mut filter = Filter::new();
+        filter.add_rule(
+            Builder::new()
+                .block()
+                .inbound()
+                .to_port(22)
+                .build()
+                .unwrap(),
+        );
+        filter.add_rule(
+            Builder::new()
+                .block()
+                .outbound()
+                .to_port(25)
+                .build()
+                .unwrap(),
+        );
+
+        let packet = |dst| PacketMeta::tcp("10.0.0.1:40000", dst).unwrap();
+        assert_eq!(filter.evaluate(&packet("10.0.0.2:22")).rule, Some(1));
+        assert_eq!(filter.evaluate(&packet("10.0.0.2:25")).rule, None);
+        let out = |dst| packet(dst).with_direction(Direction::Out);
+        assert_eq!(filter.evaluate(&out("10.0.0.2:22")).rule, None);
+        assert_eq!(filter.evaluate(&out("10.0.0.2:25")).rule, Some(2));
+
+        // XDP programs never see outgoing packets
+        match filter.load_on(&[1], AttachOptions::new()) {
+            Err(e) => assert!(e.to_string().contains("`out` rules")),
+            Ok(_) => panic!("filter with `out` rules attached to XDP"),
+        }
+    }
+
+    #[test]
+    fn rules_apply_to_their_interface() {
+        let lo = iface::index("lo").unwrap();
+        let mut filter = Filter::new();
+        filter.add_rule(Builder::new().block().on("lo").to_port(22).build().unwrap());
+
+        let packet = PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22").unwrap();
+        assert_eq!(filter.evaluate(&packet.with_ifindex(lo)).rule, Some(1));
+        assert_eq!(filter.evaluate(&packet.with_ifindex(lo + 1)).rule, None);
+        // the interface of frames from a capture is unknown
+        assert_eq!(filter.evaluate(&packet).rule, None);
+
+        assert!(filter.load_on(&[], AttachOptions::new()).is_err());
+    }
+
+    #[test]
+    fn rules_reference_interfaces_by_id() {
+        let block = |ifname| Builder::new().block().on(ifname).build().unwrap();
+        let mut filter = Filter::new();
+        for ifname in ["vlan*", "lo", "vlan*", "egress"] {
+            filter.add_rule(block(ifname));
+        }
+        assert_eq!(filter.ifaces, vec!["vlan*", "lo", "egress"]);
+
+        let mut expected = vec![
+            raw(block("vlan*")),
+            raw(block("lo")),
+            raw(block("vlan*")),
+            raw(block("egress")),
+        ];
+        for (rule, id) in expected.iter_mut().zip([1, 2, 1, 3]) {
+            rule.set_iface(id);
+        }
+        assert_eq!(ipv4_rules(&filter.rules), expected);
+
+        // `lo` and `l?` both match the loopback interface
+        let lo = iface::index("lo").unwrap();
+        assert_eq!(iface_mask(&filter.ifaces, "lo", &[]), 0b010);
+        assert_eq!(iface_mask(&filter.ifaces, "vlan100", &[]), 0b001);
+        assert_eq!(
+            iface_mask(&filter.ifaces, "eth0", &["eth0".to_string()]),
+            0b100
+        );
+        filter.add_rule(Builder::new().block().on("l?").to_port(25).build().unwrap());
+        let packet = PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:25").unwrap();
+        assert_eq!(filter.evaluate(&packet.with_ifindex(lo)).rule, Some(5));
+    }
+
+    // the tests below load the program and need root, run them with `cargo test -- --ignored`
+
+    #[test]
+    #[ignore]
+    fn test_run_agrees_with_evaluate() {
+        let mut filter = Filter::new();
+        for rule in rules() {
+            filter.add_rule(rule);
+        }
+        filter.add_rule(
+            Builder::new()
+                .block()
+                .quick()
+                .proto("udp")
+                .to_port(53)
+                .build()
+                .unwrap(),
+        );
+
+        let packets = [
+            PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22").unwrap(),
+            PacketMeta::tcp("10.1.0.1:40000", "10.0.0.2:22").unwrap(),
+            PacketMeta::tcp("192.168.0.1:40000", "10.0.0.2:22").unwrap(),
+            PacketMeta::tcp("[::1]:40000", "[::2]:22").unwrap(),
+            PacketMeta::udp("192.168.0.1:40000", "192.168.0.2:53").unwrap(),
+            PacketMeta::udp("[::3]:40000", "[::2]:53").unwrap(),
+            PacketMeta::icmp("10.0.0.1", "10.0.0.2", 8, 0).unwrap(),
+        ];
+        let frames = packets
+            .iter()
+            .map(|p| build_frame(p, b"payload"))
+            .collect::<Vec<_>>();
+
+        let actions = filter.test_run_all(&frames).unwrap();
+        for (packet, action) in packets.iter().zip(actions) {
+            let expected = match filter.evaluate(packet).action {
+                Action::Block => XdpAction::Drop,
+                Action::Pass => XdpAction::Pass,
+            };
+            assert_eq!(action, expected, "{:?}", packet);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn reload_keeps_runtime_table_entries() {
+        let filter = |tables: Vec<Table>| {
+            let mut filter = Filter::new();
+            for table in tables {
+                let rule = Builder::new().block().from_table(table.name()).build();
+                filter.add_table(table);
+                filter.add_rule(rule.unwrap());
+            }
+            filter
+        };
+        let mut bad_hosts = Table::new("bad_hosts");
+        bad_hosts.add("10.0.0.1").unwrap();
+        let mut servers = Table::new("servers");
+        servers.add("10.0.0.2").unwrap();
+
+        let loaded = filter(vec![bad_hosts.clone(), servers]).load(&[]).unwrap();
+        let mut loaded = LoadedFilter::new(loaded, Vec::new(), AttachOptions::new());
+        loaded.add_table_entry("bad_hosts", "10.0.0.3").unwrap();
+        loaded.add_table_entry("servers", "10.0.0.4").unwrap();
+
+        // the tables swap ids and 10.0.0.2 is no longer in the config
+        loaded
+            .reload(filter(vec![Table::new("servers"), bad_hosts]))
+            .unwrap();
+        let key = |id, addr: &str| table_key(id, &addr.to_ip_net().unwrap()).unwrap().1;
+        let mut keys = loaded
+            .bpf_obj
+            .map(IPV4_TABLES_MAP)
+            .and_then(|m| m.keys())
+            .unwrap();
+        keys.sort();
+        let mut expected = vec![key(2, "10.0.0.1"), key(2, "10.0.0.3"), key(1, "10.0.0.4")];
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    #[ignore]
+    fn rejected_rules_do_not_add_interfaces() {
+        let mut filter = Filter::new();
+        filter.add_rule(Builder::new().block().on("eth0").build().unwrap());
+        let loaded = filter.load(&[]).unwrap();
+        let mut loaded = LoadedFilter::new(loaded, Vec::new(), AttachOptions::new());
+
+        let rule = || Builder::new().pass().on("eth1");
+        assert!(loaded
+            .insert_rule(0, rule().keep_state().build().unwrap())
+            .is_err());
+        assert!(loaded.insert_rule(2, rule().build().unwrap()).is_err());
+        assert_eq!(loaded.ifaces, vec!["eth0".to_string()]);
+
+        loaded.insert_rule(1, rule().build().unwrap()).unwrap();
+        assert_eq!(loaded.ifaces, vec!["eth0".to_string(), "eth1".to_string()]);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_run_applies_default_action_to_truncated_frames() {
+        let mut filter = Filter::new();
+        filter.add_rule(Builder::new().block_all().unwrap());
+        filter.add_rule(Builder::new().pass().to_port(22).build().unwrap());
+
+        let frame = build_frame(
+            &PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22").unwrap(),
+            &[],
+        );
+        assert_eq!(filter.test_run(&frame).unwrap(), XdpAction::Pass);
+        assert_eq!(filter.test_run(&frame[..40]).unwrap(), XdpAction::Drop);
+    }
+
+    #[test]
+    #[ignore]
+    fn tc_programs_filter_their_direction() {
+        const TC_ACT_OK: u32 = 0;
+        const TC_ACT_SHOT: u32 = 2;
+
+        let mut filter = Filter::new();
+        let smtp = Builder::new().block().outbound().proto("tcp").to_port(25);
+        filter.add_rule(smtp.build().unwrap());
+        let dns = Builder::new().block().inbound().proto("udp").to_port(53);
+        filter.add_rule(dns.build().unwrap());
+
+        let frames = [
+            PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:25").unwrap(),
+            PacketMeta::udp("10.0.0.1:40000", "10.0.0.2:53").unwrap(),
+            PacketMeta::tcp("[::1]:40000", "[::2]:80").unwrap(),
+        ]
+        .map(|p| build_frame(&p, b"payload"));
+        let loaded = filter.clone().load(&[]).unwrap();
+        for (prog, expected) in [
+            (TC_EGRESS_PROG, [TC_ACT_SHOT, TC_ACT_OK, TC_ACT_OK]),
+            (TC_INGRESS_PROG, [TC_ACT_OK, TC_ACT_SHOT, TC_ACT_OK]),
+        ] {
+            for (frame, expected) in frames.iter().zip(expected) {
+                let action = loaded.bpf_obj.test_run(prog, frame).unwrap();
+                assert_eq!(action, expected, "{} {:?}", prog, frame);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn pinned_filter_outlives_its_process() {
+        let lo = iface::index("lo").unwrap();
+        let dir = tempdir_in("/sys/fs/bpf").unwrap();
+        let dir = dir.path().join("lo");
+        let opts = || AttachOptions::new().mode(XdpMode::Skb);
+        let filter = || {
+            let mut filter = Filter::new();
+            filter.add_rule(
+                Builder::new()
+                    .block()
+                    .proto("udp")
+                    .to_port(9)
+                    .build()
+                    .unwrap(),
+            );
+            filter
+        };
+
+        let mut loaded = filter().load_on(&[lo], opts()).unwrap();
+        loaded.pin_attached(lo, &dir).unwrap();
+        drop(loaded);
+        assert_ne!(bpf::xdp_prog_id(lo, 0).unwrap(), 0);
+
+        let reattached = filter().reattach(&[(lo, dir.clone())], opts()).unwrap();
+        drop(reattached);
+        assert!(is_pinned(&dir));
+        assert_ne!(bpf::xdp_prog_id(lo, 0).unwrap(), 0);
+
+        unload(&dir, Some(lo)).unwrap();
+        assert_eq!(bpf::xdp_prog_id(lo, 0).unwrap(), 0);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    #[ignore]
+    fn failed_xdp_replace_restores_the_attached_prog() {
+        let lo = iface::index("lo").unwrap();
+        let dir = tempdir_in("/sys/fs/bpf").unwrap();
+        let path = dir.path().join(XDP_PROG);
+        let mut loaded = Filter::new().load(&[]).unwrap();
+        loaded.bpf_obj.pin_prog(XDP_PROG, &path).unwrap();
+
+        // attached without a link, like `ip link set dev lo xdpgeneric`
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let fd = unsafe { libbpf_sys::bpf_obj_get(c_path.as_ptr()) };
+        assert!(fd >= 0);
+        let res = unsafe { libbpf_sys::bpf_set_link_xdp_fd(lo, fd, bpf::XDP_FLAGS_SKB_MODE) };
+        assert_eq!(res, 0);
+        let id = bpf::xdp_prog_id(lo, 0).unwrap();
+
+        // the link cannot be created with a program of another type
+        assert!(loaded
+            .bpf_obj
+            .attach_xdp(TC_INGRESS_PROG, lo, bpf::XDP_FLAGS_SKB_MODE)
+            .is_err());
+        assert_eq!(bpf::xdp_prog_id(lo, 0).unwrap(), id);
+
+        unsafe {
+            libbpf_sys::bpf_set_link_xdp_fd(lo, -1, bpf::XDP_FLAGS_SKB_MODE);
+            libc::close(fd);
+        }
+    }
+
+    // runs the packets in order through `block all` followed by `rule`
+    fn assert_test_run(rule: Rule, tables: Vec<Table>, packets: &[(PacketMeta, XdpAction)]) {
+        let mut filter = Filter::new();
+        for table in tables {
+            filter.add_table(table);
+        }
+        filter.add_rule(Builder::new().block_all().unwrap());
+        filter.add_rule(rule);
+
+        let frames = packets
+            .iter()
+            .map(|(p, _)| build_frame(p, &[]))
+            .collect::<Vec<_>>();
+        let actions = filter.test_run_all(&frames).unwrap();
+        for ((packet, expected), action) in packets.iter().zip(actions) {
+            assert_eq!(action, *expected, "{:?}", packet);
+        }
+    }
+
+    fn ssh_keep_state() -> Rule {
+        Builder::new()
+            .pass()
+            .proto("tcp")
+            .to_port(22)
+            .tcp_flags("S", "SA")
+            .keep_state()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn test_run_tracks_incoming_connections() {
+        let client = PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22").unwrap();
+        let other = PacketMeta::tcp("10.0.0.3:40000", "10.0.0.2:22").unwrap();
+        // XDP never sees the SYN+ACK of the host
+        assert_test_run(
+            ssh_keep_state(),
+            Vec::new(),
+            &[
+                (client.with_tcp_flags("A").unwrap(), XdpAction::Drop),
+                (client.with_tcp_flags("S").unwrap(), XdpAction::Pass),
+                (client.with_tcp_flags("A").unwrap(), XdpAction::Pass),
+                (client.with_tcp_flags("PA").unwrap(), XdpAction::Pass),
+                (other.with_tcp_flags("A").unwrap(), XdpAction::Drop),
+            ],
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn tc_programs_track_the_handshake() {
+        const TC_ACT_OK: u32 = 0;
+        const TC_ACT_SHOT: u32 = 2;
+
+        let mut filter = Filter::new();
+        filter.add_rule(Builder::new().block_all().unwrap());
+        filter.add_rule(ssh_keep_state());
+        let loaded = filter.load(&[]).unwrap();
+
+        let client = PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22").unwrap();
+        let host = PacketMeta::tcp("10.0.0.2:22", "10.0.0.1:40000").unwrap();
+        for (prog, packet, flags, expected) in [
+            (TC_EGRESS_PROG, host, "SA", TC_ACT_SHOT),
+            (TC_INGRESS_PROG, client, "S", TC_ACT_OK),
+            // the handshake only ends with the host's SYN+ACK
+            (TC_INGRESS_PROG, client, "A", TC_ACT_SHOT),
+            (TC_EGRESS_PROG, host, "SA", TC_ACT_OK),
+            (TC_INGRESS_PROG, client, "A", TC_ACT_OK),
+            (TC_INGRESS_PROG, client, "PA", TC_ACT_OK),
+            (TC_EGRESS_PROG, host, "PA", TC_ACT_OK),
+        ] {
+            let frame = build_frame(&packet.with_tcp_flags(flags).unwrap(), b"payload");
+            let action = loaded.bpf_obj.test_run(prog, &frame).unwrap();
+            assert_eq!(action, expected, "{} {}", prog, flags);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_run_matches_tables() {
+        let mut trusted = Table::new("trusted");
+        trusted.add("10.0.0.0/8").unwrap();
+        trusted.add("2001:db8::/32").unwrap();
+        let rule = Builder::new().pass().from_table("trusted").build().unwrap();
+        assert_test_run(
+            rule,
+            vec![trusted],
+            &[
+                (
+                    PacketMeta::tcp("10.1.2.3:40000", "192.168.0.1:22").unwrap(),
+                    XdpAction::Pass,
+                ),
+                (
+                    PacketMeta::tcp("192.168.0.2:40000", "192.168.0.1:22").unwrap(),
+                    XdpAction::Drop,
+                ),
+                (
+                    PacketMeta::udp("[2001:db8::1]:40000", "[::1]:53").unwrap(),
+                    XdpAction::Pass,
+                ),
+                (
+                    PacketMeta::udp("[2001:db9::1]:40000", "[::1]:53").unwrap(),
+                    XdpAction::Drop,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn test_run_matches_icmp_types() {
+        let rule = Builder::new()
+            .pass()
+            .proto("icmp")
+            .icmp_type("echoreq")
+            .build()
+            .unwrap();
+        assert_test_run(
+            rule,
+            Vec::new(),
+            &[
+                (
+                    PacketMeta::icmp("10.0.0.1", "10.0.0.2", 8, 0).unwrap(),
+                    XdpAction::Pass,
+                ),
+                (
+                    PacketMeta::icmp("10.0.0.1", "10.0.0.2", 0, 0).unwrap(),
+                    XdpAction::Drop,
+                ),
+                (
+                    PacketMeta::icmp("10.0.0.1", "10.0.0.2", 3, 1).unwrap(),
+                    XdpAction::Drop,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn test_run_matches_tcp_flags() {
+        let rule = Builder::new()
+            .pass()
+            .proto("tcp")
+            .tcp_flags("S", "SA")
+            .build()
+            .unwrap();
+        let packet = PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:80").unwrap();
+        assert_test_run(
+            rule,
+            Vec::new(),
+            &[
+                (packet.with_tcp_flags("S").unwrap(), XdpAction::Pass),
+                (packet.with_tcp_flags("SA").unwrap(), XdpAction::Drop),
+                (packet.with_tcp_flags("A").unwrap(), XdpAction::Drop),
+                (packet.with_tcp_flags("SP").unwrap(), XdpAction::Pass),
+            ],
+        );
+    }
+}
@@ -0,0 +1,72 @@
+// ICMP type names as used by OpenBSD's pf
+const ICMP_TYPES: [(&str, u8); 24] = [
+    ("echorep", 0),
+    ("unreach", 3),
+    ("squench", 4),
+    ("redir", 5),
+    ("althost", 6),
+    ("echoreq", 8),
+    ("routeradv", 9),
+    ("routersol", 10),
+    ("timex", 11),
+    ("paramprob", 12),
+    ("timereq", 13),
+    ("timerep", 14),
+    ("inforeq", 15),
+    ("inforep", 16),
+    ("maskreq", 17),
+    ("maskrep", 18),
+    ("trace", 30),
+    ("dataconv", 31),
+    ("mobredir", 32),
+    ("ipv6-where", 33),
+    ("ipv6-here", 34),
+    ("mobregreq", 35),
+    ("mobregrep", 36),
+    ("photuris", 40),
+];
+
+const ICMP6_TYPES: [(&str, u8); 23] = [
+    ("unreach", 1),
+    ("toobig", 2),
+    ("timex", 3),
+    ("paramprob", 4),
+    ("echoreq", 128),
+    ("echorep", 129),
+    ("groupqry", 130),
+    ("listqry", 130),
+    ("grouprep", 131),
+    ("listenrep", 131),
+    ("groupterm", 132),
+    ("listendone", 132),
+    ("routersol", 133),
+    ("routeradv", 134),
+    ("neighbrsol", 135),
+    ("neighbradv", 136),
+    ("redir", 137),
+    ("routrrenum", 138),
+    ("fqdnreq", 139),
+    ("fqdnrep", 140),
+    ("niqry", 139),
+    ("nirep", 140),
+    ("mtraceresp", 200),
+];
+
+/// Resolves an ICMP or ICMPv6 type given by name or number.
+pub fn icmp_type(name: &str, is_icmp6: bool) -> Option<u8> {
+    if let Ok(t) = name.parse::<u8>() {
+        return Some(t);
+    }
+
+    let types: &[(&str, u8)] = if is_icmp6 { &ICMP6_TYPES } else { &ICMP_TYPES };
+    types
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, t)| *t)
+}
+
+/// Name of an ICMP or ICMPv6 type, the first one if it has several.
+pub fn icmp_type_name(icmp_type: u8, is_icmp6: bool) -> Option<&'static str> {
+    let types: &[(&str, u8)] = if is_icmp6 { &ICMP6_TYPES } else { &ICMP_TYPES };
+    types.iter().find(|(_, t)| *t == icmp_type).map(|(n, _)| *n)
+}
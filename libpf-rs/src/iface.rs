@@ -0,0 +1,400 @@
+use std::ffi::{CStr, CString};
+use std::time::Duration;
+use std::{fs, io, mem};
+
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+
+/// Group of the interfaces with a default route, as in OpenBSD's pf.
+pub const EGRESS: &str = "egress";
+
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const RTA_HDR_LEN: usize = 4;
+const IFLA_IFNAME: u16 = 3;
+// multicast groups of rtnetlink
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+const NETLINK_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Index of the network interface with the given name, e.g. `eth0`.
+pub fn index(name: &str) -> Result<i32> {
+    let c_name = CString::new(name)
+        .map_err(|_| Error::InvalidInput(format!("invalid interface name `{}`", name)))?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => bail!(Error::InvalidInput(format!("unknown interface `{}`", name))),
+        i => Ok(i as i32),
+    }
+}
+
+/// Name of the network interface with the given index.
+pub fn name(ifindex: i32) -> Result<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let res = unsafe { libc::if_indextoname(ifindex as u32, buf.as_mut_ptr()) };
+    if res.is_null() {
+        bail!(Error::InvalidInput(format!(
+            "unknown interface {}",
+            ifindex
+        )));
+    }
+    Ok(unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Indexes and names of the interfaces of the host.
+pub fn interfaces() -> Result<Vec<(i32, String)>> {
+    let list = unsafe { libc::if_nameindex() };
+    if list.is_null() {
+        bail!(Error::Internal(io::Error::last_os_error().to_string()));
+    }
+
+    let mut res = Vec::new();
+    let mut entry = list;
+    unsafe {
+        while (*entry).if_index != 0 {
+            let name = CStr::from_ptr((*entry).if_name).to_string_lossy();
+            res.push(((*entry).if_index as i32, name.into_owned()));
+            entry = entry.add(1);
+        }
+        libc::if_freenameindex(list);
+    }
+    Ok(res)
+}
+
+/// Names of the interfaces with an IPv4 or IPv6 default route, i.e. the `egress` group.
+pub fn egress() -> Vec<String> {
+    let route = fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let ipv6_route = fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    default_route_interfaces(&route, &ipv6_route)
+}
+
+/// Whether `spec`, as given to `on`, matches an interface. `spec` is either the name
+/// of an interface, `egress`, a family of interfaces named after their driver like `vlan`
+/// for `vlan0`, `vlan1`... or a pattern where `*` and `?` match any characters.
+///
+/// `egress` is the list of interfaces of the `egress` group, see `egress()`.
+pub fn matches(spec: &str, ifname: &str, egress: &[String]) -> bool {
+    if spec == EGRESS {
+        return egress.iter().any(|i| i == ifname);
+    }
+    if spec.contains(['*', '?']) {
+        return wildcard_matches(spec.as_bytes(), ifname.as_bytes());
+    }
+
+    // interfaces with a unit number belong to the family of their name without it
+    let family = ifname.trim_end_matches(|c: char| c.is_ascii_digit());
+    spec == ifname || (family != ifname && spec == family)
+}
+
+/// Interfaces of the host that `spec` matches, see `matches`.
+pub fn expand(spec: &str) -> Result<Vec<(i32, String)>> {
+    let egress = if spec == EGRESS { egress() } else { Vec::new() };
+    Ok(interfaces()?
+        .into_iter()
+        .filter(|(_, name)| matches(spec, name, &egress))
+        .collect())
+}
+
+/// A change to the interfaces or routes of the host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkEvent {
+    /// An interface was created or changed, e.g. brought up.
+    New {
+        ifindex: i32,
+        name: String,
+    },
+    Removed {
+        ifindex: i32,
+        name: String,
+    },
+    /// A route was added or removed, which can change the `egress` group.
+    Routes,
+}
+
+/// Listens to rtnetlink for interfaces and routes being added and removed.
+pub struct LinkMonitor {
+    fd: i32,
+    buf: Vec<u8>,
+}
+
+impl LinkMonitor {
+    pub fn new() -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            bail!(Error::Internal(io::Error::last_os_error().to_string()));
+        }
+        let monitor = LinkMonitor {
+            fd,
+            buf: vec![0; NETLINK_BUFFER_SIZE],
+        };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_LINK | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE;
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            bail!(Error::Internal(io::Error::last_os_error().to_string()));
+        }
+        Ok(monitor)
+    }
+
+    /// Waits up to `timeout` for changes and returns the ones received so far.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<LinkEvent>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let res = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            bail!(Error::Internal(err.to_string()));
+        }
+
+        let mut events = Vec::new();
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd,
+                    self.buf.as_mut_ptr() as *mut libc::c_void,
+                    self.buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN) | Some(libc::EINTR) => break,
+                    // events were dropped, report a change of everything
+                    Some(libc::ENOBUFS) => {
+                        events.push(LinkEvent::Routes);
+                        continue;
+                    }
+                    _ => bail!(Error::Internal(err.to_string())),
+                }
+            }
+            events.extend(parse_events(&self.buf[..len as usize]));
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for LinkMonitor {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+// messages of the RTMGRP_LINK and RTMGRP_*_ROUTE groups, others are ignored
+fn parse_events(buf: &[u8]) -> Vec<LinkEvent> {
+    let mut events = Vec::new();
+    let mut pos = 0;
+    while pos + NLMSG_HDR_LEN <= buf.len() {
+        let len = u32::from_ne_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+        if len < NLMSG_HDR_LEN || pos + len > buf.len() {
+            break;
+        }
+        let msg_type = u16::from_ne_bytes(buf[pos + 4..pos + 6].try_into().unwrap());
+        let msg = &buf[pos + NLMSG_HDR_LEN..pos + len];
+
+        match msg_type {
+            libc::RTM_NEWLINK | libc::RTM_DELLINK if msg.len() >= IFINFOMSG_LEN => {
+                let ifindex = i32::from_ne_bytes(msg[4..8].try_into().unwrap());
+                if let Some(name) = link_name(&msg[IFINFOMSG_LEN..]) {
+                    events.push(if msg_type == libc::RTM_NEWLINK {
+                        LinkEvent::New { ifindex, name }
+                    } else {
+                        LinkEvent::Removed { ifindex, name }
+                    });
+                }
+            }
+            libc::RTM_NEWROUTE | libc::RTM_DELROUTE => events.push(LinkEvent::Routes),
+            _ => {}
+        }
+        pos += align(len);
+    }
+    events
+}
+
+// the IFLA_IFNAME attribute, a NUL terminated string
+fn link_name(mut attrs: &[u8]) -> Option<String> {
+    while attrs.len() >= RTA_HDR_LEN {
+        let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        if len < RTA_HDR_LEN || len > attrs.len() {
+            return None;
+        }
+        if attr_type == IFLA_IFNAME {
+            let value = &attrs[RTA_HDR_LEN..len];
+            let end = value.iter().position(|b| *b == 0).unwrap_or(value.len());
+            return Some(String::from_utf8_lossy(&value[..end]).into_owned());
+        }
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+    None
+}
+
+// netlink messages and attributes are aligned to 4 bytes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn default_route_interfaces(route: &str, ipv6_route: &str) -> Vec<String> {
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+    let ipv4 = route.lines().skip(1).filter_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields[..] {
+            [iface, "00000000", _, _, _, _, _, "00000000", ..] => Some(iface),
+            _ => None,
+        }
+    });
+    // destination, prefix length, source, source prefix length, next hop, metric, ..., iface
+    let ipv6 = ipv6_route.lines().filter_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields[..] {
+            [dst, "00", _, _, _, _, _, _, _, iface]
+                if iface != "lo" && dst.chars().all(|c| c == '0') =>
+            {
+                Some(iface)
+            }
+            _ => None,
+        }
+    });
+
+    let mut res: Vec<String> = Vec::new();
+    for iface in ipv4.chain(ipv6) {
+        if !res.iter().any(|i| i == iface) {
+            res.push(iface.to_string());
+        }
+    }
+    res
+}
+
+fn wildcard_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_matches(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_matches(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_route_interfaces, index, matches, name, parse_events, LinkEvent};
+
+    #[test]
+    fn loopback_index() {
+        let lo = index("lo").unwrap();
+        assert!(lo > 0);
+        assert_eq!(name(lo).unwrap(), "lo");
+        assert!(index("pfrs-none0").is_err());
+        assert!(index("l\0o").is_err());
+    }
+
+    #[test]
+    fn names_groups_and_patterns() {
+        let egress = vec!["eth0".to_string()];
+        assert!(matches("eth0", "eth0", &egress));
+        assert!(!matches("eth0", "eth01", &egress));
+        assert!(matches("vlan", "vlan12", &egress));
+        assert!(!matches("vlan", "vlanx", &egress));
+        assert!(matches("vlan*", "vlan.100", &egress));
+        assert!(matches("veth?", "veth3", &egress));
+        assert!(!matches("veth?", "veth30", &egress));
+        assert!(matches("*", "lo", &egress));
+        assert!(matches("egress", "eth0", &egress));
+        assert!(!matches("egress", "eth1", &egress));
+    }
+
+    #[test]
+    fn egress_from_default_routes() {
+        let route = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth1\t0002A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+";
+        let ipv6_route = "\
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 wlan0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200 lo
+20010db8000000000000000000000000 20 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001 eth0
+";
+        assert_eq!(
+            default_route_interfaces(route, ipv6_route),
+            vec!["eth0".to_string(), "wlan0".to_string()]
+        );
+    }
+
+    fn link_msg(msg_type: u16, ifindex: i32, name: &str) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend(((4 + name.len() + 1) as u16).to_ne_bytes());
+        attr.extend(3u16.to_ne_bytes());
+        attr.extend(name.as_bytes());
+        attr.push(0);
+        while !attr.len().is_multiple_of(4) {
+            attr.push(0);
+        }
+
+        let mut msg = Vec::new();
+        msg.extend(((16 + 16 + attr.len()) as u32).to_ne_bytes());
+        msg.extend(msg_type.to_ne_bytes());
+        msg.extend([0; 10]);
+        // ifinfomsg: family, padding, type, index, flags and change
+        msg.extend([0; 4]);
+        msg.extend(ifindex.to_ne_bytes());
+        msg.extend([0; 8]);
+        msg.extend(attr);
+        msg
+    }
+
+    #[test]
+    fn netlink_messages_are_parsed() {
+        let mut buf = link_msg(libc::RTM_NEWLINK, 7, "vlan100");
+        buf.extend(link_msg(libc::RTM_DELLINK, 8, "veth1"));
+        let mut route = vec![0; 16];
+        route[..4].copy_from_slice(&16u32.to_ne_bytes());
+        route[4..6].copy_from_slice(&libc::RTM_NEWROUTE.to_ne_bytes());
+        buf.extend(route);
+
+        assert_eq!(
+            parse_events(&buf),
+            vec![
+                LinkEvent::New {
+                    ifindex: 7,
+                    name: "vlan100".to_string()
+                },
+                LinkEvent::Removed {
+                    ifindex: 8,
+                    name: "veth1".to_string()
+                },
+                LinkEvent::Routes,
+            ]
+        );
+        // truncated messages are ignored
+        assert!(parse_events(&buf[..20]).is_empty());
+    }
+}
@@ -1,6 +1,10 @@
 use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+
 pub trait ToSockAddr {
     fn to_sock_addr(&self) -> Result<SocketAddr, AddrParseError>;
 }
@@ -23,12 +27,110 @@ impl ToSockAddr for SocketAddr {
     }
 }
 
-pub fn get_zero_addr(ipv6: bool) -> SocketAddr {
-    // These functions should not fail
-    let ip_addr = if ipv6 {
-        IpAddr::V6(Ipv6Addr::from_str("::").unwrap())
+/// An address together with the length of its network prefix.
+/// Single hosts have a prefix as long as the address itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    pub addr: SocketAddr,
+    pub prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: SocketAddr, prefix_len: u8) -> Result<Self> {
+        if prefix_len > max_prefix_len(addr.is_ipv6()) {
+            bail!(Error::InvalidInput(format!(
+                "invalid prefix length {} for {}",
+                prefix_len,
+                addr.ip()
+            )));
+        }
+
+        // clear host bits so that the rule holds the network address
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+        };
+
+        Ok(IpNet {
+            addr: SocketAddr::new(ip, addr.port()),
+            prefix_len,
+        })
+    }
+
+    pub fn host(addr: SocketAddr) -> Self {
+        IpNet {
+            addr,
+            prefix_len: max_prefix_len(addr.is_ipv6()),
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match IpNet::new(SocketAddr::new(ip, 0), self.prefix_len) {
+            Ok(net) => net.addr.ip() == self.addr.ip(),
+            // a prefix too long for `ip` is of the other IP version
+            Err(_) => false,
+        }
+    }
+}
+
+pub trait ToIpNet {
+    fn to_ip_net(&self) -> Result<IpNet>;
+}
+
+impl ToIpNet for &str {
+    fn to_ip_net(&self) -> Result<IpNet> {
+        match self.split_once('/') {
+            Some((addr, len)) => {
+                let addr = addr
+                    .to_sock_addr()
+                    .map_err(|e| Error::InvalidInput(e.to_string()))?;
+                let prefix_len = len
+                    .parse::<u8>()
+                    .map_err(|e| Error::InvalidInput(format!("invalid prefix length: {}", e)))?;
+                IpNet::new(addr, prefix_len)
+            }
+            None => {
+                let addr = self
+                    .to_sock_addr()
+                    .map_err(|e| Error::InvalidInput(e.to_string()))?;
+                Ok(IpNet::host(addr))
+            }
+        }
+    }
+}
+
+impl ToIpNet for IpAddr {
+    fn to_ip_net(&self) -> Result<IpNet> {
+        Ok(IpNet::host(SocketAddr::new(*self, 0)))
+    }
+}
+
+impl ToIpNet for SocketAddr {
+    fn to_ip_net(&self) -> Result<IpNet> {
+        Ok(IpNet::host(*self))
+    }
+}
+
+impl ToIpNet for (IpAddr, u8) {
+    fn to_ip_net(&self) -> Result<IpNet> {
+        IpNet::new(SocketAddr::new(self.0, 0), self.1)
+    }
+}
+
+pub fn max_prefix_len(ipv6: bool) -> u8 {
+    if ipv6 {
+        128
     } else {
-        IpAddr::V4(Ipv4Addr::from_str("0.0.0.0").unwrap())
-    };
-    SocketAddr::new(ip_addr, 0)
+        32
+    }
 }
@@ -4,6 +4,15 @@ mod bpf;
 mod bpfcode;
 mod compile;
 pub mod error;
+pub mod eval;
 pub mod filter;
+mod icmp;
+pub mod iface;
 mod ip;
+pub mod log;
+pub mod packet;
+pub mod pcap;
+mod proto;
 pub mod rule;
+pub mod stats;
+pub mod table;
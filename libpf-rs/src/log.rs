@@ -0,0 +1,188 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::bpf::{BPFMap, RingBuffer};
+use crate::error::Error;
+use crate::proto;
+use crate::rule::Action;
+use crate::stats;
+
+pub(crate) const LOG_MAP: &str = "log_events";
+
+/// A packet decided on by a rule with `log`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEvent {
+    /// Id of the rule, as returned by the `LoadedFilter`.
+    pub rule_id: u32,
+    pub action: Action,
+    pub proto: u8,
+    /// Addresses with the ports of TCP and UDP packets, 0 for other protocols.
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// Length of the whole frame.
+    pub len: u32,
+    /// First bytes of the frame, starting with the Ethernet header.
+    pub data: Vec<u8>,
+    pub time: SystemTime,
+}
+
+// like pflog, e.g. `@3 block proto tcp 10.0.0.1:40000 > 10.0.0.2:22 length 60`
+impl fmt::Display for LogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Block => "block",
+            Action::Pass => "pass",
+        };
+        write!(f, "@{} {}", self.rule_id, action)?;
+        match proto::proto_name(self.proto) {
+            Some(name) => write!(f, " proto {}", name)?,
+            None => write!(f, " proto {}", self.proto)?,
+        }
+        write!(f, " {} > {} length {}", self.src, self.dst, self.len)
+    }
+}
+
+// same layout as `struct log_event` up to `data`
+#[derive(Deserialize)]
+struct RawLogEvent {
+    timestamp: u64,
+    rule_id: u32,
+    action: u32,
+    ip_version: u32,
+    proto: u32,
+    saddr4: [u8; 4],
+    daddr4: [u8; 4],
+    saddr6: [u8; 16],
+    daddr6: [u8; 16],
+    sport: u16,
+    dport: u16,
+    len: u32,
+    snaplen: u32,
+}
+
+const RAW_LOG_EVENT_SIZE: usize = 76;
+
+/// Reads the events of the rules with `log` of a filter.
+///
+/// Events are kept in a buffer shared by all readers until one of them reads them,
+/// new events are dropped while it is full.
+pub struct LogReader {
+    ring_buffer: RingBuffer,
+}
+
+impl LogReader {
+    pub(crate) fn new(map: &BPFMap) -> Result<Self> {
+        let ring_buffer = RingBuffer::new(map).map_err(|e| Error::Internal(e.to_string()))?;
+        Ok(LogReader { ring_buffer })
+    }
+
+    /// Reads the events of a filter pinned with `LoadedFilter::pin`.
+    pub fn from_pinned<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let map = BPFMap::from_pin(dir.as_ref().join(LOG_MAP), 0, 0)?;
+        LogReader::new(&map)
+    }
+
+    /// Waits up to `timeout` for events and returns the ones received, if any.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<LogEvent>> {
+        let samples = self
+            .ring_buffer
+            .poll(timeout)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+
+        let now = stats::monotonic_now();
+        samples.iter().map(|s| parse_event(s, now)).collect()
+    }
+}
+
+fn parse_event(sample: &[u8], now: Duration) -> Result<LogEvent> {
+    if sample.len() < RAW_LOG_EVENT_SIZE {
+        bail!(Error::Internal(format!(
+            "log event of {} bytes is too short",
+            sample.len()
+        )));
+    }
+    let raw: RawLogEvent = bincode2::deserialize(&sample[..RAW_LOG_EVENT_SIZE])
+        .map_err(|e| Error::Internal(e.to_string()))?;
+
+    let (saddr, daddr) = match raw.ip_version {
+        4 => (IpAddr::from(raw.saddr4), IpAddr::from(raw.daddr4)),
+        _ => (IpAddr::from(raw.saddr6), IpAddr::from(raw.daddr6)),
+    };
+    let action = match raw.action {
+        a if a == Action::Block as u32 => Action::Block,
+        _ => Action::Pass,
+    };
+    let data = sample
+        .get(RAW_LOG_EVENT_SIZE..RAW_LOG_EVENT_SIZE + raw.snaplen as usize)
+        .unwrap_or(&sample[RAW_LOG_EVENT_SIZE..]);
+
+    Ok(LogEvent {
+        rule_id: raw.rule_id,
+        action,
+        proto: raw.proto as u8,
+        src: SocketAddr::new(saddr, raw.sport),
+        dst: SocketAddr::new(daddr, raw.dport),
+        len: raw.len,
+        data: data.to_vec(),
+        time: stats::to_system_time(raw.timestamp, now),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::parse_event;
+    use crate::rule::Action;
+
+    fn sample(ip_version: u32, data: &[u8]) -> Vec<u8> {
+        let mut sample = Vec::new();
+        sample.extend(1_000_000_000u64.to_le_bytes());
+        sample.extend(7u32.to_le_bytes());
+        sample.extend((Action::Block as u32).to_le_bytes());
+        sample.extend(ip_version.to_le_bytes());
+        sample.extend(6u32.to_le_bytes());
+        sample.extend([10, 0, 0, 1, 10, 0, 0, 2]);
+        let mut ip6 = [0u8; 32];
+        ip6[15] = 1;
+        ip6[31] = 2;
+        sample.extend(ip6);
+        sample.extend(40000u16.to_le_bytes());
+        sample.extend(22u16.to_le_bytes());
+        sample.extend(1500u32.to_le_bytes());
+        sample.extend((data.len() as u32).to_le_bytes());
+        sample.extend(data);
+        // padding of the C struct
+        sample.extend([0, 0, 0, 0]);
+        sample
+    }
+
+    #[test]
+    fn events_are_parsed() {
+        let event = parse_event(&sample(4, &[1, 2, 3]), Duration::from_secs(2)).unwrap();
+        assert_eq!(event.rule_id, 7);
+        assert_eq!(event.action, Action::Block);
+        assert_eq!(event.proto, 6);
+        assert_eq!(event.src, "10.0.0.1:40000".parse::<SocketAddr>().unwrap());
+        assert_eq!(event.dst, "10.0.0.2:22".parse::<SocketAddr>().unwrap());
+        assert_eq!(event.len, 1500);
+        assert_eq!(event.data, vec![1, 2, 3]);
+        assert_eq!(
+            event.to_string(),
+            "@7 block proto tcp 10.0.0.1:40000 > 10.0.0.2:22 length 1500"
+        );
+
+        let event = parse_event(&sample(6, &[]), Duration::from_secs(2)).unwrap();
+        assert_eq!(event.src, "[::1]:40000".parse::<SocketAddr>().unwrap());
+        assert_eq!(event.dst, "[::2]:22".parse::<SocketAddr>().unwrap());
+        assert!(event.data.is_empty());
+
+        assert!(parse_event(&[0; 8], Duration::from_secs(2)).is_err());
+    }
+}
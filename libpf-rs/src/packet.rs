@@ -0,0 +1,318 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+use crate::eval::{PacketMeta, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP};
+use crate::rule::Direction;
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+// locally administered, the filter does not look at them
+const SRC_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+const DST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+const TTL: u8 = 64;
+
+/// Builds an Ethernet frame carrying `packet` followed by `payload`,
+/// e.g. to run it through a filter with `Filter::test_run`.
+///
+/// TCP, UDP, ICMP and ICMPv6 packets get a header with the fields of `packet` and
+/// valid checksums, packets of other protocols only carry the payload.
+pub fn build_frame(packet: &PacketMeta, payload: &[u8]) -> Vec<u8> {
+    let mut l4 = match packet.proto {
+        IPPROTO_TCP => tcp_header(packet),
+        IPPROTO_UDP => udp_header(packet, payload.len()),
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => icmp_header(packet),
+        _ => Vec::new(),
+    };
+    l4.extend(payload);
+
+    // ICMP is the only one without a pseudo header
+    let sum_offset = match packet.proto {
+        IPPROTO_TCP => Some(16),
+        IPPROTO_UDP => Some(6),
+        IPPROTO_ICMPV6 => Some(2),
+        _ => None,
+    };
+    if let Some(offset) = sum_offset {
+        let sum = checksum(&l4, pseudo_header_sum(packet, l4.len()));
+        // a UDP checksum of 0 means there is none
+        let sum = if sum == 0 && packet.proto == IPPROTO_UDP {
+            0xffff
+        } else {
+            sum
+        };
+        l4[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+    } else if packet.proto == IPPROTO_ICMP {
+        let sum = checksum(&l4, 0);
+        l4[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    let mut frame = Vec::with_capacity(14 + 40 + l4.len());
+    frame.extend(DST_MAC);
+    frame.extend(SRC_MAC);
+    match (packet.src, packet.dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            frame.extend(ETH_P_IP.to_be_bytes());
+            let mut ip = Vec::with_capacity(20);
+            // version 4, 5 words of header
+            ip.push(0x45);
+            ip.push(0);
+            ip.extend((20 + l4.len() as u16).to_be_bytes());
+            ip.extend(0u16.to_be_bytes());
+            // don't fragment
+            ip.extend(0x4000u16.to_be_bytes());
+            ip.push(TTL);
+            ip.push(packet.proto);
+            ip.extend(0u16.to_be_bytes());
+            ip.extend(src.octets());
+            ip.extend(dst.octets());
+            let sum = checksum(&ip, 0);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            frame.extend(ip);
+        }
+        (src, dst) => {
+            frame.extend(ETH_P_IPV6.to_be_bytes());
+            frame.extend(0x6000_0000u32.to_be_bytes());
+            frame.extend((l4.len() as u16).to_be_bytes());
+            frame.push(packet.proto);
+            frame.push(TTL);
+            frame.extend(ip6_octets(src));
+            frame.extend(ip6_octets(dst));
+        }
+    }
+    frame.extend(l4);
+    frame
+}
+
+/// Reads the fields of an Ethernet frame that rules match on, the same way the filter does.
+/// The packet is inbound and its interface unknown, neither is part of the frame.
+///
+/// Returns `None` for frames that are not IPv4 or IPv6, which get the default action,
+/// and an error for truncated or malformed headers, which are counted as parse failures.
+pub fn parse_frame(frame: &[u8]) -> Result<Option<PacketMeta>> {
+    if frame.len() < 14 {
+        return Ok(None);
+    }
+
+    let (src, dst, proto, l4) = match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETH_P_IP => {
+            let ip = &frame[14..];
+            // the header length is in 32-bit words
+            let hdr_len = ip.first().map_or(0, |b| (b & 0xf) as usize * 4);
+            if hdr_len < 20 || ip.len() < hdr_len {
+                bail!(Error::InvalidInput("truncated IPv4 header".to_string()));
+            }
+            let src: [u8; 4] = ip[12..16].try_into().unwrap();
+            let dst: [u8; 4] = ip[16..20].try_into().unwrap();
+            (
+                IpAddr::from(Ipv4Addr::from(src)),
+                IpAddr::from(Ipv4Addr::from(dst)),
+                ip[9],
+                &ip[hdr_len..],
+            )
+        }
+        ETH_P_IPV6 => {
+            let ip = &frame[14..];
+            if ip.len() < 40 {
+                bail!(Error::InvalidInput("truncated IPv6 header".to_string()));
+            }
+            let src: [u8; 16] = ip[8..24].try_into().unwrap();
+            let dst: [u8; 16] = ip[24..40].try_into().unwrap();
+            (
+                IpAddr::from(Ipv6Addr::from(src)),
+                IpAddr::from(Ipv6Addr::from(dst)),
+                ip[6],
+                &ip[40..],
+            )
+        }
+        _ => return Ok(None),
+    };
+
+    let mut packet = PacketMeta {
+        src,
+        dst,
+        proto,
+        sport: 0,
+        dport: 0,
+        tcp_flags: 0,
+        icmp_type: 0,
+        icmp_code: 0,
+        direction: Direction::In,
+        ifindex: 0,
+    };
+    match proto {
+        IPPROTO_TCP => {
+            let hdr_len = l4.get(12).map_or(0, |b| (b >> 4) as usize * 4);
+            if hdr_len < 20 || l4.len() < hdr_len {
+                bail!(Error::InvalidInput("truncated TCP header".to_string()));
+            }
+            packet.sport = u16::from_be_bytes([l4[0], l4[1]]);
+            packet.dport = u16::from_be_bytes([l4[2], l4[3]]);
+            packet.tcp_flags = l4[13];
+        }
+        IPPROTO_UDP => {
+            if l4.len() < 8 {
+                bail!(Error::InvalidInput("truncated UDP header".to_string()));
+            }
+            packet.sport = u16::from_be_bytes([l4[0], l4[1]]);
+            packet.dport = u16::from_be_bytes([l4[2], l4[3]]);
+        }
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+            if l4.len() < 8 {
+                bail!(Error::InvalidInput("truncated ICMP header".to_string()));
+            }
+            packet.icmp_type = l4[0];
+            packet.icmp_code = l4[1];
+        }
+        _ => {}
+    }
+    Ok(Some(packet))
+}
+
+fn tcp_header(packet: &PacketMeta) -> Vec<u8> {
+    let mut hdr = Vec::with_capacity(20);
+    hdr.extend(packet.sport.to_be_bytes());
+    hdr.extend(packet.dport.to_be_bytes());
+    // sequence and acknowledgment numbers
+    hdr.extend([0; 8]);
+    // 5 words of header
+    hdr.push(5 << 4);
+    hdr.push(packet.tcp_flags);
+    hdr.extend(u16::MAX.to_be_bytes());
+    // checksum and urgent pointer
+    hdr.extend([0; 4]);
+    hdr
+}
+
+fn udp_header(packet: &PacketMeta, payload_len: usize) -> Vec<u8> {
+    let mut hdr = Vec::with_capacity(8);
+    hdr.extend(packet.sport.to_be_bytes());
+    hdr.extend(packet.dport.to_be_bytes());
+    hdr.extend((8 + payload_len as u16).to_be_bytes());
+    hdr.extend(0u16.to_be_bytes());
+    hdr
+}
+
+// the 4 bytes after the checksum depend on the type and are left empty
+fn icmp_header(packet: &PacketMeta) -> Vec<u8> {
+    let mut hdr = Vec::with_capacity(8);
+    hdr.push(packet.icmp_type);
+    hdr.push(packet.icmp_code);
+    hdr.extend([0; 6]);
+    hdr
+}
+
+fn pseudo_header_sum(packet: &PacketMeta, len: usize) -> u32 {
+    let mut pseudo = Vec::with_capacity(40);
+    match (packet.src, packet.dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend(src.octets());
+            pseudo.extend(dst.octets());
+            pseudo.extend([0, packet.proto]);
+            pseudo.extend((len as u16).to_be_bytes());
+        }
+        (src, dst) => {
+            pseudo.extend(ip6_octets(src));
+            pseudo.extend(ip6_octets(dst));
+            pseudo.extend((len as u32).to_be_bytes());
+            pseudo.extend([0, 0, 0, packet.proto]);
+        }
+    }
+    sum_words(&pseudo, 0)
+}
+
+// `PacketMeta` keeps both addresses of the same IP version
+fn ip6_octets(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(a) => a.to_ipv6_mapped().octets(),
+        IpAddr::V6(a) => a.octets(),
+    }
+}
+
+fn sum_words(data: &[u8], init: u32) -> u32 {
+    data.chunks(2).fold(init, |sum, w| {
+        let word = u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]);
+        sum + word as u32
+    })
+}
+
+// the Internet checksum of RFC 1071
+fn checksum(data: &[u8], init: u32) -> u16 {
+    let mut sum = sum_words(data, init);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_frame, checksum, parse_frame, pseudo_header_sum};
+    use crate::eval::PacketMeta;
+
+    #[test]
+    fn ipv4_tcp_frame() {
+        let packet = PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22")
+            .unwrap()
+            .with_tcp_flags("S")
+            .unwrap();
+        let frame = build_frame(&packet, b"hi");
+        assert_eq!(frame.len(), 14 + 20 + 20 + 2);
+        assert_eq!(&frame[12..14], &[0x08, 0x00]);
+
+        let ip = &frame[14..34];
+        assert_eq!(ip[9], 6);
+        assert_eq!(&ip[12..16], &[10, 0, 0, 1]);
+        assert_eq!(checksum(ip, 0), 0);
+
+        let tcp = &frame[34..];
+        assert_eq!(u16::from_be_bytes([tcp[0], tcp[1]]), 40000);
+        assert_eq!(u16::from_be_bytes([tcp[2], tcp[3]]), 22);
+        assert_eq!(tcp[13], 0x02);
+        assert_eq!(checksum(tcp, pseudo_header_sum(&packet, tcp.len())), 0);
+    }
+
+    #[test]
+    fn ipv6_udp_and_icmp6_frames() {
+        let packet = PacketMeta::udp("[::1]:5353", "[::2]:53").unwrap();
+        let frame = build_frame(&packet, &[0; 3]);
+        assert_eq!(frame.len(), 14 + 40 + 8 + 3);
+        assert_eq!(&frame[12..14], &[0x86, 0xdd]);
+        // next header and payload length
+        assert_eq!(frame[20], 17);
+        assert_eq!(&frame[18..20], &[0, 11]);
+        let udp = &frame[54..];
+        assert_eq!(checksum(udp, pseudo_header_sum(&packet, udp.len())), 0);
+
+        let packet = PacketMeta::icmp("::1", "::2", 128, 0).unwrap();
+        let frame = build_frame(&packet, &[]);
+        assert_eq!(frame[20], 58);
+        assert_eq!(frame[54], 128);
+        let icmp = &frame[54..];
+        assert_eq!(checksum(icmp, pseudo_header_sum(&packet, icmp.len())), 0);
+    }
+
+    #[test]
+    fn built_frames_are_parsed_back() {
+        let packets = [
+            PacketMeta::tcp("10.0.0.1:40000", "10.0.0.2:22")
+                .unwrap()
+                .with_tcp_flags("SA")
+                .unwrap(),
+            PacketMeta::udp("[::1]:5353", "[::2]:53").unwrap(),
+            PacketMeta::icmp("10.0.0.1", "10.0.0.2", 8, 0).unwrap(),
+        ];
+        for packet in packets {
+            let frame = build_frame(&packet, b"payload");
+            assert_eq!(parse_frame(&frame).unwrap(), Some(packet));
+        }
+
+        let frame = build_frame(&packets[0], &[]);
+        assert!(parse_frame(&frame[..40]).is_err());
+        // ARP
+        let mut arp = frame.clone();
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(parse_frame(&arp).unwrap(), None);
+    }
+}
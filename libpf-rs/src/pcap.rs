@@ -0,0 +1,689 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+use crate::error::Error;
+use crate::log::LogEvent;
+use crate::rule::Action;
+
+// logged frames start with the Ethernet header
+const LINKTYPE_ETHERNET: u16 = 1;
+// larger than any prefix the filter copies
+const SNAPLEN: u32 = 65535;
+// larger records only come from corrupt files, they are not allocated
+const MAX_RECORD_LEN: usize = 256 * 1024;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_COMMENT: u16 = 1;
+const PCAPNG_OPT_TSRESOL: u16 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Pcap,
+    /// Each packet has a comment with the rule and the action, e.g. `@3 block`.
+    Pcapng,
+}
+
+impl Format {
+    /// `Pcapng` for paths ending with `.pcapng`, `Pcap` otherwise.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(ext) if ext == "pcapng" => Format::Pcapng,
+            _ => Format::Pcap,
+        }
+    }
+}
+
+/// Writes logged packets in a format that tcpdump and Wireshark can read.
+pub struct PcapWriter<W: Write> {
+    out: W,
+    format: Format,
+    // bytes written so far, including the file header
+    size: u64,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header.
+    pub fn new(out: W, format: Format) -> Result<Self> {
+        let mut writer = PcapWriter {
+            out,
+            format,
+            size: 0,
+        };
+
+        let header = match format {
+            Format::Pcap => pcap_header(),
+            Format::Pcapng => pcapng_header(),
+        };
+        writer.write_bytes(&header)?;
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, event: &LogEvent) -> Result<()> {
+        let record = match self.format {
+            Format::Pcap => pcap_record(event),
+            Format::Pcapng => pcapng_record(event),
+        };
+        self.write_bytes(&record)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out
+            .flush()
+            .map_err(|e| Error::Internal(e.to_string()).into())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.out
+            .write_all(bytes)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// A capture file that moves on to `name.1.ext`, `name.2.ext`, ... once
+/// it reaches a given size, like `tcpdump -C`.
+pub struct CaptureFile {
+    path: PathBuf,
+    format: Format,
+    max_size: Option<u64>,
+    // number of the current file, 0 for `path` itself
+    index: u32,
+    writer: PcapWriter<BufWriter<File>>,
+}
+
+impl CaptureFile {
+    /// Creates the file at `path`, in the format given by its extension.
+    pub fn create<P: AsRef<Path>>(path: P, max_size: Option<u64>) -> Result<Self> {
+        let format = Format::from_path(path.as_ref());
+        Ok(CaptureFile {
+            path: path.as_ref().to_path_buf(),
+            format,
+            max_size,
+            index: 0,
+            writer: create_writer(path.as_ref(), format)?,
+        })
+    }
+
+    pub fn write(&mut self, event: &LogEvent) -> Result<()> {
+        if let Some(max_size) = self.max_size {
+            // a file holds at least one packet, however small `max_size` is
+            if self.writer.size() >= max_size {
+                self.writer.flush()?;
+                self.index += 1;
+                self.writer = create_writer(&rotated_path(&self.path, self.index), self.format)?;
+            }
+        }
+        self.writer.write(event)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A frame read from a capture file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub time: SystemTime,
+    /// Length of the frame on the wire, `data` may only hold its first bytes.
+    pub len: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reads the frames of a pcap or pcapng file of Ethernet frames,
+/// the format is told by the first bytes of the file.
+pub struct PcapReader<R: Read> {
+    input: R,
+    format: Format,
+    big_endian: bool,
+    // pcap timestamps are in microseconds or in nanoseconds
+    nanos: bool,
+    // units per second of the timestamps of each pcapng interface, by interface id
+    interfaces: Vec<u64>,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .map_err(|e| Error::InvalidInput(format!("{}: {}", path.as_ref().display(), e)))?;
+        PcapReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads the file header.
+    pub fn new(input: R) -> Result<Self> {
+        let mut reader = PcapReader {
+            input,
+            format: Format::Pcap,
+            big_endian: false,
+            nanos: false,
+            interfaces: Vec::new(),
+        };
+
+        let magic = match reader.read_bytes(4)? {
+            Some(magic) => magic,
+            None => bail!(Error::InvalidInput("empty capture file".to_string())),
+        };
+        let le = u32::from_le_bytes(magic[..4].try_into().unwrap());
+        let be = u32::from_be_bytes(magic[..4].try_into().unwrap());
+        if le == PCAPNG_SECTION_HEADER {
+            reader.format = Format::Pcapng;
+            let len = reader.read_all(4)?;
+            reader.read_section_header(&len)?;
+            return Ok(reader);
+        }
+
+        (reader.big_endian, reader.nanos) = match (le, be) {
+            (PCAP_MAGIC, _) => (false, false),
+            (PCAP_MAGIC_NANOS, _) => (false, true),
+            (_, PCAP_MAGIC) => (true, false),
+            (_, PCAP_MAGIC_NANOS) => (true, true),
+            _ => bail!(Error::InvalidInput("not a pcap or pcapng file".to_string())),
+        };
+        let header = reader.read_all(20)?;
+        check_link_type(reader.u32_at(&header, 16) as u16)?;
+        Ok(reader)
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Frame>> {
+        match self.format {
+            Format::Pcap => self.next_pcap_frame(),
+            Format::Pcapng => self.next_pcapng_frame(),
+        }
+    }
+
+    fn next_pcap_frame(&mut self) -> Result<Option<Frame>> {
+        let header = match self.read_bytes(16)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let secs = self.u32_at(&header, 0) as u64;
+        let frac = self.u32_at(&header, 4) as u64;
+        let captured = self.u32_at(&header, 8);
+        let len = self.u32_at(&header, 12);
+        check_record_len(captured as usize)?;
+
+        let frac = if self.nanos {
+            Duration::from_nanos(frac)
+        } else {
+            Duration::from_micros(frac)
+        };
+        Ok(Some(Frame {
+            time: UNIX_EPOCH + Duration::from_secs(secs) + frac,
+            len,
+            data: self.read_all(captured as usize)?,
+        }))
+    }
+
+    // skips the blocks that do not hold frames
+    fn next_pcapng_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let header = match self.read_bytes(8)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            let block_type = self.u32_at(&header, 0);
+            if block_type == PCAPNG_SECTION_HEADER {
+                self.read_section_header(&header[4..])?;
+                continue;
+            }
+
+            let block_len = self.u32_at(&header, 4) as usize;
+            if block_len < 12 || !block_len.is_multiple_of(4) {
+                bail!(Error::InvalidInput(format!(
+                    "invalid pcapng block length {}",
+                    block_len
+                )));
+            }
+            check_record_len(block_len)?;
+            // the body is followed by the length again
+            let block = self.read_all(block_len - 8)?;
+            let body = &block[..block.len() - 4];
+
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                    check_link_type(self.u16_at(body, 0))?;
+                    let resolution = self.tsresol(&body[8..]);
+                    self.interfaces.push(resolution);
+                }
+                PCAPNG_ENHANCED_PACKET if body.len() >= 20 => {
+                    let interface = self.u32_at(body, 0) as usize;
+                    let resolution = match self.interfaces.get(interface) {
+                        Some(r) => *r,
+                        None => bail!(Error::InvalidInput(format!(
+                            "packet of unknown interface {}",
+                            interface
+                        ))),
+                    };
+                    let ts = (self.u32_at(body, 4) as u64) << 32 | self.u32_at(body, 8) as u64;
+                    let nanos = (ts % resolution) as u128 * 1_000_000_000 / resolution as u128;
+                    let captured = (self.u32_at(body, 12) as usize).min(body.len() - 20);
+                    return Ok(Some(Frame {
+                        time: UNIX_EPOCH
+                            + Duration::from_secs(ts / resolution)
+                            + Duration::from_nanos(nanos as u64),
+                        len: self.u32_at(body, 16),
+                        data: body[20..20 + captured].to_vec(),
+                    }));
+                }
+                // simple packets have no timestamp
+                PCAPNG_SIMPLE_PACKET if body.len() >= 4 => {
+                    let len = self.u32_at(body, 0);
+                    let captured = (len as usize).min(body.len() - 4);
+                    return Ok(Some(Frame {
+                        time: UNIX_EPOCH,
+                        len,
+                        data: body[4..4 + captured].to_vec(),
+                    }));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    // reads the rest of a section header block whose length was read as `len`,
+    // its byte order magic tells the byte order of the whole section
+    fn read_section_header(&mut self, len: &[u8]) -> Result<()> {
+        let magic = self.read_all(4)?;
+        let magic = magic[..4].try_into().unwrap();
+        self.big_endian = if u32::from_le_bytes(magic) == PCAPNG_BYTE_ORDER_MAGIC {
+            false
+        } else if u32::from_be_bytes(magic) == PCAPNG_BYTE_ORDER_MAGIC {
+            true
+        } else {
+            bail!(Error::InvalidInput(
+                "invalid pcapng byte order magic".to_string()
+            ));
+        };
+        let block_len = self.u32_at(len, 0) as usize;
+        if block_len < 16 {
+            bail!(Error::InvalidInput(format!(
+                "invalid pcapng block length {}",
+                block_len
+            )));
+        }
+        check_record_len(block_len)?;
+        self.read_all(block_len - 12)?;
+        // interface ids start from 0 in each section
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    // units per second from the `if_tsresol` option, microseconds by default
+    fn tsresol(&self, mut options: &[u8]) -> u64 {
+        while options.len() >= 4 {
+            let code = self.u16_at(options, 0);
+            let len = self.u16_at(options, 2) as usize;
+            if code == PCAPNG_OPT_END || options.len() < 4 + len {
+                break;
+            }
+            if code == PCAPNG_OPT_TSRESOL && len >= 1 {
+                let exp = (options[4] & 0x7f) as u32;
+                let base: u64 = if options[4] & 0x80 != 0 { 2 } else { 10 };
+                return base.checked_pow(exp).unwrap_or(1_000_000);
+            }
+            let next = ((4 + len).div_ceil(4) * 4).min(options.len());
+            options = &options[next..];
+        }
+        1_000_000
+    }
+
+    // `None` if the file ends before the first byte
+    fn read_bytes(&mut self, len: usize) -> Result<Option<Vec<u8>>> {
+        let mut bytes = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            match self.input.read(&mut bytes[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => bail!(Error::InvalidInput("truncated capture file".to_string())),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => bail!(Error::Internal(e.to_string())),
+            }
+        }
+        Ok(Some(bytes))
+    }
+
+    fn read_all(&mut self, len: usize) -> Result<Vec<u8>> {
+        match self.read_bytes(len)? {
+            Some(bytes) => Ok(bytes),
+            None if len == 0 => Ok(Vec::new()),
+            None => bail!(Error::InvalidInput("truncated capture file".to_string())),
+        }
+    }
+
+    fn u32_at(&self, bytes: &[u8], pos: usize) -> u32 {
+        let b = bytes[pos..pos + 4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    fn u16_at(&self, bytes: &[u8], pos: usize) -> u16 {
+        let b = bytes[pos..pos + 2].try_into().unwrap();
+        if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+// the filter only sees Ethernet frames
+fn check_link_type(link_type: u16) -> Result<()> {
+    if link_type != LINKTYPE_ETHERNET {
+        bail!(Error::InvalidInput(format!(
+            "link type {} is not supported, only Ethernet captures are",
+            link_type
+        )));
+    }
+    Ok(())
+}
+
+fn check_record_len(len: usize) -> Result<()> {
+    if len > MAX_RECORD_LEN {
+        bail!(Error::InvalidInput(format!(
+            "capture record of {} bytes is larger than {} bytes",
+            len, MAX_RECORD_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn create_writer(path: &Path, format: Format) -> Result<PcapWriter<BufWriter<File>>> {
+    let file = File::create(path).map_err(|e| Error::Internal(e.to_string()))?;
+    PcapWriter::new(BufWriter::new(file), format)
+}
+
+// `drops.pcap` becomes `drops.1.pcap`
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, index),
+    };
+    path.with_file_name(name)
+}
+
+fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend(PCAP_MAGIC.to_le_bytes());
+    header.extend(2u16.to_le_bytes());
+    header.extend(4u16.to_le_bytes());
+    // timezone offset and timestamp accuracy
+    header.extend(0i32.to_le_bytes());
+    header.extend(0u32.to_le_bytes());
+    header.extend(SNAPLEN.to_le_bytes());
+    header.extend((LINKTYPE_ETHERNET as u32).to_le_bytes());
+    header
+}
+
+fn pcap_record(event: &LogEvent) -> Vec<u8> {
+    let micros = timestamp_micros(event);
+    let mut record = Vec::with_capacity(16 + event.data.len());
+    record.extend(((micros / 1_000_000) as u32).to_le_bytes());
+    record.extend(((micros % 1_000_000) as u32).to_le_bytes());
+    record.extend((event.data.len() as u32).to_le_bytes());
+    record.extend(event.len.to_le_bytes());
+    record.extend(&event.data);
+    record
+}
+
+fn pcapng_header() -> Vec<u8> {
+    let mut section = Vec::new();
+    section.extend(PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+    section.extend(1u16.to_le_bytes());
+    section.extend(0u16.to_le_bytes());
+    // the length of the section is not known in advance
+    section.extend((-1i64).to_le_bytes());
+
+    // timestamps are in microseconds by default
+    let mut interface = Vec::new();
+    interface.extend(LINKTYPE_ETHERNET.to_le_bytes());
+    interface.extend(0u16.to_le_bytes());
+    interface.extend(SNAPLEN.to_le_bytes());
+
+    let mut header = pcapng_block(PCAPNG_SECTION_HEADER, &section);
+    header.extend(pcapng_block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+    header
+}
+
+fn pcapng_record(event: &LogEvent) -> Vec<u8> {
+    let micros = timestamp_micros(event);
+    let mut body = Vec::with_capacity(20 + event.data.len() + 32);
+    // the only interface
+    body.extend(0u32.to_le_bytes());
+    body.extend(((micros >> 32) as u32).to_le_bytes());
+    body.extend((micros as u32).to_le_bytes());
+    body.extend((event.data.len() as u32).to_le_bytes());
+    body.extend(event.len.to_le_bytes());
+    body.extend(&event.data);
+    pad(&mut body);
+
+    let action = match event.action {
+        Action::Block => "block",
+        Action::Pass => "pass",
+    };
+    let comment = format!("@{} {}", event.rule_id, action);
+    body.extend(PCAPNG_OPT_COMMENT.to_le_bytes());
+    body.extend((comment.len() as u16).to_le_bytes());
+    body.extend(comment.as_bytes());
+    pad(&mut body);
+    // end of options
+    body.extend([0; 4]);
+
+    pcapng_block(PCAPNG_ENHANCED_PACKET, &body)
+}
+
+// the block length comes before and after the body
+fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let len = (12 + body.len()) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend(block_type.to_le_bytes());
+    block.extend(len.to_le_bytes());
+    block.extend(body);
+    block.extend(len.to_le_bytes());
+    block
+}
+
+// pcapng fields are padded to 32 bits
+fn pad(bytes: &mut Vec<u8>) {
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+}
+
+fn timestamp_micros(event: &LogEvent) -> u64 {
+    event
+        .time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tempfile::tempdir;
+
+    use super::{rotated_path, CaptureFile, Format, PcapReader, PcapWriter};
+    use crate::log::LogEvent;
+    use crate::rule::Action;
+
+    fn event(data: &[u8]) -> LogEvent {
+        LogEvent {
+            rule_id: 3,
+            action: Action::Block,
+            proto: 6,
+            src: "10.0.0.1:40000".parse().unwrap(),
+            dst: "10.0.0.2:22".parse().unwrap(),
+            len: 1500,
+            data: data.to_vec(),
+            time: UNIX_EPOCH + Duration::from_micros(5_000_007),
+        }
+    }
+
+    fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn pcap_records() {
+        let mut writer = PcapWriter::new(Vec::new(), Format::Pcap).unwrap();
+        writer.write(&event(&[1, 2, 3])).unwrap();
+        let bytes = writer.out;
+
+        assert_eq!(bytes.len(), 24 + 16 + 3);
+        assert_eq!(u32_at(&bytes, 0), 0xa1b2c3d4);
+        // Ethernet
+        assert_eq!(u32_at(&bytes, 20), 1);
+
+        assert_eq!(u32_at(&bytes, 24), 5);
+        assert_eq!(u32_at(&bytes, 28), 7);
+        assert_eq!(u32_at(&bytes, 32), 3);
+        assert_eq!(u32_at(&bytes, 36), 1500);
+        assert_eq!(&bytes[40..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn pcapng_blocks() {
+        let mut writer = PcapWriter::new(Vec::new(), Format::Pcapng).unwrap();
+        writer.write(&event(&[1, 2, 3])).unwrap();
+        let bytes = writer.out;
+
+        // every block starts and ends with its length, which is a multiple of 4
+        let mut pos = 0;
+        let mut types = Vec::new();
+        while pos < bytes.len() {
+            let len = u32_at(&bytes, pos + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(&bytes, pos + len - 4) as usize, len);
+            types.push(u32_at(&bytes, pos));
+            pos += len;
+        }
+        assert_eq!(pos, bytes.len());
+        assert_eq!(types, vec![0x0a0d0d0a, 1, 6]);
+
+        let comment = b"@3 block";
+        assert!(bytes.windows(comment.len()).any(|w| w == comment));
+    }
+
+    #[test]
+    fn capture_files_rotate_by_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("drops.pcap");
+
+        let mut file = CaptureFile::create(&path, Some(64)).unwrap();
+        for _ in 0..3 {
+            file.write(&event(&[0; 30])).unwrap();
+        }
+        file.flush().unwrap();
+
+        // the header and one packet already exceed 64 bytes
+        for p in [path.clone(), rotated_path(&path, 1), rotated_path(&path, 2)] {
+            assert_eq!(std::fs::metadata(p).unwrap().len(), 24 + 16 + 30);
+        }
+        assert_eq!(rotated_path(&path, 1), dir.path().join("drops.1.pcap"));
+        assert_eq!(Format::from_path("drops.pcapng"), Format::Pcapng);
+    }
+
+    #[test]
+    fn written_frames_are_read_back() {
+        for format in [Format::Pcap, Format::Pcapng] {
+            let mut writer = PcapWriter::new(Vec::new(), format).unwrap();
+            writer.write(&event(&[1, 2, 3])).unwrap();
+            writer.write(&event(&[4, 5, 6, 7, 8])).unwrap();
+
+            let frames = PcapReader::new(writer.out.as_slice())
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0].data, vec![1, 2, 3]);
+            assert_eq!(frames[1].data, vec![4, 5, 6, 7, 8]);
+            assert_eq!(frames[1].len, 1500);
+            assert_eq!(
+                frames[1].time,
+                UNIX_EPOCH + Duration::from_micros(5_000_007)
+            );
+        }
+    }
+
+    #[test]
+    fn big_endian_pcap_in_nanoseconds() {
+        let mut bytes = Vec::new();
+        for word in [0xa1b23c4du32, 0x0002_0004, 0, 0, 65535, 1] {
+            bytes.extend(word.to_be_bytes());
+        }
+        for word in [5u32, 7, 2, 60] {
+            bytes.extend(word.to_be_bytes());
+        }
+        bytes.extend([9, 9]);
+
+        let frames = PcapReader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            frames[0].time,
+            UNIX_EPOCH + Duration::from_nanos(5_000_000_007)
+        );
+        assert_eq!((frames[0].len, frames[0].data.clone()), (60, vec![9, 9]));
+
+        // only Ethernet captures can be replayed
+        bytes[23] = 101;
+        assert!(PcapReader::new(bytes.as_slice()).is_err());
+        assert!(PcapReader::new(&b"nope"[..]).is_err());
+    }
+
+    #[test]
+    fn oversized_records_are_rejected() {
+        let mut bytes = Vec::new();
+        for word in [0xa1b2c3d4u32, 0x0004_0002, 0, 0, 65535, 1] {
+            bytes.extend(word.to_le_bytes());
+        }
+        for word in [5u32, 7, u32::MAX, u32::MAX] {
+            bytes.extend(word.to_le_bytes());
+        }
+        let mut frames = PcapReader::new(bytes.as_slice()).unwrap();
+        let err = frames.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("larger than"));
+
+        let mut writer = PcapWriter::new(Vec::new(), Format::Pcapng).unwrap();
+        writer.write(&event(&[1, 2, 3])).unwrap();
+        let mut bytes = writer.out;
+        // length of the first block after the section and interface headers
+        let pos = u32_at(&bytes, 4) as usize;
+        let pos = pos + u32_at(&bytes, pos + 4) as usize;
+        bytes[pos + 4..pos + 8].copy_from_slice(&0xfffff000u32.to_le_bytes());
+        let mut frames = PcapReader::new(bytes.as_slice()).unwrap();
+        let err = frames.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("larger than"));
+    }
+}
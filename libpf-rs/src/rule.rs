@@ -1,47 +1,300 @@
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::error::Error;
-use crate::ip::{get_zero_addr, ToSockAddr};
+use crate::eval::{PacketMeta, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_TCP, IPPROTO_UDP};
+use crate::icmp;
+use crate::ip::{IpNet, ToIpNet};
+use crate::proto;
 
 #[derive(Debug)]
 pub(crate) enum Proto {
     UDP,
     TCP,
+    ICMP,
+    ICMP6,
+    Other(u8),
     Any,
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Proto {
+    fn from_number(proto: u8) -> Proto {
+        match proto {
+            1 => Proto::ICMP,
+            6 => Proto::TCP,
+            17 => Proto::UDP,
+            58 => Proto::ICMP6,
+            p => Proto::Other(p),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Action {
     Block = 1,
     Pass = 2,
 }
 
+/// Direction of the packets a rule applies to, rules without one apply to both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    In = 1,
+    Out = 2,
+}
+
+/// Port comparison operators, as in OpenBSD's pf.
+///
+/// `Range`, `Within` and `Outside` compare against two ports
+/// and are written `lo:hi`, `lo >< hi` and `lo <> hi` respectively.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortOp {
+    Eq = 1,
+    Ne = 2,
+    Lt = 3,
+    Le = 4,
+    Gt = 5,
+    Ge = 6,
+    Range = 7,
+    Within = 8,
+    Outside = 9,
+}
+
+impl PortOp {
+    fn is_range(&self) -> bool {
+        matches!(self, PortOp::Range | PortOp::Within | PortOp::Outside)
+    }
+
+    fn from_raw(op: u16) -> Option<PortOp> {
+        [
+            PortOp::Eq,
+            PortOp::Ne,
+            PortOp::Lt,
+            PortOp::Le,
+            PortOp::Gt,
+            PortOp::Ge,
+            PortOp::Range,
+            PortOp::Within,
+            PortOp::Outside,
+        ]
+        .into_iter()
+        .find(|o| *o as u16 == op)
+    }
+}
+
+// ports are kept in host byte order so that ranges can be compared
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub(crate) struct RawPort {
+    op: u16,
+    lo: u16,
+    hi: u16,
+}
+
+impl RawPort {
+    // same as `eval_port`, only TCP and UDP packets have ports
+    fn matches(&self, proto: u8, port: u16, negate: bool) -> bool {
+        let op = match PortOp::from_raw(self.op) {
+            Some(op) => op,
+            None => return true,
+        };
+        if proto != IPPROTO_TCP && proto != IPPROTO_UDP {
+            return false;
+        }
+
+        let (lo, hi) = (self.lo, self.hi);
+        let res = match op {
+            PortOp::Eq => port == lo,
+            PortOp::Ne => port != lo,
+            PortOp::Lt => port < lo,
+            PortOp::Le => port <= lo,
+            PortOp::Gt => port > lo,
+            PortOp::Ge => port >= lo,
+            PortOp::Range => port >= lo && port <= hi,
+            PortOp::Within => port > lo && port < hi,
+            PortOp::Outside => port < lo || port > hi,
+        };
+        res != negate
+    }
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
 pub(crate) struct RawRule {
+    // set by the filter when the rule is written to the maps
+    id: u32,
     action: u32,
     quick: u32,
+    keep_state: u32,
+    log: u32,
+    // `Direction` of the rule, 0 if it applies to both
+    direction: u32,
+    // id of the interfaces given with `on`, 0 if the rule applies to all of them
+    iface: u32,
     proto: u32,
-    sport: u16,
-    dport: u16,
+    negate: u32,
+    // ids of the tables of the addresses, 0 if the rule has no table
+    stable: u32,
+    dtable: u32,
+    sport: RawPort,
+    dport: RawPort,
+    tcp_flags: u16,
+    tcp_flags_mask: u16,
+    icmp_type: u8,
+    icmp_code: u8,
+    icmp_match: u16,
+    sprefix: u32,
+    dprefix: u32,
     saddr4: u32,
     daddr4: u32,
     saddr6: u128,
     daddr6: u128,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum InnerRule {
     DefaultRule(Action),
     IPv4Rule(RawRule),
     IPv6Rule(RawRule),
+    // applies to both IPv4 and IPv6 packets
+    IPRule(RawRule),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Rule {
     inner: InnerRule,
+    // names of the tables of the addresses, resolved to ids by the filter
+    stable: Option<String>,
+    dtable: Option<String>,
+    // interface, group or pattern given with `on`, resolved to an id by the filter
+    ifname: Option<String>,
+}
+
+impl RawRule {
+    pub(crate) fn keeps_state(&self) -> bool {
+        self.keep_state != 0
+    }
+
+    pub(crate) fn set_tables(&mut self, stable: u32, dtable: u32) {
+        self.stable = stable;
+        self.dtable = dtable;
+    }
+
+    pub(crate) fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    pub(crate) fn set_iface(&mut self, iface: u32) {
+        self.iface = iface;
+    }
+
+    pub(crate) fn action(&self) -> Action {
+        if self.action == Action::Block as u32 {
+            Action::Block
+        } else {
+            Action::Pass
+        }
+    }
+
+    pub(crate) fn is_quick(&self) -> bool {
+        self.quick != 0
+    }
+
+    pub(crate) fn is_outbound(&self) -> bool {
+        self.direction == Direction::Out as u32
+    }
+
+    pub(crate) fn applies_to(&self, direction: Direction) -> bool {
+        self.direction == 0 || self.direction == direction as u32
+    }
+
+    /// Same matching as `eval_ipv4_rule` and `eval_ipv6_rule` on the ruleset of the packet's
+    /// direction, for a packet of the IP version of the rule. `in_table` tells whether an
+    /// address is in the table with an id and `on_iface` whether the packet's interface is
+    /// one of the interfaces with an id.
+    pub(crate) fn matches(
+        &self,
+        packet: &PacketMeta,
+        in_table: &dyn Fn(u32, IpAddr) -> bool,
+        on_iface: &dyn Fn(u32) -> bool,
+    ) -> bool {
+        let proto = packet.proto;
+        // the program only reads the fields of the packet's protocol
+        let tcp_flags = if proto == IPPROTO_TCP {
+            packet.tcp_flags
+        } else {
+            0
+        };
+        let (icmp_type, icmp_code) = match proto {
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => (packet.icmp_type, packet.icmp_code),
+            _ => (0, 0),
+        };
+
+        self.applies_to(packet.direction)
+            && (self.iface == 0 || on_iface(self.iface))
+            && (self.proto == PROTO_ANY || self.proto == proto as u32)
+            && self
+                .sport
+                .matches(proto, packet.sport, self.negate & NEGATE_SPORT != 0)
+            && self
+                .dport
+                .matches(proto, packet.dport, self.negate & NEGATE_DPORT != 0)
+            && (self.tcp_flags_mask == 0
+                || (proto == IPPROTO_TCP
+                    && tcp_flags as u16 & self.tcp_flags_mask == self.tcp_flags))
+            && (self.icmp_match & ICMP_MATCH_TYPE == 0 || self.icmp_type == icmp_type)
+            && (self.icmp_match & ICMP_MATCH_CODE == 0 || self.icmp_code == icmp_code)
+            && addr_matches(
+                self.stable,
+                raw_addr(self.saddr4, self.saddr6, packet.src.is_ipv6()),
+                self.sprefix,
+                packet.src,
+                in_table,
+            ) != (self.negate & NEGATE_SADDR != 0)
+            && addr_matches(
+                self.dtable,
+                raw_addr(self.daddr4, self.daddr6, packet.dst.is_ipv6()),
+                self.dprefix,
+                packet.dst,
+                in_table,
+            ) != (self.negate & NEGATE_DADDR != 0)
+    }
+}
+
+impl InnerRule {
+    pub(crate) fn set_tables(&mut self, stable: u32, dtable: u32) {
+        match self {
+            InnerRule::IPv4Rule(r) | InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => {
+                r.set_tables(stable, dtable)
+            }
+            InnerRule::DefaultRule(_) => {}
+        }
+    }
+
+    pub(crate) fn set_id(&mut self, id: u32) {
+        match self {
+            InnerRule::IPv4Rule(r) | InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => r.set_id(id),
+            InnerRule::DefaultRule(_) => {}
+        }
+    }
+
+    pub(crate) fn set_iface(&mut self, iface: u32) {
+        match self {
+            InnerRule::IPv4Rule(r) | InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => {
+                r.set_iface(iface)
+            }
+            InnerRule::DefaultRule(_) => {}
+        }
+    }
+
+    pub(crate) fn keeps_state(&self) -> bool {
+        match self {
+            InnerRule::IPv4Rule(r) | InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => {
+                r.keeps_state()
+            }
+            InnerRule::DefaultRule(_) => false,
+        }
+    }
 }
 
 impl Rule {
@@ -49,27 +302,203 @@ impl Rule {
     pub(crate) fn get_rule(self) -> InnerRule {
         self.inner
     }
+
+    pub(crate) fn tables(&self) -> (Option<&str>, Option<&str>) {
+        (self.stable.as_deref(), self.dtable.as_deref())
+    }
+
+    pub(crate) fn ifname(&self) -> Option<&str> {
+        self.ifname.as_deref()
+    }
+}
+
+// written in pf.conf syntax, ports given as part of an address are written with `port`
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (r, is_ipv6) = match &self.inner {
+            InnerRule::DefaultRule(Action::Pass) => return write!(f, "pass all"),
+            InnerRule::DefaultRule(Action::Block) => return write!(f, "block all"),
+            InnerRule::IPv4Rule(r) => (r, Some(false)),
+            InnerRule::IPv6Rule(r) => (r, Some(true)),
+            InnerRule::IPRule(r) => (r, None),
+        };
+
+        if r.action == Action::Block as u32 {
+            write!(f, "block")?;
+        } else {
+            write!(f, "pass")?;
+        }
+        if r.direction == Direction::In as u32 {
+            write!(f, " in")?;
+        } else if r.direction == Direction::Out as u32 {
+            write!(f, " out")?;
+        }
+        if r.log != 0 {
+            write!(f, " log")?;
+        }
+        if r.quick != 0 {
+            write!(f, " quick")?;
+        }
+        if let Some(ifname) = &self.ifname {
+            write!(f, " on {}", ifname)?;
+        }
+        if r.proto != PROTO_ANY {
+            match proto::proto_name(r.proto as u8) {
+                Some(name) => write!(f, " proto {}", name)?,
+                None => write!(f, " proto {}", r.proto)?,
+            }
+        }
+
+        let (saddr, daddr) = match is_ipv6 {
+            Some(is_ipv6) => (
+                Some(raw_addr(r.saddr4, r.saddr6, is_ipv6)),
+                Some(raw_addr(r.daddr4, r.daddr6, is_ipv6)),
+            ),
+            None => (None, None),
+        };
+        let src = Host {
+            addr: saddr.filter(|_| r.sprefix != 0),
+            prefix: r.sprefix,
+            table: self.stable.as_deref(),
+            negate: r.negate & NEGATE_SADDR != 0,
+            port: r.sport,
+            negate_port: r.negate & NEGATE_SPORT != 0,
+        };
+        let dst = Host {
+            addr: daddr.filter(|_| r.dprefix != 0),
+            prefix: r.dprefix,
+            table: self.dtable.as_deref(),
+            negate: r.negate & NEGATE_DADDR != 0,
+            port: r.dport,
+            negate_port: r.negate & NEGATE_DPORT != 0,
+        };
+        if src.is_any() && dst.is_any() {
+            write!(f, " all")?;
+        } else {
+            write!(f, " from {} to {}", src, dst)?;
+        }
+
+        if r.tcp_flags_mask != 0 {
+            write!(
+                f,
+                " flags {}/{}",
+                tcp_flags_str(r.tcp_flags),
+                tcp_flags_str(r.tcp_flags_mask)
+            )?;
+        }
+        if r.icmp_match & ICMP_MATCH_TYPE != 0 {
+            let is_icmp6 = r.proto == 58;
+            let keyword = if is_icmp6 { "icmp6-type" } else { "icmp-type" };
+            match icmp::icmp_type_name(r.icmp_type, is_icmp6) {
+                Some(name) => write!(f, " {} {}", keyword, name)?,
+                None => write!(f, " {} {}", keyword, r.icmp_type)?,
+            }
+            if r.icmp_match & ICMP_MATCH_CODE != 0 {
+                write!(f, " code {}", r.icmp_code)?;
+            }
+        }
+        if r.keeps_state() {
+            write!(f, " keep state")?;
+        }
+        Ok(())
+    }
+}
+
+// one side of a rule, for display
+struct Host<'a> {
+    // `None` for any address
+    addr: Option<IpAddr>,
+    prefix: u32,
+    table: Option<&'a str>,
+    negate: bool,
+    port: RawPort,
+    negate_port: bool,
+}
+
+impl Host<'_> {
+    fn is_any(&self) -> bool {
+        self.addr.is_none() && self.table.is_none() && self.port.op == 0
+    }
+}
+
+impl fmt::Display for Host<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negate {
+            write!(f, "!")?;
+        }
+        match (self.table, self.addr) {
+            (Some(table), _) => write!(f, "<{}>", table)?,
+            (None, Some(addr)) if self.prefix == if addr.is_ipv6() { 128 } else { 32 } => {
+                write!(f, "{}", addr)?
+            }
+            (None, Some(addr)) => write!(f, "{}/{}", addr, self.prefix)?,
+            (None, None) => write!(f, "any")?,
+        }
+
+        let (lo, hi) = (self.port.lo, self.port.hi);
+        match self.port.op {
+            op if op == PortOp::Eq as u16 && self.negate_port => write!(f, " port != {}", lo),
+            op if op == PortOp::Eq as u16 => write!(f, " port = {}", lo),
+            op if op == PortOp::Ne as u16 => write!(f, " port != {}", lo),
+            op if op == PortOp::Lt as u16 => write!(f, " port < {}", lo),
+            op if op == PortOp::Le as u16 => write!(f, " port <= {}", lo),
+            op if op == PortOp::Gt as u16 => write!(f, " port > {}", lo),
+            op if op == PortOp::Ge as u16 => write!(f, " port >= {}", lo),
+            op if op == PortOp::Range as u16 => write!(f, " port {}:{}", lo, hi),
+            op if op == PortOp::Within as u16 => write!(f, " port {} >< {}", lo, hi),
+            op if op == PortOp::Outside as u16 => write!(f, " port {} <> {}", lo, hi),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug)]
 struct Parts {
     action: Action,
-    is_ipv6: bool,
+    // `None` if the rule applies to both IP versions
+    is_ipv6: Option<bool>,
     quick: bool,
+    keep_state: bool,
+    log: bool,
+    // `None` if the rule applies to both directions
+    direction: Option<Direction>,
+    ifname: Option<String>,
     proto: Proto,
-    saddr: Option<SocketAddr>,
-    daddr: Option<SocketAddr>,
+    saddr: Option<IpNet>,
+    daddr: Option<IpNet>,
+    stable: Option<String>,
+    dtable: Option<String>,
+    sport: Option<RawPort>,
+    dport: Option<RawPort>,
+    tcp_flags: Option<(u16, u16)>,
+    // `NEGATE_*` bits of the fields given with `!`
+    negate: u32,
+    // type name or number and whether it is an ICMPv6 type
+    icmp_type: Option<(String, bool)>,
+    icmp_code: Option<u8>,
 }
 
 impl Default for Parts {
     fn default() -> Self {
         Parts {
             action: Action::Pass,
-            is_ipv6: false,
+            is_ipv6: None,
             quick: false,
+            keep_state: false,
+            log: false,
+            direction: None,
+            ifname: None,
             proto: Proto::Any,
             saddr: None,
             daddr: None,
+            stable: None,
+            dtable: None,
+            sport: None,
+            dport: None,
+            tcp_flags: None,
+            negate: 0,
+            icmp_type: None,
+            icmp_code: None,
         }
     }
 }
@@ -107,85 +536,327 @@ impl Builder {
         })
     }
 
+    /// Only matches packets received by the interface, written `in` in pf.conf.
+    pub fn inbound(self) -> Builder {
+        self.and_then(|mut parts| {
+            parts.direction = Some(Direction::In);
+            Ok(parts)
+        })
+    }
+
+    /// Only matches packets sent by the interface, written `out` in pf.conf.
+    /// Outbound rules need the filter to be attached with `Hook::Tc`.
+    pub fn outbound(self) -> Builder {
+        self.and_then(|mut parts| {
+            parts.direction = Some(Direction::Out);
+            Ok(parts)
+        })
+    }
+
+    /// Only matches packets received or sent by the given interfaces: an interface name,
+    /// a group like `egress` or a pattern like `vlan*`, see `iface::matches`.
+    /// They are looked up when the filter is loaded and as interfaces come and go.
+    pub fn on<T: AsRef<str>>(self, ifname: T) -> Builder {
+        self.and_then(|mut parts| {
+            if ifname.as_ref().is_empty() {
+                bail!(Error::InvalidInput("empty interface name".to_string()));
+            }
+            parts.ifname = Some(ifname.as_ref().to_string());
+            Ok(parts)
+        })
+    }
+
+    /// Sends an event for every packet the rule decides on to the log of the filter,
+    /// see `LoadedFilter::log_reader`.
+    pub fn log(self) -> Builder {
+        self.and_then(|mut parts| {
+            parts.log = true;
+            Ok(parts)
+        })
+    }
+
+    /// Tracks the connection of the first matching packet so that
+    /// packets of the same flow, in either direction, are passed
+    /// without going through the rules again.
+    ///
+    /// Connections are only tracked from the packets the filter sees. Attached with
+    /// `Hook::Xdp`, that is incoming packets, so the replies to connections the host
+    /// opens are not tracked and need `Hook::Tc`. Since it never sees the host's SYN+ACK,
+    /// the initiator's ACK completes the TCP handshake.
+    pub fn keep_state(self) -> Builder {
+        self.and_then(|mut parts| {
+            parts.keep_state = true;
+            Ok(parts)
+        })
+    }
+
+    /// Matches an IP protocol given by number (0-255) or by name, e.g. `tcp`, `gre` or `esp`.
     pub fn proto<T: AsRef<str>>(self, proto: T) -> Builder {
         self.and_then(|mut parts| {
-            parts.proto = match proto.as_ref().to_lowercase().as_str() {
-                "udp" => Proto::UDP,
-                "tcp" => Proto::TCP,
-                _ => {
-                    bail!(Error::InvalidInput(
-                        "invalid protocol must be `tcp` or `udp`".to_string(),
-                    ));
+            parts.proto = match proto::proto_number(proto.as_ref()) {
+                Some(p) => Proto::from_number(p),
+                None => {
+                    bail!(Error::InvalidInput(format!(
+                        "unknown protocol `{}`",
+                        proto.as_ref()
+                    )));
                 }
             };
             Ok(parts)
         })
     }
 
+    /// Matches TCP packets whose flags in `mask` are exactly the ones in `set`,
+    /// e.g. `tcp_flags("S", "SA")` matches packets with SYN set and ACK unset.
+    /// Flags are given with pf's letters `FSRPAUEW`.
+    pub fn tcp_flags<T: AsRef<str>>(self, set: T, mask: T) -> Builder {
+        self.and_then(|mut parts| {
+            let set = parse_tcp_flags(set.as_ref())?;
+            let mask = parse_tcp_flags(mask.as_ref())?;
+            if set & !mask != 0 {
+                bail!(Error::InvalidInput(
+                    "TCP flags must be a subset of the mask".to_string(),
+                ));
+            }
+            parts.tcp_flags = Some((set, mask));
+            Ok(parts)
+        })
+    }
+
+    /// Matches ICMP packets of the given type, by name (e.g. `echoreq`) or number.
+    pub fn icmp_type<T: AsRef<str>>(self, icmp_type: T) -> Builder {
+        self.and_then(|mut parts| {
+            parts.icmp_type = Some((icmp_type.as_ref().to_string(), false));
+            Ok(parts)
+        })
+    }
+
+    /// Matches ICMPv6 packets of the given type, by name (e.g. `neighbrsol`) or number.
+    pub fn icmp6_type<T: AsRef<str>>(self, icmp_type: T) -> Builder {
+        self.and_then(|mut parts| {
+            parts.icmp_type = Some((icmp_type.as_ref().to_string(), true));
+            Ok(parts)
+        })
+    }
+
+    /// Matches packets with the given ICMP code, needs `icmp_type` or `icmp6_type`.
+    pub fn icmp_code(self, code: u8) -> Builder {
+        self.and_then(move |mut parts| {
+            parts.icmp_code = Some(code);
+            Ok(parts)
+        })
+    }
+
     pub fn set_ipv4(self) -> Builder {
         self.and_then(|mut parts| {
-            parts.is_ipv6 = false;
+            parts.is_ipv6 = Some(false);
             Ok(parts)
         })
     }
 
     pub fn set_ipv6(self) -> Builder {
         self.and_then(|mut parts| {
-            parts.is_ipv6 = true;
+            parts.is_ipv6 = Some(true);
+            Ok(parts)
+        })
+    }
+
+    pub fn from_addr<T: ToIpNet>(self, src: T) -> Builder {
+        self.and_then(move |mut parts| {
+            let net = src.to_ip_net()?;
+            parts.is_ipv6 = Some(net.is_ipv6());
+            parts.saddr = Some(net);
+            parts.stable = None;
+            parts.negate &= !NEGATE_SADDR;
+            Ok(parts)
+        })
+    }
+
+    /// Matches packets whose source address is not in `src`, like `from !10.0.0.0/8`.
+    pub fn from_addr_not<T: ToIpNet>(self, src: T) -> Builder {
+        self.from_addr(src).and_then(|mut parts| {
+            if matches!(parts.saddr, Some(net) if net.prefix_len == 0) {
+                bail!(Error::InvalidInput(
+                    "a negated address with prefix 0 matches nothing".to_string(),
+                ));
+            }
+            parts.negate |= NEGATE_SADDR;
             Ok(parts)
         })
     }
 
-    pub fn from_addr<T: ToSockAddr>(self, src: T) -> Builder {
+    /// Matches packets whose source address is in the table `name`.
+    pub fn from_table<T: AsRef<str>>(self, name: T) -> Builder {
         self.and_then(move |mut parts| {
-            let addr = src
-                .to_sock_addr()
-                .map_err(|e| Error::InvalidInput(e.to_string()))?;
-            parts.is_ipv6 = addr.is_ipv6();
-            parts.saddr = Some(addr);
+            parts.stable = Some(name.as_ref().to_string());
+            parts.saddr = None;
+            parts.negate &= !NEGATE_SADDR;
+            Ok(parts)
+        })
+    }
+
+    /// Matches packets whose source address is not in the table `name`.
+    pub fn from_table_not<T: AsRef<str>>(self, name: T) -> Builder {
+        self.from_table(name).and_then(|mut parts| {
+            parts.negate |= NEGATE_SADDR;
             Ok(parts)
         })
     }
 
     pub fn from_port(self, port: u16) -> Builder {
+        self.from_port_op(PortOp::Eq, port)
+    }
+
+    /// Matches packets whose source port is not `port`.
+    pub fn from_port_not(self, port: u16) -> Builder {
+        self.from_port(port).and_then(|mut parts| {
+            parts.negate |= NEGATE_SPORT;
+            Ok(parts)
+        })
+    }
+
+    pub fn from_port_op(self, op: PortOp, port: u16) -> Builder {
         self.and_then(move |mut parts| {
-            parts.saddr = parts
-                .saddr
-                .or_else(|| {
-                    let addr = get_zero_addr(parts.is_ipv6);
-                    Some(addr)
-                })
-                .and_then(|mut addr| {
-                    addr.set_port(port);
-                    Some(addr)
-                });
+            if op.is_range() {
+                bail!(Error::InvalidInput(format!(
+                    "port operator {:?} expects a range",
+                    op
+                )));
+            }
+            parts.negate &= !NEGATE_SPORT;
+            parts.sport = Some(RawPort {
+                op: op as u16,
+                lo: port,
+                hi: port,
+            });
             Ok(parts)
         })
     }
 
-    pub fn to_addr<T: ToSockAddr>(self, dst: T) -> Builder {
+    pub fn from_port_range(self, lo: u16, hi: u16) -> Builder {
+        self.from_port_range_op(PortOp::Range, lo, hi)
+    }
+
+    pub fn from_port_range_op(self, op: PortOp, lo: u16, hi: u16) -> Builder {
         self.and_then(move |mut parts| {
-            let addr = dst
-                .to_sock_addr()
-                .map_err(|e| Error::InvalidInput(e.to_string()))?;
-            parts.is_ipv6 = addr.is_ipv6();
-            parts.daddr = Some(addr);
+            if !op.is_range() {
+                bail!(Error::InvalidInput(format!(
+                    "port operator {:?} expects a single port",
+                    op
+                )));
+            }
+            if lo > hi {
+                bail!(Error::InvalidInput(format!(
+                    "invalid port range {}:{}",
+                    lo, hi
+                )));
+            }
+            parts.negate &= !NEGATE_SPORT;
+            parts.sport = Some(RawPort {
+                op: op as u16,
+                lo,
+                hi,
+            });
+            Ok(parts)
+        })
+    }
+
+    pub fn to_addr<T: ToIpNet>(self, dst: T) -> Builder {
+        self.and_then(move |mut parts| {
+            let net = dst.to_ip_net()?;
+            parts.is_ipv6 = Some(net.is_ipv6());
+            parts.daddr = Some(net);
+            parts.dtable = None;
+            parts.negate &= !NEGATE_DADDR;
+            Ok(parts)
+        })
+    }
+
+    /// Matches packets whose destination address is not in `dst`, like `to !10.0.0.0/8`.
+    pub fn to_addr_not<T: ToIpNet>(self, dst: T) -> Builder {
+        self.to_addr(dst).and_then(|mut parts| {
+            if matches!(parts.daddr, Some(net) if net.prefix_len == 0) {
+                bail!(Error::InvalidInput(
+                    "a negated address with prefix 0 matches nothing".to_string(),
+                ));
+            }
+            parts.negate |= NEGATE_DADDR;
+            Ok(parts)
+        })
+    }
+
+    /// Matches packets whose destination address is in the table `name`.
+    pub fn to_table<T: AsRef<str>>(self, name: T) -> Builder {
+        self.and_then(move |mut parts| {
+            parts.dtable = Some(name.as_ref().to_string());
+            parts.daddr = None;
+            parts.negate &= !NEGATE_DADDR;
+            Ok(parts)
+        })
+    }
+
+    /// Matches packets whose destination address is not in the table `name`.
+    pub fn to_table_not<T: AsRef<str>>(self, name: T) -> Builder {
+        self.to_table(name).and_then(|mut parts| {
+            parts.negate |= NEGATE_DADDR;
             Ok(parts)
         })
     }
 
     pub fn to_port(self, port: u16) -> Builder {
+        self.to_port_op(PortOp::Eq, port)
+    }
+
+    /// Matches packets whose destination port is not `port`.
+    pub fn to_port_not(self, port: u16) -> Builder {
+        self.to_port(port).and_then(|mut parts| {
+            parts.negate |= NEGATE_DPORT;
+            Ok(parts)
+        })
+    }
+
+    pub fn to_port_op(self, op: PortOp, port: u16) -> Builder {
         self.and_then(move |mut parts| {
-            parts.daddr = parts
-                .daddr
-                .or_else(|| {
-                    let addr = get_zero_addr(parts.is_ipv6);
-                    Some(addr)
-                })
-                .and_then(|mut addr| {
-                    addr.set_port(port);
-                    Some(addr)
-                });
+            if op.is_range() {
+                bail!(Error::InvalidInput(format!(
+                    "port operator {:?} expects a range",
+                    op
+                )));
+            }
+            parts.negate &= !NEGATE_DPORT;
+            parts.dport = Some(RawPort {
+                op: op as u16,
+                lo: port,
+                hi: port,
+            });
+            Ok(parts)
+        })
+    }
+
+    pub fn to_port_range(self, lo: u16, hi: u16) -> Builder {
+        self.to_port_range_op(PortOp::Range, lo, hi)
+    }
+
+    pub fn to_port_range_op(self, op: PortOp, lo: u16, hi: u16) -> Builder {
+        self.and_then(move |mut parts| {
+            if !op.is_range() {
+                bail!(Error::InvalidInput(format!(
+                    "port operator {:?} expects a single port",
+                    op
+                )));
+            }
+            if lo > hi {
+                bail!(Error::InvalidInput(format!(
+                    "invalid port range {}:{}",
+                    lo, hi
+                )));
+            }
+            parts.negate &= !NEGATE_DPORT;
+            parts.dport = Some(RawPort {
+                op: op as u16,
+                lo,
+                hi,
+            });
             Ok(parts)
         })
     }
@@ -194,6 +865,9 @@ impl Builder {
         self.inner.and_then(|_| {
             Ok(Rule {
                 inner: InnerRule::DefaultRule(Action::Pass),
+                stable: None,
+                dtable: None,
+                ifname: None,
             })
         })
     }
@@ -202,6 +876,9 @@ impl Builder {
         self.inner.and_then(|_| {
             Ok(Rule {
                 inner: InnerRule::DefaultRule(Action::Block),
+                stable: None,
+                dtable: None,
+                ifname: None,
             })
         })
     }
@@ -210,7 +887,7 @@ impl Builder {
         self.inner.and_then(|parts| {
             let mut raw_rule = RawRule::default();
 
-            let is_ipv6: bool;
+            let is_ipv6: Option<bool>;
 
             match (&parts.saddr, &parts.daddr) {
                 (Some(s), Some(d)) => {
@@ -220,10 +897,10 @@ impl Builder {
                             "src & dst IP versions do not match".to_string(),
                         ));
                     }
-                    is_ipv6 = s.is_ipv6();
+                    is_ipv6 = Some(s.is_ipv6());
                 }
-                (Some(s), None) => is_ipv6 = s.is_ipv6(),
-                (None, Some(d)) => is_ipv6 = d.is_ipv6(),
+                (Some(s), None) => is_ipv6 = Some(s.is_ipv6()),
+                (None, Some(d)) => is_ipv6 = Some(d.is_ipv6()),
                 (None, None) => is_ipv6 = parts.is_ipv6,
             }
 
@@ -231,27 +908,57 @@ impl Builder {
                 bail!(Error::Build("error: IP version mismatch".to_string()));
             }
 
-            if let Some(SocketAddr::V4(a)) = parts.saddr {
-                let addr: u32 = (*a.ip()).into();
-                raw_rule.saddr4 = addr.to_be();
-                raw_rule.sport = a.port().to_be();
-            }
-            if let Some(SocketAddr::V4(a)) = parts.daddr {
-                let addr: u32 = (*a.ip()).into();
-                raw_rule.daddr4 = addr.to_be();
-                raw_rule.dport = a.port().to_be();
-            }
-            if let Some(SocketAddr::V6(a)) = parts.saddr {
-                let addr: u128 = (*a.ip()).into();
-                raw_rule.saddr6 = addr.to_be();
-                raw_rule.sport = a.port().to_be();
+            // ICMP is only used with IPv4 and ICMPv6 with IPv6
+            let is_ipv6 = match (&parts.proto, is_ipv6) {
+                (Proto::ICMP, Some(true)) | (Proto::ICMP6, Some(false)) => {
+                    bail!(Error::Build(
+                        "ICMP version does not match IP version".to_string(),
+                    ));
+                }
+                (Proto::ICMP, _) => Some(false),
+                (Proto::ICMP6, _) => Some(true),
+                (_, is_ipv6) => is_ipv6,
+            };
+
+            if let Some(net) = parts.saddr {
+                raw_rule.sprefix = net.prefix_len as u32;
+                match net.addr {
+                    SocketAddr::V4(a) => {
+                        let addr: u32 = (*a.ip()).into();
+                        raw_rule.saddr4 = addr.to_be();
+                    }
+                    SocketAddr::V6(a) => {
+                        let addr: u128 = (*a.ip()).into();
+                        raw_rule.saddr6 = addr.to_be();
+                    }
+                }
             }
-            if let Some(SocketAddr::V6(a)) = parts.daddr {
-                let addr: u128 = (*a.ip()).into();
-                raw_rule.daddr6 = addr.to_be();
-                raw_rule.dport = a.port().to_be();
+            if let Some(net) = parts.daddr {
+                raw_rule.dprefix = net.prefix_len as u32;
+                match net.addr {
+                    SocketAddr::V4(a) => {
+                        let addr: u32 = (*a.ip()).into();
+                        raw_rule.daddr4 = addr.to_be();
+                    }
+                    SocketAddr::V6(a) => {
+                        let addr: u128 = (*a.ip()).into();
+                        raw_rule.daddr6 = addr.to_be();
+                    }
+                }
             }
 
+            // a port given as part of the address, e.g. `10.0.0.1:80`
+            raw_rule.sport = parts
+                .sport
+                .or_else(|| parts.saddr.and_then(|net| port_from_addr(&net)))
+                .unwrap_or_default();
+            raw_rule.dport = parts
+                .dport
+                .or_else(|| parts.daddr.and_then(|net| port_from_addr(&net)))
+                .unwrap_or_default();
+
+            raw_rule.negate = parts.negate;
+
             match parts.action {
                 Action::Block => raw_rule.action = 1,
                 Action::Pass => raw_rule.action = 2,
@@ -262,19 +969,76 @@ impl Builder {
                 true => 1,
             };
 
+            if parts.keep_state && matches!(parts.action, Action::Block) {
+                bail!(Error::Build(
+                    "`keep state` is only valid for `pass` rules".to_string(),
+                ));
+            }
+            raw_rule.keep_state = match parts.keep_state {
+                false => 0,
+                true => 1,
+            };
+            raw_rule.log = match parts.log {
+                false => 0,
+                true => 1,
+            };
+            raw_rule.direction = parts.direction.map_or(0, |d| d as u32);
+
+            if let Some((set, mask)) = parts.tcp_flags {
+                if !matches!(parts.proto, Proto::TCP | Proto::Any) {
+                    bail!(Error::Build(
+                        "TCP flags given for a non TCP rule".to_string(),
+                    ));
+                }
+                raw_rule.tcp_flags = set;
+                raw_rule.tcp_flags_mask = mask;
+            }
+
+            if let Some((name, is_icmp6)) = &parts.icmp_type {
+                match (&parts.proto, is_icmp6) {
+                    (Proto::ICMP, false) | (Proto::ICMP6, true) => {}
+                    (_, false) => bail!(Error::Build(
+                        "ICMP type given without `proto icmp`".to_string(),
+                    )),
+                    (_, true) => bail!(Error::Build(
+                        "ICMPv6 type given without `proto icmp6`".to_string(),
+                    )),
+                }
+                raw_rule.icmp_type = icmp::icmp_type(name, *is_icmp6)
+                    .ok_or_else(|| Error::InvalidInput(format!("unknown ICMP type `{}`", name)))?;
+                raw_rule.icmp_match |= ICMP_MATCH_TYPE;
+
+                if let Some(code) = parts.icmp_code {
+                    raw_rule.icmp_code = code;
+                    raw_rule.icmp_match |= ICMP_MATCH_CODE;
+                }
+            } else if parts.icmp_code.is_some() {
+                bail!(Error::Build(
+                    "ICMP code given without an ICMP type".to_string(),
+                ));
+            }
+
             raw_rule.proto = match &parts.proto {
                 Proto::TCP => 6,
                 Proto::UDP => 17,
-                Proto::Any => 0,
+                Proto::ICMP => 1,
+                Proto::ICMP6 => 58,
+                Proto::Other(p) => *p as u32,
+                Proto::Any => PROTO_ANY,
             };
 
-            let inner_rule = if is_ipv6 {
-                InnerRule::IPv6Rule(raw_rule)
-            } else {
-                InnerRule::IPv4Rule(raw_rule)
+            let inner_rule = match is_ipv6 {
+                Some(true) => InnerRule::IPv6Rule(raw_rule),
+                Some(false) => InnerRule::IPv4Rule(raw_rule),
+                None => InnerRule::IPRule(raw_rule),
             };
 
-            Ok(Rule { inner: inner_rule })
+            Ok(Rule {
+                inner: inner_rule,
+                stable: parts.stable,
+                dtable: parts.dtable,
+                ifname: parts.ifname,
+            })
         })
     }
 
@@ -287,3 +1051,431 @@ impl Builder {
         }
     }
 }
+
+const NEGATE_SADDR: u32 = 1;
+const NEGATE_DADDR: u32 = 2;
+const NEGATE_SPORT: u32 = 4;
+const NEGATE_DPORT: u32 = 8;
+
+// outside of the 0-255 range of IP protocol numbers
+const PROTO_ANY: u32 = 256;
+
+const ICMP_MATCH_TYPE: u16 = 1;
+const ICMP_MATCH_CODE: u16 = 2;
+
+/// Letters of all TCP flags, in the order of their bits in the TCP header.
+pub const TCP_FLAGS: &str = "FSRPAUEW";
+
+pub(crate) fn parse_tcp_flags(flags: &str) -> Result<u16> {
+    flags
+        .chars()
+        .try_fold(0, |res, flag| match TCP_FLAGS.find(flag) {
+            Some(bit) => Ok(res | 1 << bit),
+            None => bail!(Error::InvalidInput(format!(
+                "invalid TCP flag `{}`, must be one of `{}`",
+                flag, TCP_FLAGS
+            ))),
+        })
+}
+
+fn tcp_flags_str(flags: u16) -> String {
+    TCP_FLAGS
+        .chars()
+        .enumerate()
+        .filter(|(bit, _)| flags & 1 << bit != 0)
+        .map(|(_, flag)| flag)
+        .collect()
+}
+
+// addresses of the rules are in network byte order
+fn raw_addr(addr4: u32, addr6: u128, is_ipv6: bool) -> IpAddr {
+    if is_ipv6 {
+        IpAddr::from(u128::from_be(addr6).to_be_bytes())
+    } else {
+        IpAddr::from(u32::from_be(addr4).to_be_bytes())
+    }
+}
+
+// same as `eval_ipv4_addr` and `eval_ipv6_addr`, a table replaces the address
+fn addr_matches(
+    table: u32,
+    rule_addr: IpAddr,
+    prefix: u32,
+    addr: IpAddr,
+    in_table: &dyn Fn(u32, IpAddr) -> bool,
+) -> bool {
+    if table != 0 {
+        return in_table(table, addr);
+    }
+    prefix == 0
+        || IpNet::new(SocketAddr::new(rule_addr, 0), prefix as u8)
+            .map(|net| net.contains(addr))
+            .unwrap_or(false)
+}
+
+fn port_from_addr(net: &IpNet) -> Option<RawPort> {
+    match net.addr.port() {
+        0 => None,
+        port => Some(RawPort {
+            op: PortOp::Eq as u16,
+            lo: port,
+            hi: port,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Builder, Direction, InnerRule, PortOp, RawPort, RawRule, NEGATE_DPORT, NEGATE_SADDR,
+    };
+
+    fn raw(builder: Builder) -> RawRule {
+        match builder.build().unwrap().get_rule() {
+            InnerRule::IPv4Rule(r) | InnerRule::IPv6Rule(r) | InnerRule::IPRule(r) => r,
+            InnerRule::DefaultRule(_) => panic!("expected a filter rule"),
+        }
+    }
+
+    #[test]
+    fn host_addr_has_full_prefix() {
+        let r = raw(Builder::new().from_addr("10.0.0.1"));
+        assert_eq!(r.sprefix, 32);
+        assert_eq!(r.saddr4, u32::from_be_bytes([10, 0, 0, 1]).to_be());
+    }
+
+    #[test]
+    fn subnet_ipv4() {
+        let r = raw(Builder::new()
+            .from_addr("10.1.2.3/8")
+            .to_addr("192.168.0.0/16"));
+        assert_eq!(r.sprefix, 8);
+        assert_eq!(r.saddr4, u32::from_be_bytes([10, 0, 0, 0]).to_be());
+        assert_eq!(r.dprefix, 16);
+        assert_eq!(r.daddr4, u32::from_be_bytes([192, 168, 0, 0]).to_be());
+    }
+
+    #[test]
+    fn subnet_ipv6() {
+        let r = raw(Builder::new().from_addr("2001:db8:ffff::1/32"));
+        assert_eq!(r.sprefix, 32);
+        assert_eq!(r.saddr6, 0x2001_0db8_u128.wrapping_shl(96).to_be());
+        assert_eq!(r.dprefix, 0);
+    }
+
+    #[test]
+    fn port_without_addr_matches_any_addr() {
+        let r = raw(Builder::new().to_port(22));
+        assert_eq!(r.dprefix, 0);
+        assert_eq!(
+            r.dport,
+            RawPort {
+                op: 1,
+                lo: 22,
+                hi: 22
+            }
+        );
+    }
+
+    #[test]
+    fn rule_without_addr_applies_to_both_versions() {
+        let rule = Builder::new().to_port(22).build().unwrap().get_rule();
+        assert!(matches!(rule, InnerRule::IPRule(_)));
+
+        let rule = Builder::new()
+            .set_ipv6()
+            .to_port(22)
+            .build()
+            .unwrap()
+            .get_rule();
+        assert!(matches!(rule, InnerRule::IPv6Rule(_)));
+    }
+
+    #[test]
+    fn keep_state() {
+        let r = raw(Builder::new().pass().keep_state().to_port(22));
+        assert!(r.keeps_state());
+
+        let r = raw(Builder::new().pass().to_port(22));
+        assert!(!r.keeps_state());
+
+        assert!(Builder::new().block().keep_state().build().is_err());
+    }
+
+    #[test]
+    fn direction() {
+        assert_eq!(raw(Builder::new().block()).direction, 0);
+        assert_eq!(
+            raw(Builder::new().block().inbound()).direction,
+            Direction::In as u32
+        );
+        let r = raw(Builder::new().pass().outbound().to_port(53));
+        assert_eq!(r.direction, Direction::Out as u32);
+        assert!(r.is_outbound());
+    }
+
+    #[test]
+    fn interface() {
+        // interfaces are looked up by the filter, they may not exist yet
+        let rule = Builder::new().block().on("vlan*").build().unwrap();
+        assert_eq!(rule.ifname(), Some("vlan*"));
+        assert_eq!(Builder::new().block().build().unwrap().ifname(), None);
+        assert!(Builder::new().block().on("").build().is_err());
+    }
+
+    #[test]
+    fn tcp_flags() {
+        let r = raw(Builder::new().tcp_flags("S", "SA"));
+        assert_eq!(r.tcp_flags, 0x02);
+        assert_eq!(r.tcp_flags_mask, 0x12);
+
+        let r = raw(Builder::new().tcp_flags("", "FSRPAUEW"));
+        assert_eq!(r.tcp_flags, 0);
+        assert_eq!(r.tcp_flags_mask, 0xff);
+
+        assert!(Builder::new().tcp_flags("SA", "S").build().is_err());
+        assert!(Builder::new().tcp_flags("X", "SA").build().is_err());
+        assert!(Builder::new()
+            .proto("udp")
+            .tcp_flags("S", "SA")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn icmp_type_and_code() {
+        let rule = Builder::new()
+            .proto("icmp")
+            .icmp_type("echoreq")
+            .icmp_code(0)
+            .build()
+            .unwrap()
+            .get_rule();
+        match rule {
+            InnerRule::IPv4Rule(r) => {
+                assert_eq!(
+                    (r.proto, r.icmp_type, r.icmp_code, r.icmp_match),
+                    (1, 8, 0, 3)
+                );
+            }
+            _ => panic!("expected an IPv4 rule"),
+        }
+
+        let rule = Builder::new()
+            .proto("icmp6")
+            .icmp6_type("neighbrsol")
+            .build()
+            .unwrap()
+            .get_rule();
+        match rule {
+            InnerRule::IPv6Rule(r) => {
+                assert_eq!((r.proto, r.icmp_type, r.icmp_match), (58, 135, 1));
+            }
+            _ => panic!("expected an IPv6 rule"),
+        }
+    }
+
+    #[test]
+    fn proto_names_and_numbers() {
+        let protos = [
+            ("gre", 47),
+            ("ESP", 50),
+            ("ipv6-icmp", 58),
+            ("0", 0),
+            ("255", 255),
+        ];
+        for (proto, number) in protos {
+            let rule = Builder::new().proto(proto).build().unwrap().get_rule();
+            match rule {
+                InnerRule::IPRule(r) | InnerRule::IPv6Rule(r) => assert_eq!(r.proto, number),
+                _ => panic!("unexpected rule for `{}`", proto),
+            }
+        }
+
+        assert!(Builder::new().proto("256").build().is_err());
+        assert!(Builder::new().proto("bogus").build().is_err());
+        assert!(Builder::new()
+            .proto("esp")
+            .tcp_flags("S", "SA")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn negation() {
+        let r = raw(Builder::new()
+            .from_addr_not("10.0.0.0/8")
+            .to_addr("10.0.0.1")
+            .to_port_not(443));
+        assert_eq!(r.negate, NEGATE_SADDR | NEGATE_DPORT);
+        assert_eq!(
+            r.dport,
+            RawPort {
+                op: PortOp::Eq as u16,
+                lo: 443,
+                hi: 443
+            }
+        );
+
+        // setting the field again without `!` clears the negation
+        let r = raw(Builder::new()
+            .from_addr_not("::1")
+            .from_addr("::2")
+            .to_port_not(22)
+            .to_port(22));
+        assert_eq!(r.negate, 0);
+
+        assert!(Builder::new().from_addr_not("0.0.0.0/0").build().is_err());
+    }
+
+    #[test]
+    fn invalid_icmp() {
+        assert!(Builder::new().icmp_type("echoreq").build().is_err());
+        assert!(Builder::new()
+            .proto("icmp")
+            .icmp6_type("echoreq")
+            .build()
+            .is_err());
+        assert!(Builder::new()
+            .proto("icmp")
+            .icmp_type("bogus")
+            .build()
+            .is_err());
+        assert!(Builder::new().proto("icmp").icmp_code(1).build().is_err());
+        assert!(Builder::new()
+            .proto("icmp")
+            .from_addr("::1")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn port_from_sock_addr() {
+        let r = raw(Builder::new().from_addr("10.0.0.1:53"));
+        assert_eq!(
+            r.sport,
+            RawPort {
+                op: 1,
+                lo: 53,
+                hi: 53
+            }
+        );
+        assert_eq!(r.dport, RawPort::default());
+    }
+
+    #[test]
+    fn port_zero_is_matchable() {
+        let r = raw(Builder::new().to_port(0));
+        assert_eq!(
+            r.dport,
+            RawPort {
+                op: 1,
+                lo: 0,
+                hi: 0
+            }
+        );
+    }
+
+    #[test]
+    fn port_ops() {
+        let r = raw(Builder::new()
+            .from_port_op(PortOp::Gt, 1024)
+            .to_port_range(6000, 6010));
+        assert_eq!(
+            r.sport,
+            RawPort {
+                op: 5,
+                lo: 1024,
+                hi: 1024
+            }
+        );
+        assert_eq!(
+            r.dport,
+            RawPort {
+                op: 7,
+                lo: 6000,
+                hi: 6010
+            }
+        );
+
+        let r = raw(Builder::new().to_port_range_op(PortOp::Within, 6000, 6010));
+        assert_eq!(
+            r.dport,
+            RawPort {
+                op: 8,
+                lo: 6000,
+                hi: 6010
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_port_ops() {
+        assert!(Builder::new()
+            .to_port_op(PortOp::Range, 22)
+            .build()
+            .is_err());
+        assert!(Builder::new()
+            .to_port_range_op(PortOp::Ne, 1, 2)
+            .build()
+            .is_err());
+        assert!(Builder::new().to_port_range(2000, 1000).build().is_err());
+    }
+
+    #[test]
+    fn invalid_prefix() {
+        assert!(Builder::new().from_addr("10.0.0.0/33").build().is_err());
+        assert!(Builder::new().from_addr("::/129").build().is_err());
+        assert!(Builder::new().from_addr("10.0.0.0/x").build().is_err());
+    }
+
+    #[test]
+    fn display_as_pf_syntax() {
+        let rule = |builder: Builder| builder.build().unwrap().to_string();
+
+        assert_eq!(Builder::new().block_all().unwrap().to_string(), "block all");
+        assert_eq!(rule(Builder::new().pass()), "pass all");
+        assert_eq!(
+            rule(
+                Builder::new()
+                    .block()
+                    .inbound()
+                    .log()
+                    .quick()
+                    .on("lo")
+                    .proto("tcp")
+                    .from_addr_not("10.0.0.0/8")
+                    .to_addr("10.1.1.1:22")
+                    .tcp_flags("S", "SA")
+            ),
+            "block in log quick on lo proto tcp from !10.0.0.0/8 to 10.1.1.1 port = 22 flags S/SA"
+        );
+        assert_eq!(
+            rule(
+                Builder::new()
+                    .pass()
+                    .outbound()
+                    .proto("udp")
+                    .from_table("dns")
+                    .to_port_range_op(PortOp::Outside, 1, 1023)
+                    .keep_state()
+            ),
+            "pass out proto udp from <dns> to any port 1 <> 1023 keep state"
+        );
+        assert_eq!(
+            rule(
+                Builder::new()
+                    .pass()
+                    .proto("icmp6")
+                    .to_addr("2001:db8::/32")
+                    .icmp6_type("echoreq")
+                    .icmp_code(0)
+            ),
+            "pass proto icmp6 from any to 2001:db8::/32 icmp6-type echoreq code 0"
+        );
+        assert_eq!(
+            rule(Builder::new().block().proto("gre").from_port_not(53)),
+            "block proto gre from any port != 53 to any"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::bpf::BPFMap;
+use crate::error::Error;
+
+pub(crate) const RULE_STATS_MAP: &str = "rule_stats";
+pub(crate) const FILTER_STATS_MAP: &str = "filter_stats";
+
+// indexes of `filter_stats`
+const STATS_DEFAULT_ACTION: u32 = 0;
+const STATS_PARSE_FAILURE: u32 = 1;
+
+const KEY_SIZE: u32 = 4;
+const VALUE_SIZE: u32 = 24;
+
+/// Packets and bytes counted by the filter, summed over all CPUs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+    /// When the last packet was counted, `None` if there was none yet.
+    pub last_hit: Option<SystemTime>,
+}
+
+/// Counters of the packets a rule decided on.
+///
+/// Packets of connections tracked with `keep state` skip the rules and are not counted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RuleStats {
+    pub id: u32,
+    pub counters: Counters,
+}
+
+/// Counters of the packets no rule decided on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FilterStats {
+    /// Packets that got the default action, including the ones that could not be parsed.
+    pub default_action: Counters,
+    /// Packets with truncated or malformed IP, TCP, UDP or ICMP headers.
+    pub parse_failures: Counters,
+}
+
+// same layout as `struct counters`
+#[derive(Deserialize)]
+struct RawCounters {
+    packets: u64,
+    bytes: u64,
+    last_hit: u64,
+}
+
+pub(crate) fn rule_stats(map: &BPFMap, ids: &[u32]) -> Result<Vec<RuleStats>> {
+    ids.iter()
+        .map(|id| {
+            Ok(RuleStats {
+                id: *id,
+                counters: lookup(map, *id)?,
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn filter_stats(map: &BPFMap) -> Result<FilterStats> {
+    Ok(FilterStats {
+        default_action: lookup(map, STATS_DEFAULT_ACTION)?,
+        parse_failures: lookup(map, STATS_PARSE_FAILURE)?,
+    })
+}
+
+/// Reads the counters of a filter pinned with `LoadedFilter::pin`,
+/// rules that did not match any packet yet are left out.
+pub fn read_pinned<P: AsRef<Path>>(dir: P) -> Result<(Vec<RuleStats>, FilterStats)> {
+    let rules = BPFMap::from_pin(dir.as_ref().join(RULE_STATS_MAP), KEY_SIZE, VALUE_SIZE)?;
+    let filter = BPFMap::from_pin(dir.as_ref().join(FILTER_STATS_MAP), KEY_SIZE, VALUE_SIZE)?;
+
+    let mut ids = rules
+        .keys()?
+        .iter()
+        .map(|k| bincode2::deserialize(k).map_err(|e| Error::Internal(e.to_string()).into()))
+        .collect::<Result<Vec<u32>>>()?;
+    ids.sort_unstable();
+
+    Ok((rule_stats(&rules, &ids)?, filter_stats(&filter)?))
+}
+
+fn lookup(map: &BPFMap, key: u32) -> Result<Counters> {
+    let key = bincode2::serialize(&key).map_err(|e| Error::Internal(e.to_string()))?;
+    let values = match map.lookup_percpu(&key)? {
+        Some(values) => values,
+        None => return Ok(Counters::default()),
+    };
+
+    let mut raw = Vec::with_capacity(values.len());
+    for value in values {
+        let counters: RawCounters =
+            bincode2::deserialize(&value).map_err(|e| Error::Internal(e.to_string()))?;
+        raw.push(counters);
+    }
+    Ok(sum(&raw, monotonic_now()))
+}
+
+fn sum(values: &[RawCounters], now: Duration) -> Counters {
+    let last_hit = values.iter().map(|c| c.last_hit).max().unwrap_or(0);
+    Counters {
+        packets: values.iter().map(|c| c.packets).sum(),
+        bytes: values.iter().map(|c| c.bytes).sum(),
+        last_hit: match last_hit {
+            0 => None,
+            ns => Some(to_system_time(ns, now)),
+        },
+    }
+}
+
+// the program records times with bpf_ktime_get_ns, in ns since boot
+pub(crate) fn to_system_time(ns: u64, now: Duration) -> SystemTime {
+    SystemTime::now() - now.saturating_sub(Duration::from_nanos(ns))
+}
+
+// same clock as bpf_ktime_get_ns
+pub(crate) fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{sum, RawCounters};
+
+    #[test]
+    fn counters_are_summed_over_cpus() {
+        let values = [
+            RawCounters {
+                packets: 2,
+                bytes: 120,
+                last_hit: 5_000_000_000,
+            },
+            RawCounters {
+                packets: 0,
+                bytes: 0,
+                last_hit: 0,
+            },
+            RawCounters {
+                packets: 1,
+                bytes: 1500,
+                last_hit: 8_000_000_000,
+            },
+        ];
+
+        let counters = sum(&values, Duration::from_secs(10));
+        assert_eq!(counters.packets, 3);
+        assert_eq!(counters.bytes, 1620);
+
+        // the latest hit of all CPUs, 2s before `now`
+        let ago = SystemTime::now()
+            .duration_since(counters.last_hit.unwrap())
+            .unwrap();
+        assert!(ago >= Duration::from_secs(2) && ago < Duration::from_secs(3));
+
+        assert_eq!(sum(&values[1..2], Duration::from_secs(10)).last_hit, None);
+    }
+}
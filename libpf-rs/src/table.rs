@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::ip::{IpNet, ToIpNet};
+
+pub(crate) const IPV4_TABLES_MAP: &str = "ipv4_tables";
+pub(crate) const IPV6_TABLES_MAP: &str = "ipv6_tables";
+
+/// A named set of addresses and subnets, like pf's `table <name> { ... }`.
+///
+/// Rules reference tables by name with `Builder::from_table` and `Builder::to_table`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Table {
+    name: String,
+    entries: Vec<IpNet>,
+}
+
+impl Table {
+    pub fn new<T: AsRef<str>>(name: T) -> Self {
+        Table {
+            name: name.as_ref().to_string(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn add<T: ToIpNet>(&mut self, addr: T) -> Result<()> {
+        let net = to_entry(addr)?;
+        if !self.entries.contains(&net) {
+            self.entries.push(net);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn entries(&self) -> &[IpNet] {
+        self.entries.as_slice()
+    }
+}
+
+pub(crate) fn to_entry<T: ToIpNet>(addr: T) -> Result<IpNet> {
+    let net = addr.to_ip_net()?;
+    if net.addr.port() != 0 {
+        bail!(Error::InvalidInput(format!(
+            "table entry {}: entries cannot have a port",
+            net.addr
+        )));
+    }
+    Ok(net)
+}
+
+// keys of the LPM trie maps, the table id comes first so that it is always
+// part of the prefix and entries of different tables never match each other
+#[derive(Serialize)]
+struct Ipv4Key {
+    prefixlen: u32,
+    table: u32,
+    addr: u32,
+}
+
+#[derive(Serialize)]
+struct Ipv6Key {
+    prefixlen: u32,
+    table: u32,
+    addr: u128,
+}
+
+/// Id of the table of a key returned by `table_key`.
+pub(crate) fn key_table(key: &[u8]) -> u32 {
+    u32::from_le_bytes([key[4], key[5], key[6], key[7]])
+}
+
+/// The same key in the table with the given id.
+pub(crate) fn with_table(key: &[u8], id: u32) -> Vec<u8> {
+    let mut key = key.to_vec();
+    key[4..8].copy_from_slice(&id.to_le_bytes());
+    key
+}
+
+/// Returns the map and the key of an entry of the table with the given id.
+pub(crate) fn table_key(id: u32, net: &IpNet) -> Result<(&'static str, Vec<u8>)> {
+    let prefixlen = u32::BITS + net.prefix_len as u32;
+    let res = match net.addr {
+        SocketAddr::V4(a) => {
+            let addr: u32 = (*a.ip()).into();
+            let key = Ipv4Key {
+                prefixlen,
+                table: id,
+                addr: addr.to_be(),
+            };
+            (IPV4_TABLES_MAP, bincode2::serialize(&key))
+        }
+        SocketAddr::V6(a) => {
+            let addr: u128 = (*a.ip()).into();
+            let key = Ipv6Key {
+                prefixlen,
+                table: id,
+                addr: addr.to_be(),
+            };
+            (IPV6_TABLES_MAP, bincode2::serialize(&key))
+        }
+    };
+
+    match res {
+        (map, Ok(key)) => Ok((map, key)),
+        (_, Err(e)) => Err(Error::Internal(e.to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_table, table_key, with_table, Table, IPV4_TABLES_MAP, IPV6_TABLES_MAP};
+    use crate::ip::ToIpNet;
+
+    #[test]
+    fn entries_are_unique() {
+        let mut table = Table::new("bad_hosts");
+        table.add("10.0.0.0/8").unwrap();
+        table.add("10.1.2.3/8").unwrap();
+        table.add("::1").unwrap();
+        assert_eq!(table.entries().len(), 2);
+        assert!(table.add("10.0.0.0/33").is_err());
+        assert!(table.add("10.0.0.1:80").is_err());
+    }
+
+    #[test]
+    fn keys_start_with_table_id() {
+        let (map, key) = table_key(3, &"10.0.0.0/8".to_ip_net().unwrap()).unwrap();
+        assert_eq!(map, IPV4_TABLES_MAP);
+        assert_eq!(key, vec![40, 0, 0, 0, 3, 0, 0, 0, 10, 0, 0, 0]);
+
+        let (map, key) = table_key(1, &"::1".to_ip_net().unwrap()).unwrap();
+        assert_eq!(map, IPV6_TABLES_MAP);
+        assert_eq!(key.len(), 24);
+        assert_eq!(&key[..8], &[160, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(key[23], 1);
+
+        let moved = with_table(&key, 2);
+        assert_eq!(key_table(&moved), 2);
+        assert_eq!(moved, table_key(2, &"::1".to_ip_net().unwrap()).unwrap().1);
+    }
+}
@@ -2,41 +2,50 @@ use std::fs;
 use std::iter::Peekable;
 use std::vec::IntoIter;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Error, Result};
 
 use crate::token::Token;
 use crate::token::{
-    ALL, ASSIGN, BLOCK, CLOSE_CBRACK, FROM, NL, ON, OPEN_CBRACK, PASS, PORT, PROTO, REPLACE_PREFIX,
-    TO,
+    ALL, ASSIGN, BLOCK, CLOSE_CBRACK, CODE, FLAGS, FROM, ICMP6_TYPE, ICMP_TYPE, IN, KEEP, LOG, NL,
+    NOT, ON, OPEN_CBRACK, OUT, PASS, PERSIST, PORT, PROTO, QUICK, REPLACE_PREFIX, STATE, TABLE, TO,
 };
 
 pub struct Lexer {
     buf: Peekable<IntoIter<char>>,
+    // the iterator stops at the first invalid token and keeps the reason here
+    error: Option<Error>,
 }
 
 impl Lexer {
     pub fn from_str(str: String) -> Lexer {
         Lexer {
             buf: str.chars().collect::<Vec<_>>().into_iter().peekable(),
+            error: None,
         }
     }
 
     pub fn from_file(file_path: &str) -> Result<Self> {
         Ok(Self::from_str(
-            fs::read_to_string(file_path)
-                .expect("could not read file")
+            fs::read_to_string(file_path)?
                 .trim_start_matches(|c: char| c.is_ascii_whitespace())
                 .to_string(),
         ))
     }
 
-    fn read_ident(&mut self) -> Token {
+    /// Takes the error that stopped the lexer, if any
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    fn read_ident(&mut self) -> Result<Token> {
         self.consume_whitespace();
-        let ident = self.read_next().expect("invalid token `$`");
-        Token::Ident(ident)
+        let ident = self
+            .read_next()
+            .ok_or_else(|| anyhow!("invalid token `$`"))?;
+        Ok(Token::Ident(ident))
     }
 
-    fn read_list_items(&mut self) -> Token {
+    fn read_list_items(&mut self) -> Result<Token> {
         let mut items: Vec<Token> = Vec::new();
 
         // consume `{` if there is one
@@ -51,7 +60,11 @@ impl Lexer {
             self.read_while(|c| c.is_ascii_whitespace() && c != NL);
 
             if self.peek_then_read(|c| c == NL).is_some() {
-                panic!(r#"unexpected token `\n` in list"#)
+                bail!(r#"unexpected token `\n` in list"#)
+            }
+
+            if self.buf.peek().is_none() {
+                bail!("missing `}}` at the end of list")
             }
 
             if self.peek_then_read(|c| c == CLOSE_CBRACK).is_some() {
@@ -65,10 +78,10 @@ impl Lexer {
         }
 
         if items.is_empty() {
-            panic!("error: no tokens inside list")
+            bail!("no tokens inside list")
         }
 
-        Token::List(items)
+        Ok(Token::List(items))
     }
 
     fn interpret(&mut self, word: String) -> Token {
@@ -128,6 +141,21 @@ impl Lexer {
         self.consume_whitespace();
         Token::Nl
     }
+
+    fn or_stop<F>(&mut self, read: F) -> Option<Token>
+    where
+        F: FnOnce(&mut Self) -> Result<Token>,
+    {
+        match read(self) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                self.error = Some(e);
+                // drop the rest of the input so that the iterator ends here
+                self.buf = Vec::new().into_iter().peekable();
+                None
+            }
+        }
+    }
 }
 
 impl Iterator for Lexer {
@@ -144,10 +172,17 @@ impl Iterator for Lexer {
             return Some(self.read_newline());
         }
         if self.peek_then_read(|c| c == OPEN_CBRACK).is_some() {
-            return Some(self.read_list_items());
+            return self.or_stop(|lex| lex.read_list_items());
         }
         if self.peek_then_read(|c| c == REPLACE_PREFIX).is_some() {
-            return Some(self.read_ident());
+            return self.or_stop(|lex| lex.read_ident());
+        }
+        if self.peek_then_read(|c| c == NOT).is_some() {
+            // `!=` is a port operator, otherwise `!` negates what follows it
+            if self.peek_then_read(|c| c == ASSIGN).is_some() {
+                return Some(self.interpret(format!("{}{}", NOT, ASSIGN)));
+            }
+            return Some(Token::Not);
         }
 
         let s = match self.read_next() {
@@ -159,11 +194,23 @@ impl Iterator for Lexer {
             ALL => Some(Token::All),
             PASS => Some(Token::Pass),
             BLOCK => Some(Token::Block),
+            QUICK => Some(Token::Quick),
+            IN => Some(Token::In),
+            OUT => Some(Token::Out),
+            LOG => Some(Token::Log),
             ON => Some(Token::On),
             PROTO => Some(Token::Proto),
             PORT => Some(Token::Port),
             FROM => Some(Token::From),
             TO => Some(Token::To),
+            FLAGS => Some(Token::Flags),
+            ICMP_TYPE => Some(Token::IcmpType),
+            ICMP6_TYPE => Some(Token::Icmp6Type),
+            CODE => Some(Token::Code),
+            KEEP => Some(Token::Keep),
+            STATE => Some(Token::State),
+            TABLE => Some(Token::Table),
+            PERSIST => Some(Token::Persist),
             _ => Some(self.interpret(s)),
         }
     }
@@ -172,7 +219,10 @@ impl Iterator for Lexer {
 #[cfg(test)]
 mod tests {
     use super::Lexer;
-    use super::Token::{Assign, Block, Def, From, Ident, List, Nl, Pass, Proto, To, Val};
+    use super::Token::{
+        Assign, Block, Def, From, IcmpType, Ident, In, Keep, List, Log, Nl, Not, Out, Pass, Proto,
+        Quick, State, Table, To, Val,
+    };
 
     macro_rules! test_lexer {
         ($name:ident, $input:expr, $expect:expr) => {
@@ -196,16 +246,20 @@ mod tests {
             fn $name() {
                 let rule = String::from($input);
                 let mut lex = Lexer::from_str(rule.clone());
-                assert_eq!(lex.read_list_items(), $expect, "input was `{}`", rule)
+                assert_eq!(
+                    lex.read_list_items().unwrap(),
+                    $expect,
+                    "input was `{}`",
+                    rule
+                )
             }
         };
         ($name:ident, $input:expr) => {
             #[test]
-            #[should_panic]
             fn $name() {
                 let rule = String::from($input);
                 let mut lex = Lexer::from_str(rule.clone());
-                lex.read_list_items();
+                assert!(lex.read_list_items().is_err(), "input was `{}`", rule);
             }
         };
     }
@@ -221,11 +275,11 @@ mod tests {
         };
         ($name:ident, $input:expr) => {
             #[test]
-            #[should_panic]
             fn $name() {
                 let input = String::from($input);
                 let mut lex = Lexer::from_str(input.clone());
-                lex.next();
+                assert_eq!(lex.next(), None, "input was `{}`", input);
+                assert!(lex.take_error().is_some(), "input was `{}`", input);
             }
         };
     }
@@ -256,9 +310,20 @@ mod tests {
     test_list!(read_list_fail2, "{ \n a }");
     test_list!(read_list_fail3, "{ }");
     test_list!(read_list_fail4, "{}");
+    test_list!(read_list_fail5, "{ a");
 
     test_next!(next_pass, "pass", Pass);
     test_next!(next_block, "block", Block);
+    test_next!(next_quick, "quick", Quick);
+    test_next!(next_log, "log", Log);
+    test_next!(next_in, "in", In);
+    test_next!(next_out, "out", Out);
+    test_next!(next_icmp_type, "icmp-type", IcmpType);
+    test_next!(next_keep, "keep state", Keep);
+    test_next!(next_state, "state", State);
+    test_next!(next_not, "!10.0.0.1", Not);
+    test_next!(next_table, "table <bad_hosts>", Table);
+    test_next!(next_not_equal, "!= 22", Val("!=".to_string()));
     test_next!(next_proto, "proto", Proto);
     test_next!(next_from, "from", From);
     test_next!(next_to, "to", To);
@@ -1,18 +1,25 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
 use std::{thread, time};
 
 use anyhow::{anyhow, Result};
-use clap::Parser as ClapParser;
+use clap::{AppSettings, Parser as ClapParser, Subcommand};
+use signal_hook::consts::SIGHUP;
 
 use lexer::Lexer;
-use libpf_rs::filter::Filter;
-use libpf_rs::rule::Rule;
-use libpf_rs::BPFLink;
+use libpf_rs::eval::Verdict;
+use libpf_rs::filter::{self, AttachOptions, Filter, Hook, LoadedFilter, XdpMode};
+use libpf_rs::iface::{self, LinkMonitor};
+use libpf_rs::log::LogReader;
+use libpf_rs::packet;
+use libpf_rs::pcap::{CaptureFile, PcapReader};
+use libpf_rs::rule::Action;
+use libpf_rs::stats::{self, Counters};
 
-use crate::parser::Parser;
+use crate::parser::{Parser, Ruleset};
 use crate::preproc::PreProc;
 
 mod lexer;
@@ -20,51 +27,189 @@ mod parser;
 mod preproc;
 mod token;
 
+// counters and logs of running filters are pinned under this directory, one per interface name,
+// along with the program, maps and link of filters loaded with `pf load`
+const PIN_DIR: &str = "/sys/fs/bpf/pfrs";
+
 #[derive(ClapParser)]
 #[clap(name = "pf")]
 #[clap(author = "Fausto Miguel Guarniz <mi9uel9@gmail.com>")]
 #[clap(version = "0.1.0")]
 #[clap(about = "eBPF-based packet filter for Rust", long_about = None)]
+#[clap(setting(AppSettings::SubcommandsNegateReqs))]
 struct Cli {
-    /// index of device where filter should be attached to
-    ifindex: i32,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// devices the filter should be attached to: names like eth0, groups like egress or
+    /// patterns like 'vlan*'. Devices that match later are attached to as they appear
+    #[clap(required = true, value_name = "IFNAME")]
+    interfaces: Vec<String>,
 
     /// path to config file
-    #[clap(short, long, parse(from_os_str), value_name = "FILE")]
+    #[clap(short, long, global = true, parse(from_os_str), value_name = "FILE")]
     config: Option<PathBuf>,
 
     #[clap(short)]
     /// Only generate .c and .o files for filter
     generate: bool,
+
+    /// Attach to XDP, which only sees incoming packets, or to TC ingress and egress
+    #[clap(long, global = true, possible_values = ["xdp", "tc"], default_value = "xdp")]
+    hook: String,
+
+    /// XDP mode: skb (generic), drv (native) or hw (offloaded), picked by the kernel if unset
+    #[clap(long, global = true, possible_values = ["skb", "drv", "hw"])]
+    mode: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Attach the filter, pin it under /sys/fs/bpf/pfrs and exit. It stays attached until
+    /// `pf unload`. A filter already pinned on a device is replaced
+    Load {
+        /// devices the filter should be attached to: names, groups or patterns.
+        /// Devices that match later are not attached to
+        #[clap(required = true, value_name = "IFNAME")]
+        interfaces: Vec<String>,
+    },
+    /// Detach a filter pinned by `pf load` and remove its pins
+    Unload {
+        /// names of the devices the filter is attached to
+        #[clap(required = true, value_name = "IFNAME")]
+        ifnames: Vec<String>,
+    },
+    /// Show the rules of a running filter with their counters, like `pfctl -vsr`.
+    /// The config must be the one the filter was last loaded with
+    Stats {
+        /// name of a device the filter is attached to
+        ifname: String,
+    },
+    /// Print the packets of the rules with `log` of a running filter
+    Log {
+        /// name of a device the filter is attached to
+        ifname: String,
+
+        /// Write the packets to a pcap file instead, or pcapng if it ends with `.pcapng`
+        #[clap(short, long, parse(from_os_str), value_name = "FILE")]
+        write: Option<PathBuf>,
+
+        /// Start a new file once the current one reaches this size
+        #[clap(long, value_name = "BYTES", requires = "write")]
+        rotate_size: Option<u64>,
+    },
+    /// Run the frames of a pcap or pcapng file through the rules of the config
+    /// and print how many each rule matched and which packets would be blocked.
+    /// Connections tracked with `keep state` are not taken into account
+    Replay {
+        /// capture of Ethernet frames
+        #[clap(parse(from_os_str), value_name = "CAPTURE")]
+        capture: PathBuf,
+
+        /// Device the packets were captured on, for the rules with `on`
+        #[clap(short, long, value_name = "IFNAME")]
+        interface: Option<String>,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
 
+    if let Some(Command::Log {
+        ifname,
+        write,
+        rotate_size,
+    }) = cli.command
+    {
+        print_log(&ifname, write, rotate_size).unwrap();
+        return;
+    }
+
+    if let Some(Command::Unload { ifnames }) = cli.command {
+        for ifname in ifnames.iter() {
+            match unload_filter(ifname) {
+                Ok(_) => println!("pf-rs: filter is unloaded from {}", ifname),
+                Err(e) => panic!("{}", e.to_string()),
+            }
+        }
+        return;
+    }
+
     let mut config = PathBuf::from_str("/etc/pfrs/pfrs.conf").unwrap();
     if let Some(path) = cli.config.as_deref() {
         config = PathBuf::from(path);
     }
 
-    let l = Lexer::from_file(config.as_path().to_str().unwrap()).unwrap();
+    let ruleset = read_config(config.as_path()).unwrap();
 
-    let pre_proc = PreProc::new(l);
-    let tokens = pre_proc.preprocess().unwrap();
+    if let Some(Command::Stats { ifname }) = cli.command {
+        print_stats(ruleset, &ifname).unwrap();
+        return;
+    }
 
-    let parser = Parser::new(tokens);
-    let rules = parser.parse_statements().unwrap();
+    if let Some(Command::Replay { capture, interface }) = cli.command {
+        replay(ruleset, capture.as_path(), interface.as_deref()).unwrap();
+        return;
+    }
 
     if cli.generate {
-        generate_filter(rules).unwrap();
+        generate_filter(ruleset).unwrap();
         return;
     }
 
-    let res = load_filter(rules, cli.ifindex);
-    match res {
-        Ok(_) => println!("pf-rs: filter is attached"),
+    let specs = match cli.command {
+        Some(Command::Load { ref interfaces }) => interfaces,
+        _ => &cli.interfaces,
+    };
+    let interfaces = match expand_interfaces(specs) {
+        Ok(i) if i.is_empty() => panic!("no interface matches {}", specs.join(" ")),
+        Ok(i) => i,
+        Err(e) => panic!("{}", e.to_string()),
+    };
+    let mode = match cli.mode.as_deref() {
+        Some("skb") => XdpMode::Skb,
+        Some("drv") => XdpMode::Drv,
+        Some("hw") => XdpMode::Hw,
+        _ => XdpMode::Auto,
+    };
+    let hook = match cli.hook.as_str() {
+        "tc" => Hook::Tc,
+        _ => Hook::Xdp,
+    };
+    let opts = AttachOptions::new().hook(hook).mode(mode);
+    // keep the filter around so that it can be updated
+    let mut filter = match load_filter(ruleset, &interfaces, opts) {
+        Ok(f) => {
+            println!("pf-rs: filter is attached");
+            f
+        }
         Err(e) => panic!("{}", e.to_string()),
+    };
+
+    // the filter stays attached once dropped
+    if let Some(Command::Load { .. }) = cli.command {
+        for (ifindex, ifname) in interfaces.iter() {
+            match filter.pin_attached(*ifindex, pin_dir(ifname)) {
+                Ok(_) => println!("pf-rs: filter is pinned on {}", ifname),
+                Err(e) => panic!("{}", e.to_string()),
+            }
+        }
+        return;
     }
 
+    for (_, ifname) in interfaces.iter() {
+        pin_filter(&mut filter, ifname);
+    }
+
+    // interfaces that match the command line once created get the filter too
+    let mut monitor = match LinkMonitor::new() {
+        Ok(m) => Some(m),
+        Err(e) => {
+            eprintln!("pf-rs: new interfaces will not be filtered: {}", e);
+            None
+        }
+    };
+
     // /* keep it alive */
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -74,24 +219,293 @@ fn main() {
     })
     .unwrap();
 
+    // reload the config on SIGHUP
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone()).unwrap();
+
     while running.load(Ordering::SeqCst) {
-        eprint!(".");
-        thread::sleep(time::Duration::from_secs(1));
+        if reload.swap(false, Ordering::SeqCst) {
+            match reload_filter(&mut filter, config.as_path()) {
+                Ok(_) => println!("pf-rs: reloaded {}", config.display()),
+                Err(e) => eprintln!(
+                    "pf-rs: keeping the current rules, failed to reload {}: {}",
+                    config.display(),
+                    e
+                ),
+            }
+        }
+        match monitor.as_mut() {
+            Some(m) => match m.poll(time::Duration::from_secs(1)) {
+                Ok(events) if !events.is_empty() => {
+                    if let Err(e) = update_interfaces(&mut filter, &cli.interfaces) {
+                        eprintln!("pf-rs: failed to update the interfaces: {}", e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("pf-rs: new interfaces will not be filtered: {}", e);
+                    monitor = None;
+                }
+            },
+            None => thread::sleep(time::Duration::from_secs(1)),
+        }
     }
 }
 
-pub fn load_filter(rules: Vec<Rule>, ifindex: i32) -> Result<BPFLink> {
-    let mut f = Filter::new();
-    for r in rules.into_iter() {
-        f.add_rule(r);
+// indexes and names of the interfaces matching any of the names, groups and patterns
+fn expand_interfaces(specs: &[String]) -> Result<Vec<(i32, String)>> {
+    let mut interfaces: Vec<(i32, String)> = Vec::new();
+    for spec in specs {
+        for (ifindex, ifname) in iface::expand(spec)? {
+            if !interfaces.iter().any(|(i, _)| *i == ifindex) {
+                interfaces.push((ifindex, ifname));
+            }
+        }
     }
-    Ok(f.load_on(ifindex)?)
+    Ok(interfaces)
 }
 
-pub fn generate_filter(rules: Vec<Rule>) -> Result<()> {
+// follows the interfaces of the host after they changed
+fn update_interfaces(filter: &mut LoadedFilter, specs: &[String]) -> Result<()> {
+    filter.update_interfaces()?;
+
+    let interfaces = expand_interfaces(specs)?;
+    for ifindex in filter.ifindexes() {
+        if !interfaces.iter().any(|(i, _)| *i == ifindex) {
+            filter.detach(ifindex);
+            println!("pf-rs: filter is detached from device {}", ifindex);
+        }
+    }
+    for (ifindex, ifname) in interfaces {
+        if filter.ifindexes().contains(&ifindex) {
+            continue;
+        }
+        // the other interfaces stay filtered
+        match filter.attach(ifindex) {
+            Ok(_) => {
+                println!("pf-rs: filter is attached to {}", ifname);
+                pin_filter(filter, &ifname);
+            }
+            Err(e) => eprintln!("pf-rs: failed to attach to {}: {}", ifname, e),
+        }
+    }
+    Ok(())
+}
+
+fn pin_filter(filter: &mut LoadedFilter, ifname: &str) {
+    if let Err(e) = filter.pin(pin_dir(ifname)) {
+        eprintln!(
+            "pf-rs: `pf stats` and `pf log` are not available on {}: {}",
+            ifname, e
+        );
+    }
+}
+
+fn read_config(path: &Path) -> Result<Ruleset> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("invalid unicode in config path"))?;
+    let tokens = PreProc::new(Lexer::from_file(path)?).preprocess()?;
+    Parser::new(tokens).parse_statements()
+}
+
+// the old filter stays attached until the new one replaces it
+pub fn reload_filter(filter: &mut LoadedFilter, config: &Path) -> Result<()> {
+    // an invalid config leaves the loaded filter as it is
+    let ruleset = read_config(config)?;
+    filter.reload(build_filter(ruleset))?;
+    Ok(())
+}
+
+fn pin_dir(ifname: &str) -> PathBuf {
+    Path::new(PIN_DIR).join(ifname)
+}
+
+// devices with a filter pinned by `pf load` get the new one in its place, which stays pinned
+pub fn load_filter(
+    ruleset: Ruleset,
+    interfaces: &[(i32, String)],
+    opts: AttachOptions,
+) -> Result<LoadedFilter> {
+    let (pinned, unpinned): (Vec<_>, Vec<_>) = interfaces
+        .iter()
+        .partition(|(_, ifname)| filter::is_pinned(pin_dir(ifname)));
+    let unpinned = unpinned.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+    if pinned.is_empty() {
+        return build_filter(ruleset).load_on(&unpinned, opts);
+    }
+
+    let dirs = pinned
+        .iter()
+        .map(|(i, ifname)| (*i, pin_dir(ifname)))
+        .collect::<Vec<_>>();
+    // the pinned filters are replaced by this one, which is pinned in their place
+    let mut filter = build_filter(ruleset).reattach(&dirs, opts)?;
+    for ifindex in unpinned {
+        filter.attach(ifindex)?;
+    }
+    Ok(filter)
+}
+
+// the device may be gone, its filter is then gone too
+fn unload_filter(ifname: &str) -> Result<()> {
+    let ifindex = iface::index(ifname).ok();
+    filter::unload(pin_dir(ifname), ifindex)
+}
+
+pub fn generate_filter(ruleset: Ruleset) -> Result<()> {
+    build_filter(ruleset).generate_src().map_err(|e| anyhow!(e))
+}
+
+fn build_filter(ruleset: Ruleset) -> Filter {
     let mut f = Filter::new();
-    for r in rules.into_iter() {
+    for t in ruleset.tables.into_iter() {
+        f.add_table(t);
+    }
+    for r in ruleset.rules.into_iter() {
         f.add_rule(r);
     }
-    f.generate_src().map_err(|e| anyhow!(e))
+    f
+}
+
+// rules get ids in the order of the config, starting from 1
+fn print_stats(ruleset: Ruleset, ifname: &str) -> Result<()> {
+    let (rules, filter) = stats::read_pinned(pin_dir(ifname))?;
+
+    for (i, rule) in ruleset.rules.iter().enumerate() {
+        let id = i as u32 + 1;
+        let counters = rules
+            .iter()
+            .find(|r| r.id == id)
+            .map(|r| r.counters)
+            .unwrap_or_default();
+        println!("@{} {}", id, rule);
+        println!("{}", format_counters(&counters));
+    }
+    println!("default action");
+    println!("{}", format_counters(&filter.default_action));
+    println!("parse failures");
+    println!("{}", format_counters(&filter.parse_failures));
+    Ok(())
+}
+
+// until interrupted, events are consumed so only one `pf log` should run per filter
+fn print_log(ifname: &str, write: Option<PathBuf>, rotate_size: Option<u64>) -> Result<()> {
+    let mut log = LogReader::from_pinned(pin_dir(ifname))?;
+    let mut capture = match write {
+        Some(path) => Some(CaptureFile::create(path, rotate_size)?),
+        None => None,
+    };
+
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })?;
+
+    while running.load(Ordering::SeqCst) {
+        let events = log.poll(time::Duration::from_secs(1))?;
+        match capture.as_mut() {
+            Some(capture) => {
+                for e in events.iter() {
+                    capture.write(e)?;
+                }
+                capture.flush()?;
+            }
+            None => events.iter().for_each(|e| println!("{}", e)),
+        }
+    }
+    Ok(())
+}
+
+// frames are evaluated in userspace, in the order of the capture
+fn replay(ruleset: Ruleset, capture: &Path, ifname: Option<&str>) -> Result<()> {
+    let ifindex = match ifname {
+        Some(ifname) => iface::index(ifname)?,
+        None => 0,
+    };
+    let rules = ruleset
+        .rules
+        .iter()
+        .map(|r| r.to_string())
+        .collect::<Vec<_>>();
+    let filter = build_filter(ruleset);
+
+    let mut hits = vec![Counters::default(); rules.len()];
+    let mut default_action = Counters::default();
+    let mut parse_failures = Counters::default();
+    let mut blocked = Vec::new();
+
+    for (i, frame) in PcapReader::open(capture)?.enumerate() {
+        let frame = frame?;
+        // what blocked packets are listed with
+        let (action, counters, desc) = match packet::parse_frame(&frame.data) {
+            Ok(Some(packet)) => match filter.evaluate(&packet.with_ifindex(ifindex)) {
+                Verdict {
+                    action,
+                    rule: Some(id),
+                } => (
+                    action,
+                    &mut hits[id as usize - 1],
+                    format!("@{} {}", id, packet),
+                ),
+                Verdict { action, rule: None } => {
+                    (action, &mut default_action, format!("default {}", packet))
+                }
+            },
+            Ok(None) => (
+                filter.default_action(),
+                &mut default_action,
+                "default".to_string(),
+            ),
+            // like the filter, count them as parse failures and apply the default action
+            Err(e) => {
+                parse_failures.packets += 1;
+                parse_failures.bytes += frame.len as u64;
+                (
+                    filter.default_action(),
+                    &mut default_action,
+                    format!("default ({})", e),
+                )
+            }
+        };
+        counters.packets += 1;
+        counters.bytes += frame.len as u64;
+        if action == Action::Block {
+            blocked.push(format!("#{} {} length {}", i + 1, desc, frame.len));
+        }
+    }
+
+    for (i, rule) in rules.iter().enumerate() {
+        println!("@{} {}", i + 1, rule);
+        println!("{}", format_hits(&hits[i]));
+    }
+    println!("default action");
+    println!("{}", format_hits(&default_action));
+    println!("parse failures");
+    println!("{}", format_hits(&parse_failures));
+    println!("blocked packets");
+    blocked.iter().for_each(|b| println!("  {}", b));
+    Ok(())
+}
+
+fn format_hits(counters: &Counters) -> String {
+    format!(
+        "  [ Packets: {:<10} Bytes: {:<12} ]",
+        counters.packets, counters.bytes
+    )
+}
+
+fn format_counters(counters: &Counters) -> String {
+    let last_hit = match counters
+        .last_hit
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+    {
+        Some(ago) => format!("{}s ago", ago.as_secs()),
+        None => "never".to_string(),
+    };
+    format!(
+        "  [ Packets: {:<10} Bytes: {:<12} Last hit: {} ]",
+        counters.packets, counters.bytes, last_hit
+    )
 }
@@ -3,13 +3,27 @@ use std::vec::IntoIter;
 
 use anyhow::{bail, Result};
 
-use libpf_rs::rule::{Builder, Rule};
+use libpf_rs::rule::{Builder, PortOp, Rule, TCP_FLAGS};
+use libpf_rs::table::Table;
 
-use crate::token::Token;
+use crate::token::{Token, ANY};
+
+enum Port {
+    Single(PortOp, u16),
+    Range(PortOp, u16, u16),
+}
+
+/// Rules and tables of a config file
+#[derive(Debug, Default)]
+pub struct Ruleset {
+    pub rules: Vec<Rule>,
+    pub tables: Vec<Table>,
+}
 
 pub struct Parser {
     tokens: Peekable<IntoIter<Token>>,
     rules: Vec<Rule>,
+    tables: Vec<Table>,
 }
 
 impl Parser {
@@ -17,6 +31,7 @@ impl Parser {
         Parser {
             tokens: tokens.into_iter().peekable(),
             rules: Vec::new(),
+            tables: Vec::new(),
         }
     }
 
@@ -32,72 +47,605 @@ impl Parser {
         None
     }
 
-    fn read_or_die<P>(&mut self, p: P, msg: &str) -> Token
-    where
-        P: FnOnce(&Token) -> bool,
-    {
-        match self.peek_then_read(p) {
-            Some(t) => t,
-            None => panic!("{}", msg),
+    fn read_arg(&mut self, msg: &str) -> Result<String> {
+        match self.tokens.next() {
+            Some(Token::Val(s)) => Ok(s),
+            _ => bail!("{}", msg),
         }
     }
 
-    fn read_arg(&mut self) -> Option<String> {
-        match self.tokens.next()? {
-            Token::Val(s) => Some(s),
+    fn read_port(&mut self) -> Result<Port> {
+        // `port = 22` is lexed as an assignment
+        if self
+            .peek_then_read(|t| matches!(t, Token::Assign))
+            .is_some()
+        {
+            let port = self.read_arg("missing port after `=`")?;
+            return Ok(Port::Single(PortOp::Eq, port.parse::<u16>()?));
+        }
+
+        let arg = self.read_arg("missing port after `port`")?;
+        if let Some(op) = unary_port_op(arg.as_str()) {
+            let port = self.read_arg("missing port after operator")?;
+            return Ok(Port::Single(op, port.parse::<u16>()?));
+        }
+
+        if let Some((lo, hi)) = arg.split_once(':') {
+            return Ok(Port::Range(
+                PortOp::Range,
+                lo.parse::<u16>()?,
+                hi.parse::<u16>()?,
+            ));
+        }
+
+        let lo = arg.parse::<u16>()?;
+        let range_op = self.peek_then_read(|t| match t {
+            Token::Val(v) => range_port_op(v.as_str()).is_some(),
+            _ => false,
+        });
+        if let Some(op) = range_op.and_then(|t| match t {
+            Token::Val(v) => range_port_op(v.as_str()),
             _ => None,
+        }) {
+            let hi = self.read_arg("missing port after operator")?;
+            return Ok(Port::Range(op, lo, hi.parse::<u16>()?));
+        }
+
+        Ok(Port::Single(PortOp::Eq, lo))
+    }
+
+    // `any` is the same as leaving out the address
+    fn read_addr(&mut self, msg: &str) -> Result<Option<String>> {
+        Ok(Some(self.read_arg(msg)?).filter(|addr| addr != ANY))
+    }
+
+    // `table <name> [persist] [{ addr ... }]`
+    fn parse_table(&mut self) -> Result<()> {
+        let arg = self.read_arg("expected table name after `table`")?;
+        let mut table = match table_name(arg.as_str()) {
+            Some(name) => Table::new(name),
+            None => bail!("expected `<name>` after `table` but got `{}`", arg),
+        };
+
+        // tables are kept whether rules reference them or not
+        self.peek_then_read(|t| matches!(t, Token::Persist));
+
+        if let Some(Token::List(entries)) = self.peek_then_read(|t| matches!(t, Token::List(_))) {
+            for entry in entries {
+                match entry {
+                    Token::Val(addr) => table.add(addr.as_str())?,
+                    t => bail!("invalid entry {:?} in table <{}>", t, table.name()),
+                }
+            }
         }
+
+        self.tables.push(table);
+        Ok(())
     }
 
     fn parse_statement(&mut self) -> Result<()> {
+        if self.peek_then_read(|t| matches!(t, Token::Table)).is_some() {
+            return self.parse_table();
+        }
+
         let mut builder = Builder::new();
+        // options that make `pass all` and `block all` regular rules instead of default actions
+        let mut has_options = false;
 
-        if self.peek_then_read(|t| matches!(t, Token::Pass)).is_some() {
+        let is_pass = if self.peek_then_read(|t| matches!(t, Token::Pass)).is_some() {
             builder = builder.pass();
+            true
         } else if self.peek_then_read(|t| matches!(t, Token::Block)).is_some() {
             builder = builder.block();
+            false
         } else {
             bail!("expected `pass` or `block` token");
+        };
+
+        if self.peek_then_read(|t| matches!(t, Token::In)).is_some() {
+            builder = builder.inbound();
+            has_options = true;
+        } else if self.peek_then_read(|t| matches!(t, Token::Out)).is_some() {
+            builder = builder.outbound();
+            has_options = true;
         }
 
-        // self.read_or_die(|t| matches!(t, Token::Proto), "expected token `proto`");
-        // builder = builder.proto(self.read_arg().expect("expected protocol after `proto`"));
+        if self.peek_then_read(|t| matches!(t, Token::Log)).is_some() {
+            builder = builder.log();
+            has_options = true;
+        }
 
-        self.read_or_die(|t| matches!(t, Token::From), "expected token `from`");
-        builder = builder.from_addr(
-            self.read_arg()
-                .expect("expected src IP after `to`")
-                .as_str(),
-        );
+        if self.peek_then_read(|t| matches!(t, Token::Quick)).is_some() {
+            builder = builder.quick();
+            has_options = true;
+        }
 
-        if self.peek_then_read(|t| matches!(t, Token::Port)).is_some() {
-            let port = self.read_arg().expect("missing src port after `port`");
-            builder = builder.from_port(port.parse::<u16>()?);
+        if self.peek_then_read(|t| matches!(t, Token::On)).is_some() {
+            builder = builder.on(self.read_arg("expected interface after `on`")?);
+            has_options = true;
         }
 
-        self.read_or_die(|t| matches!(t, Token::To), "expected token `to`");
-        builder = builder.to_addr(
-            self.read_arg()
-                .expect("expected dst IP after `to`")
-                .as_str(),
-        );
+        if self.peek_then_read(|t| matches!(t, Token::Proto)).is_some() {
+            builder = builder.proto(self.read_arg("expected protocol after `proto`")?);
+            has_options = true;
+        }
+
+        // `all` is short for `from any to any`
+        let all = self.peek_then_read(|t| matches!(t, Token::All)).is_some();
+
+        if !all && self.peek_then_read(|t| matches!(t, Token::From)).is_some() {
+            let negate = self.peek_then_read(|t| matches!(t, Token::Not)).is_some();
+            builder = match (self.read_addr("expected src IP after `from`")?, negate) {
+                (Some(addr), false) => match table_name(addr.as_str()) {
+                    Some(table) => builder.from_table(table),
+                    None => builder.from_addr(addr.as_str()),
+                },
+                (Some(addr), true) => match table_name(addr.as_str()) {
+                    Some(table) => builder.from_table_not(table),
+                    None => builder.from_addr_not(addr.as_str()),
+                },
+                (None, true) => bail!("`! any` does not match any address"),
+                (None, false) => builder,
+            };
+
+            if self.peek_then_read(|t| matches!(t, Token::Port)).is_some() {
+                builder = match self.read_port()? {
+                    Port::Single(op, port) => builder.from_port_op(op, port),
+                    Port::Range(op, lo, hi) => builder.from_port_range_op(op, lo, hi),
+                };
+            }
+        }
+
+        if !all && self.peek_then_read(|t| matches!(t, Token::To)).is_some() {
+            let negate = self.peek_then_read(|t| matches!(t, Token::Not)).is_some();
+            builder = match (self.read_addr("expected dst IP after `to`")?, negate) {
+                (Some(addr), false) => match table_name(addr.as_str()) {
+                    Some(table) => builder.to_table(table),
+                    None => builder.to_addr(addr.as_str()),
+                },
+                (Some(addr), true) => match table_name(addr.as_str()) {
+                    Some(table) => builder.to_table_not(table),
+                    None => builder.to_addr_not(addr.as_str()),
+                },
+                (None, true) => bail!("`! any` does not match any address"),
+                (None, false) => builder,
+            };
+
+            if self.peek_then_read(|t| matches!(t, Token::Port)).is_some() {
+                builder = match self.read_port()? {
+                    Port::Single(op, port) => builder.to_port_op(op, port),
+                    Port::Range(op, lo, hi) => builder.to_port_range_op(op, lo, hi),
+                };
+            }
+        }
+
+        if self.peek_then_read(|t| matches!(t, Token::Flags)).is_some() {
+            let flags = self.read_arg("expected TCP flags after `flags`")?;
+            // `flags S/SA`, `flags S` (all flags in the mask), `flags /SA` and `flags any`
+            if flags != ANY {
+                builder = match flags.split_once('/') {
+                    Some((set, mask)) => builder.tcp_flags(set, mask),
+                    None => builder.tcp_flags(flags.as_str(), TCP_FLAGS),
+                };
+            }
+            has_options = true;
+        }
+
+        if let Some(t) = self.peek_then_read(|t| matches!(t, Token::IcmpType | Token::Icmp6Type)) {
+            let icmp_type = self.read_arg("expected ICMP type")?;
+            builder = match t {
+                Token::IcmpType => builder.icmp_type(icmp_type),
+                _ => builder.icmp6_type(icmp_type),
+            };
+            if self.peek_then_read(|t| matches!(t, Token::Code)).is_some() {
+                let code = self.read_arg("expected ICMP code after `code`")?;
+                builder = builder.icmp_code(code.parse()?);
+            }
+            has_options = true;
+        }
 
-        if self.peek_then_read(|t| matches!(t, Token::Port)).is_some() {
-            let port = self.read_arg().expect("missing dst port after `port`");
-            builder = builder.to_port(port.parse::<u16>()?);
+        if self.peek_then_read(|t| matches!(t, Token::Keep)).is_some() {
+            if self.peek_then_read(|t| matches!(t, Token::State)).is_none() {
+                bail!("expected `state` after `keep`");
+            }
+            builder = builder.keep_state();
+            has_options = true;
         }
 
-        self.rules.push(builder.build()?);
+        let rule = match (all && !has_options, is_pass) {
+            (true, true) => builder.pass_all()?,
+            (true, false) => builder.block_all()?,
+            (false, _) => builder.build()?,
+        };
+        self.rules.push(rule);
         Ok(())
     }
 
-    pub fn parse_statements(mut self) -> Result<Vec<Rule>> {
+    pub fn parse_statements(mut self) -> Result<Ruleset> {
         loop {
             if self.tokens.peek().is_none() {
                 break;
             }
             self.parse_statement()?;
         }
-        Ok(self.rules)
+        Ok(Ruleset {
+            rules: self.rules,
+            tables: self.tables,
+        })
+    }
+}
+
+// tables are referenced as `<name>`
+fn table_name(arg: &str) -> Option<&str> {
+    arg.strip_prefix('<')?.strip_suffix('>')
+}
+
+fn unary_port_op(op: &str) -> Option<PortOp> {
+    match op {
+        "=" => Some(PortOp::Eq),
+        "!=" => Some(PortOp::Ne),
+        "<" => Some(PortOp::Lt),
+        "<=" => Some(PortOp::Le),
+        ">" => Some(PortOp::Gt),
+        ">=" => Some(PortOp::Ge),
+        _ => None,
+    }
+}
+
+fn range_port_op(op: &str) -> Option<PortOp> {
+    match op {
+        "><" => Some(PortOp::Within),
+        "<>" => Some(PortOp::Outside),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpf_rs::rule::{Builder, PortOp, Rule};
+    use libpf_rs::table::Table;
+
+    use super::{Parser, Ruleset};
+    use crate::lexer::Lexer;
+    use crate::preproc::PreProc;
+
+    fn parse_ruleset(input: &str) -> Ruleset {
+        let tokens = PreProc::new(Lexer::from_str(input.to_string()))
+            .preprocess()
+            .unwrap();
+        Parser::new(tokens).parse_statements().unwrap()
+    }
+
+    fn parse(input: &str) -> Vec<Rule> {
+        parse_ruleset(input).rules
+    }
+
+    fn parse_err(input: &str) -> String {
+        PreProc::new(Lexer::from_str(input.to_string()))
+            .preprocess()
+            .and_then(|tokens| Parser::new(tokens).parse_statements())
+            .unwrap_err()
+            .to_string()
+    }
+
+    macro_rules! test_parser {
+        ($name:ident, $input:expr, $expect:expr) => {
+            #[test]
+            fn $name() {
+                assert_eq!(parse($input), $expect, "input was `{}`", $input)
+            }
+        };
+    }
+
+    test_parser!(
+        parse_hosts,
+        "block from 10.11.4.2 to 10.11.3.2",
+        vec![Builder::new()
+            .block()
+            .from_addr("10.11.4.2")
+            .to_addr("10.11.3.2")
+            .build()
+            .unwrap()]
+    );
+
+    test_parser!(
+        parse_subnets,
+        "pass from 2001:db8::/32 port 53 to 2001:db8:1::1",
+        vec![Builder::new()
+            .pass()
+            .from_addr("2001:db8::/32")
+            .from_port(53)
+            .to_addr("2001:db8:1::1")
+            .build()
+            .unwrap()]
+    );
+
+    test_parser!(
+        parse_port_range,
+        "pass from 10.0.0.1 port 1024:65535 to 10.0.0.2 port = 80",
+        vec![Builder::new()
+            .pass()
+            .from_addr("10.0.0.1")
+            .from_port_range(1024, 65535)
+            .to_addr("10.0.0.2")
+            .to_port(80)
+            .build()
+            .unwrap()]
+    );
+
+    test_parser!(
+        parse_port_unary_op,
+        "block from 10.0.0.1 port > 1024 to 10.0.0.2 port != 22",
+        vec![Builder::new()
+            .block()
+            .from_addr("10.0.0.1")
+            .from_port_op(PortOp::Gt, 1024)
+            .to_addr("10.0.0.2")
+            .to_port_op(PortOp::Ne, 22)
+            .build()
+            .unwrap()]
+    );
+
+    test_parser!(
+        parse_port_range_op,
+        "block from 10.0.0.1 port 6000 >< 6010 to 10.0.0.2 port 1 <> 1023",
+        vec![Builder::new()
+            .block()
+            .from_addr("10.0.0.1")
+            .from_port_range_op(PortOp::Within, 6000, 6010)
+            .to_addr("10.0.0.2")
+            .to_port_range_op(PortOp::Outside, 1, 1023)
+            .build()
+            .unwrap()]
+    );
+
+    test_parser!(
+        parse_quick_keeps_order,
+        "block from 10.0.0.1 to 10.0.0.2 \n pass quick from 10.0.0.1 to 10.0.0.2",
+        vec![
+            Builder::new()
+                .block()
+                .from_addr("10.0.0.1")
+                .to_addr("10.0.0.2")
+                .build()
+                .unwrap(),
+            Builder::new()
+                .pass()
+                .quick()
+                .from_addr("10.0.0.1")
+                .to_addr("10.0.0.2")
+                .build()
+                .unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_default_actions,
+        "pass all \n block all",
+        vec![
+            Builder::new().pass_all().unwrap(),
+            Builder::new().block_all().unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_all_with_options,
+        "block quick proto tcp all",
+        vec![Builder::new().block().quick().proto("tcp").build().unwrap()]
+    );
+
+    test_parser!(
+        parse_log,
+        "block log quick from 10.0.0.1 \n pass log all",
+        vec![
+            Builder::new()
+                .block()
+                .log()
+                .quick()
+                .from_addr("10.0.0.1")
+                .build()
+                .unwrap(),
+            Builder::new().pass().log().build().unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_full_statement,
+        "block quick on eth0 proto tcp from any to 10.0.0.1 port 22",
+        vec![Builder::new()
+            .block()
+            .quick()
+            .on("eth0")
+            .proto("tcp")
+            .to_addr("10.0.0.1")
+            .to_port(22)
+            .build()
+            .unwrap()]
+    );
+
+    test_parser!(
+        parse_optional_from_and_to,
+        "pass proto udp to any port 53 \n block from 10.0.0.0/8 \n pass proto tcp",
+        vec![
+            Builder::new()
+                .pass()
+                .proto("udp")
+                .to_port(53)
+                .build()
+                .unwrap(),
+            Builder::new()
+                .block()
+                .from_addr("10.0.0.0/8")
+                .build()
+                .unwrap(),
+            Builder::new().pass().proto("tcp").build().unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_on,
+        "block in quick on lo proto tcp all",
+        vec![Builder::new()
+            .block()
+            .inbound()
+            .quick()
+            .on("lo")
+            .proto("tcp")
+            .build()
+            .unwrap()]
+    );
+
+    // groups and patterns are expanded once the filter is loaded
+    test_parser!(
+        parse_on_groups_and_patterns,
+        "block on egress all \n pass on vlan* all",
+        vec![
+            Builder::new().block().on("egress").build().unwrap(),
+            Builder::new().pass().on("vlan*").build().unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_keep_state,
+        "pass proto tcp to 10.0.0.1 port 22 keep state \n pass all keep state",
+        vec![
+            Builder::new()
+                .pass()
+                .proto("tcp")
+                .to_addr("10.0.0.1")
+                .to_port(22)
+                .keep_state()
+                .build()
+                .unwrap(),
+            Builder::new().pass().keep_state().build().unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_tcp_flags,
+        "pass proto tcp to any port 22 flags S/SA keep state \n block proto tcp flags /FSRPAUEW \n block flags FPU",
+        vec![
+            Builder::new()
+                .pass()
+                .proto("tcp")
+                .to_port(22)
+                .tcp_flags("S", "SA")
+                .keep_state()
+                .build()
+                .unwrap(),
+            Builder::new()
+                .block()
+                .proto("tcp")
+                .tcp_flags("", "FSRPAUEW")
+                .build()
+                .unwrap(),
+            Builder::new()
+                .block()
+                .tcp_flags("FPU", "FSRPAUEW")
+                .build()
+                .unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_icmp_type,
+        "pass proto icmp icmp-type echoreq code 0 \n block proto icmp6 from ::1 icmp6-type 135",
+        vec![
+            Builder::new()
+                .pass()
+                .proto("icmp")
+                .icmp_type("echoreq")
+                .icmp_code(0)
+                .build()
+                .unwrap(),
+            Builder::new()
+                .block()
+                .proto("icmp6")
+                .from_addr("::1")
+                .icmp6_type("neighbrsol")
+                .build()
+                .unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_direction,
+        "block in log quick proto tcp to any port 22 \n pass out all",
+        vec![
+            Builder::new()
+                .block()
+                .inbound()
+                .log()
+                .quick()
+                .proto("tcp")
+                .to_port(22)
+                .build()
+                .unwrap(),
+            Builder::new().pass().outbound().build().unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_proto_names_and_numbers,
+        "pass proto gre \n block proto 50",
+        vec![
+            Builder::new().pass().proto("gre").build().unwrap(),
+            Builder::new().block().proto("esp").build().unwrap()
+        ]
+    );
+
+    test_parser!(
+        parse_negation,
+        "block from !10.0.0.0/8 to any port != 443 \n pass from ! ::1 to !::2",
+        vec![
+            Builder::new()
+                .block()
+                .from_addr_not("10.0.0.0/8")
+                .to_port_op(PortOp::Ne, 443)
+                .build()
+                .unwrap(),
+            Builder::new()
+                .pass()
+                .from_addr_not("::1")
+                .to_addr_not("::2")
+                .build()
+                .unwrap()
+        ]
+    );
+
+    #[test]
+    fn parse_tables() {
+        let ruleset = parse_ruleset(
+            "table <bad_hosts> persist { 10.0.0.0/8 ::1 } \n table <empty> persist \n block from <bad_hosts> to !<empty>",
+        );
+
+        let mut bad_hosts = Table::new("bad_hosts");
+        bad_hosts.add("10.0.0.0/8").unwrap();
+        bad_hosts.add("::1").unwrap();
+        assert_eq!(ruleset.tables, vec![bad_hosts, Table::new("empty")]);
+        assert_eq!(
+            ruleset.rules,
+            vec![Builder::new()
+                .block()
+                .from_table("bad_hosts")
+                .to_table_not("empty")
+                .build()
+                .unwrap()]
+        );
+    }
+
+    #[test]
+    fn invalid_configs_are_errors() {
+        assert_eq!(parse_err("block on"), "expected interface after `on`");
+        assert_eq!(parse_err("pass from"), "expected src IP after `from`");
+        assert_eq!(
+            parse_err("pass to any port >"),
+            "missing port after operator"
+        );
+        assert_eq!(parse_err("block from $hosts"), "unknown identifier hosts");
+        assert_eq!(
+            parse_err("block from { a"),
+            "missing `}` at the end of list"
+        );
     }
 }
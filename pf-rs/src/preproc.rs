@@ -2,22 +2,26 @@ use std::collections::{HashMap, VecDeque};
 use std::iter::Peekable;
 use std::vec::IntoIter;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use crate::token::Token;
 use crate::Lexer;
 
 pub struct PreProc {
     tokens: Vec<Token>,
+    // why the lexer stopped early, reported by `preprocess`
+    lex_error: Option<anyhow::Error>,
     buf: Peekable<IntoIter<Token>>,
     idents: HashMap<String, Token>,
 }
 
 impl PreProc {
-    pub fn new(lex: Lexer) -> Self {
+    pub fn new(mut lex: Lexer) -> Self {
+        let tokens = lex.by_ref().collect::<Vec<_>>();
         PreProc {
             tokens: Vec::new(),
-            buf: lex.collect::<Vec<_>>().into_iter().peekable(),
+            lex_error: lex.take_error(),
+            buf: tokens.into_iter().peekable(),
             idents: HashMap::new(),
         }
     }
@@ -32,7 +36,7 @@ impl PreProc {
                     self.tokens.push(
                         token_vec
                             .pop_front()
-                            .expect("error: failed to process list token"),
+                            .ok_or_else(|| anyhow!("failed to process list token"))?,
                     )
                 } else {
                     self.tokens.push(token.clone());
@@ -47,6 +51,12 @@ impl PreProc {
 
         let mut line = self.process_macros(raw_line)?;
 
+        // the list of a table holds its entries, it is not expanded into several lines
+        if let Some(Token::Table) = line.first() {
+            self.tokens.append(&mut line);
+            return Ok(());
+        }
+
         for token in line.iter() {
             if let Token::List(token_vec) = token {
                 buf.push(token_vec.clone())
@@ -68,17 +78,20 @@ impl PreProc {
 
         while let Some(t) = tokens.next() {
             if let Token::Ident(name) = t {
-                let msg = format!("unknown identifier {}", name.as_str());
-                let val = self.idents.get(name.as_str()).expect(msg.as_str());
+                let val = self
+                    .idents
+                    .get(name.as_str())
+                    .ok_or_else(|| anyhow!("unknown identifier {}", name))?;
                 res.push(val.clone());
             } else if let Token::Def(name) = t {
                 tokens
                     .next()
                     .filter(|t| matches!(t, Token::Assign))
-                    .expect("expected `=` in macro declaration"); // this will never panic
+                    .ok_or_else(|| anyhow!("expected `=` in macro declaration"))?;
 
-                let msg = format!("invalid `{} = [no value]`", name.as_str());
-                let token = tokens.next().expect(msg.as_str());
+                let token = tokens
+                    .next()
+                    .ok_or_else(|| anyhow!("invalid `{} = [no value]`", name))?;
                 self.idents.insert(name, token);
             } else {
                 res.push(t);
@@ -89,6 +102,10 @@ impl PreProc {
     }
 
     pub fn preprocess(mut self) -> Result<Vec<Token>> {
+        if let Some(e) = self.lex_error.take() {
+            return Err(e);
+        }
+
         // skip initial new lines if any
         while let Some(Token::Nl) = self.buf.peek() {
             self.buf.next();
@@ -1,13 +1,27 @@
 pub const ALL: &str = "all";
+pub const ANY: &str = "any";
 pub const PASS: &str = "pass";
 pub const BLOCK: &str = "block";
+pub const QUICK: &str = "quick";
+pub const IN: &str = "in";
+pub const OUT: &str = "out";
+pub const LOG: &str = "log";
+pub const KEEP: &str = "keep";
+pub const STATE: &str = "state";
 pub const PROTO: &str = "proto";
 pub const ON: &str = "on";
 pub const FROM: &str = "from";
 pub const TO: &str = "to";
 pub const PORT: &str = "port";
+pub const FLAGS: &str = "flags";
+pub const TABLE: &str = "table";
+pub const PERSIST: &str = "persist";
+pub const ICMP_TYPE: &str = "icmp-type";
+pub const ICMP6_TYPE: &str = "icmp6-type";
+pub const CODE: &str = "code";
 pub const NL: char = '\n';
 pub const ASSIGN: char = '=';
+pub const NOT: char = '!';
 pub const REPLACE_PREFIX: char = '$';
 pub const OPEN_CBRACK: char = '{';
 pub const CLOSE_CBRACK: char = '}';
@@ -17,11 +31,24 @@ pub enum Token {
     All,
     Assign,
     Block,
+    Code,
+    Flags,
     From,
+    IcmpType,
+    Icmp6Type,
+    In,
+    Keep,
+    Log,
     Nl,
+    Not,
+    Out,
     Pass,
+    Persist,
     Proto,
+    Quick,
     On,
+    State,
+    Table,
     To,
     Port,
     Val(String),